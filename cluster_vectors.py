@@ -14,9 +14,11 @@ commit_hash = {}
 index_dic = {}
 commit_cnt = 0
 feature_cnt = 0
+# rows written by `difft --token-ngrams N` use this in the change type column
+TOKEN_NGRAM_NAMESPACE = "TokenNgram"
 
 def process_commit_vector(vec_list: list):
-    res = np.zeros(len(index_dic))
+    res = np.zeros(feature_cnt)
     #res = np.zeros(len(index_dic) + 1)
     #res[0] = commit_hash[(vec_list[0][0], vec_list[0][1])]
     for vec in vec_list:
@@ -60,11 +62,22 @@ def compress_vectors(vectors, m):
 if __name__ == '__main__':
     parent_dic = {}
     grandparent_dic = {}
+    ngram_dic = {}
     vec_file = sys.argv[1]
     # 第一遍读，获取所有的commit数以及出现的节点类型
     with open(vec_file, "r", encoding="utf-8") as f:
         reader = csv.reader(f)
         for row in reader: # repo, commit, added/deleted, parent, grandparent
+            if row[2] == TOKEN_NGRAM_NAMESPACE: # repo, commit, TokenNgram, added/deleted, n-gram
+                if (row[3], row[4]) not in ngram_dic.keys():
+                    ngram_dic[(row[3], row[4])] = 1
+                else:
+                    ngram_dic[(row[3], row[4])] = ngram_dic[(row[3], row[4])] + 1
+                if (row[0], row[1]) not in commit_hash.keys():
+                    commit_hash[(row[0], row[1])] = commit_cnt
+                    commit_cnt = commit_cnt + 1
+                    commits.append((row[0], row[1]))
+                continue
             if row[3] not in parent_dic.keys():
                 parent_dic[row[3]] = 1
             else:
@@ -89,8 +102,17 @@ if __name__ == '__main__':
         for j in sorted(grandparent_dic.items(), key = lambda x:x[1], reverse=True):
             index_dic[("Added", i[0], j[0])] = feature_cnt
             index_dic[("Deleted", i[0], j[0])] = feature_cnt + offset
+            # atoms matched by a ReplacedAtom edge
+            index_dic[("Replaced", i[0], j[0])] = feature_cnt + 2 * offset
             feature_cnt = feature_cnt + 1
 
+    # token n-gram features go after the node kind features
+    feature_cnt = 3 * offset
+    for i in sorted(ngram_dic.items(), key = lambda x:x[1], reverse=True):
+        index_dic[(TOKEN_NGRAM_NAMESPACE, i[0][0], i[0][1])] = feature_cnt
+        feature_cnt = feature_cnt + 1
+    print(f'token n-gram number = {len(ngram_dic)}')
+
 
     vecs = np.empty(0)
 
@@ -12,6 +12,8 @@ use version_check as rustc;
 
 struct TreeSitterParser {
     name: &'static str,
+    /// The cargo feature that enables this parser.
+    feature: &'static str,
     src_dir: &'static str,
     extra_files: Vec<&'static str>,
 }
@@ -92,261 +94,324 @@ fn main() {
     let parsers = vec![
         TreeSitterParser {
             name: "tree-sitter-ada",
+            feature: "lang-ada",
             src_dir: "vendored_parsers/tree-sitter-ada-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-bash",
+            feature: "lang-bash",
             src_dir: "vendored_parsers/tree-sitter-bash-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-c",
+            feature: "lang-c",
             src_dir: "vendored_parsers/tree-sitter-c-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-cpp",
+            feature: "lang-cpp",
             src_dir: "vendored_parsers/tree-sitter-cpp-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-c-sharp",
+            feature: "lang-c-sharp",
             src_dir: "vendored_parsers/tree-sitter-c-sharp-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-clojure",
+            feature: "lang-clojure",
             src_dir: "vendored_parsers/tree-sitter-clojure-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-cmake",
+            feature: "lang-cmake",
             src_dir: "vendored_parsers/tree-sitter-cmake-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-commonlisp",
+            feature: "lang-commonlisp",
             src_dir: "vendored_parsers/tree-sitter-commonlisp-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-css",
+            feature: "lang-css",
             src_dir: "vendored_parsers/tree-sitter-css-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-dart",
+            feature: "lang-dart",
             src_dir: "vendored_parsers/tree-sitter-dart-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-elisp",
+            feature: "lang-elisp",
             src_dir: "vendored_parsers/tree-sitter-elisp-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-elixir",
+            feature: "lang-elixir",
             src_dir: "vendored_parsers/tree-sitter-elixir-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-elm",
+            feature: "lang-elm",
             src_dir: "vendored_parsers/tree-sitter-elm-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-elvish",
+            feature: "lang-elvish",
             src_dir: "vendored_parsers/tree-sitter-elvish-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-erlang",
+            feature: "lang-erlang",
             src_dir: "vendored_parsers/tree-sitter-erlang-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-gleam",
+            feature: "lang-gleam",
             src_dir: "vendored_parsers/tree-sitter-gleam-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-go",
+            feature: "lang-go",
             src_dir: "vendored_parsers/tree-sitter-go-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-hack",
+            feature: "lang-hack",
             src_dir: "vendored_parsers/tree-sitter-hack-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-hare",
+            feature: "lang-hare",
             src_dir: "vendored_parsers/tree-sitter-hare-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-haskell",
+            feature: "lang-haskell",
             src_dir: "vendored_parsers/tree-sitter-haskell-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-hcl",
+            feature: "lang-hcl",
             src_dir: "vendored_parsers/tree-sitter-hcl-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-html",
+            feature: "lang-html",
             src_dir: "vendored_parsers/tree-sitter-html-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-janet-simple",
+            feature: "lang-janet",
             src_dir: "vendored_parsers/tree-sitter-janet-simple-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-java",
+            feature: "lang-java",
             src_dir: "vendored_parsers/tree-sitter-java-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-javascript",
+            feature: "lang-javascript",
             src_dir: "vendored_parsers/tree-sitter-javascript-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-json",
+            feature: "lang-json",
             src_dir: "vendored_parsers/tree-sitter-json-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-julia",
+            feature: "lang-julia",
             src_dir: "vendored_parsers/tree-sitter-julia-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-kotlin",
+            feature: "lang-kotlin",
             src_dir: "vendored_parsers/tree-sitter-kotlin-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-lua",
+            feature: "lang-lua",
             src_dir: "vendored_parsers/tree-sitter-lua-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-make",
+            feature: "lang-make",
             src_dir: "vendored_parsers/tree-sitter-make-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-newick",
+            feature: "lang-newick",
             src_dir: "vendored_parsers/tree-sitter-newick-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-nix",
+            feature: "lang-nix",
             src_dir: "vendored_parsers/tree-sitter-nix-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-ocaml",
+            feature: "lang-ocaml",
             src_dir: "vendored_parsers/tree-sitter-ocaml-src/ocaml/src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-ocaml-interface",
+            feature: "lang-ocaml",
             src_dir: "vendored_parsers/tree-sitter-ocaml-src/interface/src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-pascal",
+            feature: "lang-pascal",
             src_dir: "vendored_parsers/tree-sitter-pascal-src",
             extra_files: vec![],
         },
         TreeSitterParser {
             name: "tree-sitter-perl",
+            feature: "lang-perl",
             src_dir: "vendored_parsers/tree-sitter-perl-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-php",
+            feature: "lang-php",
             src_dir: "vendored_parsers/tree-sitter-php-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-python",
+            feature: "lang-python",
             src_dir: "vendored_parsers/tree-sitter-python-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-qmljs",
+            feature: "lang-qml",
             src_dir: "vendored_parsers/tree-sitter-qmljs-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-r",
+            feature: "lang-r",
             src_dir: "vendored_parsers/tree-sitter-r-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-racket",
+            feature: "lang-racket",
             src_dir: "vendored_parsers/tree-sitter-racket-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-ruby",
+            feature: "lang-ruby",
             src_dir: "vendored_parsers/tree-sitter-ruby-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-rust",
+            feature: "lang-rust",
             src_dir: "vendored_parsers/tree-sitter-rust-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-scala",
+            feature: "lang-scala",
             src_dir: "vendored_parsers/tree-sitter-scala-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-sql",
+            feature: "lang-sql",
             src_dir: "vendored_parsers/tree-sitter-sql-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-swift",
+            feature: "lang-swift",
             src_dir: "vendored_parsers/tree-sitter-swift-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-toml",
+            feature: "lang-toml",
             src_dir: "vendored_parsers/tree-sitter-toml-src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-tsx",
+            feature: "lang-typescript",
             src_dir: "vendored_parsers/tree-sitter-typescript-src/tsx/src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-typescript",
+            feature: "lang-typescript",
             src_dir: "vendored_parsers/tree-sitter-typescript-src/typescript/src",
             extra_files: vec!["scanner.c"],
         },
         TreeSitterParser {
             name: "tree-sitter-yaml",
+            feature: "lang-yaml",
             src_dir: "vendored_parsers/tree-sitter-yaml-src",
             extra_files: vec!["scanner.cc"],
         },
         TreeSitterParser {
             name: "tree-sitter-zig",
+            feature: "lang-zig",
             src_dir: "vendored_parsers/tree-sitter-zig-src",
             extra_files: vec![],
         },
     ];
 
+    // Cargo sets CARGO_FEATURE_<NAME> for each enabled feature.
+    let parsers: Vec<TreeSitterParser> = parsers
+        .into_iter()
+        .filter(|p| {
+            let var = format!(
+                "CARGO_FEATURE_{}",
+                p.feature.to_uppercase().replace('-', "_")
+            );
+            std::env::var_os(var).is_some()
+        })
+        .collect();
+
     // Only rerun if relevant files in the vendored_parsers/ directory change.
     for parser in &parsers {
         println!("cargo:rerun-if-changed={}", parser.src_dir);
@@ -0,0 +1,311 @@
+//! A static HTML report of clustering results.
+//!
+//! The report is a directory containing an index page, a page per
+//! cluster and a stylesheet, so it can be zipped up and shared with
+//! people who don't have difftastic or the mined repositories.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    cluster_view::{member_files, Cluster, ClusterMember, MemberFiles},
+    display::html::{escape, STYLESHEET},
+};
+
+/// How many features to show in each cluster's histogram.
+const MAX_HISTOGRAM_ROWS: usize = 30;
+
+const REPORT_STYLESHEET: &str = "\
+nav { margin-bottom: 1em; }
+nav a { margin-right: 1em; }
+table.histogram { border-collapse: collapse; margin-bottom: 1.5em; }
+table.histogram td { padding: 0.1em 0.5em; font-family: monospace; }
+table.histogram div.bar { background: #69c; height: 0.8em; }
+pre.commit-message { background: #f6f6f6; padding: 0.5em; }
+";
+
+/// Feature counts for each (repo, commit) pair.
+pub type FeatureHistograms = FxHashMap<(String, String), FxHashMap<String, usize>>;
+
+/// Count the features of each commit in a vector file, as written by
+/// the diff mode. Each row is `repo,commit,...`, and the remaining
+/// columns, joined by spaces, name the feature.
+pub fn parse_vector_file(src: &str) -> FeatureHistograms {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(src.as_bytes());
+
+    let mut res = FeatureHistograms::default();
+    for record in reader.records().filter_map(|record| record.ok()) {
+        if record.len() < 3 {
+            continue;
+        }
+        let feature: Vec<&str> = record.iter().skip(2).collect();
+        *res.entry((record[0].to_owned(), record[1].to_owned()))
+            .or_default()
+            .entry(feature.join(" "))
+            .or_insert(0) += 1;
+    }
+    res
+}
+
+/// The total feature counts over all the members of `cluster`, most
+/// frequent first.
+fn cluster_histogram(cluster: &Cluster, histograms: &FeatureHistograms) -> Vec<(String, usize)> {
+    let mut totals: FxHashMap<&str, usize> = FxHashMap::default();
+    for member in &cluster.members {
+        if let Some(counts) = histograms.get(&(member.repo.clone(), member.commit.clone())) {
+            for (feature, count) in counts {
+                *totals.entry(feature).or_insert(0) += count;
+            }
+        }
+    }
+
+    let mut res: Vec<_> = totals
+        .into_iter()
+        .map(|(feature, count)| (feature.to_owned(), count))
+        .collect();
+    res.sort_by(|(f1, c1), (f2, c2)| c2.cmp(c1).then_with(|| f1.cmp(f2)));
+    res
+}
+
+fn cluster_file_name(cluster_i: usize) -> String {
+    format!("cluster-{}.html", cluster_i + 1)
+}
+
+fn write_page_start(out: &mut impl Write, title: &str) -> io::Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>{}</title>", escape(title))?;
+    writeln!(out, "<link rel=\"stylesheet\" href=\"style.css\">")?;
+    writeln!(out, "</head>\n<body>")?;
+    writeln!(out, "<h1>{}</h1>", escape(title))
+}
+
+fn write_page_end(out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "</body>\n</html>")
+}
+
+fn write_nav(out: &mut impl Write, cluster_i: usize, num_clusters: usize) -> io::Result<()> {
+    write!(out, "<nav>")?;
+    if cluster_i > 0 {
+        write!(
+            out,
+            "<a href=\"{}\">&larr; Previous</a>",
+            cluster_file_name(cluster_i - 1)
+        )?;
+    }
+    write!(out, "<a href=\"index.html\">All clusters</a>")?;
+    if cluster_i + 1 < num_clusters {
+        write!(
+            out,
+            "<a href=\"{}\">Next &rarr;</a>",
+            cluster_file_name(cluster_i + 1)
+        )?;
+    }
+    writeln!(out, "</nav>")
+}
+
+fn write_histogram(out: &mut impl Write, histogram: &[(String, usize)]) -> io::Result<()> {
+    writeln!(out, "<h2>Features</h2>")?;
+    if histogram.is_empty() {
+        return writeln!(out, "<p>No features recorded for these members.</p>");
+    }
+
+    let max_count = histogram[0].1;
+    writeln!(out, "<table class=\"histogram\">")?;
+    for (feature, count) in histogram.iter().take(MAX_HISTOGRAM_ROWS) {
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td><div class=\"bar\" style=\"width: {}px\"></div></td></tr>",
+            escape(feature),
+            count,
+            count * 300 / max_count
+        )?;
+    }
+    writeln!(out, "</table>")?;
+    if histogram.len() > MAX_HISTOGRAM_ROWS {
+        writeln!(
+            out,
+            "<p>{} less frequent features not shown.</p>",
+            histogram.len() - MAX_HISTOGRAM_ROWS
+        )?;
+    }
+    Ok(())
+}
+
+fn write_index(out: &mut impl Write, clusters: &[Cluster]) -> io::Result<()> {
+    write_page_start(out, "Clusters")?;
+    if let Some(threshold) = clusters.first().and_then(|c| c.distance_threshold) {
+        writeln!(out, "<p>Distance threshold: {}</p>", threshold)?;
+    }
+
+    writeln!(out, "<ul>")?;
+    for (i, cluster) in clusters.iter().enumerate() {
+        let repos: Vec<&str> = cluster.members.iter().map(|m| &m.repo[..]).collect();
+        writeln!(
+            out,
+            "<li><a href=\"{}\">Cluster {}</a>: {} members ({})</li>",
+            cluster_file_name(i),
+            i + 1,
+            cluster.members.len(),
+            escape(&repos.join(", "))
+        )?;
+    }
+    writeln!(out, "</ul>")?;
+    write_page_end(out)
+}
+
+fn write_cluster_page(
+    out: &mut impl Write,
+    clusters: &[Cluster],
+    cluster_i: usize,
+    code_dir: &Path,
+    histograms: Option<&FeatureHistograms>,
+    render: &mut impl FnMut(&MemberFiles, &mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    let cluster = &clusters[cluster_i];
+    write_page_start(
+        out,
+        &format!("Cluster {} of {}", cluster_i + 1, clusters.len()),
+    )?;
+    write_nav(out, cluster_i, clusters.len())?;
+
+    if let Some(histograms) = histograms {
+        write_histogram(out, &cluster_histogram(cluster, histograms))?;
+    }
+
+    writeln!(out, "<h2>Members</h2>")?;
+    for member in &cluster.members {
+        write_member(out, member, code_dir, render)?;
+    }
+
+    write_nav(out, cluster_i, clusters.len())?;
+    write_page_end(out)
+}
+
+fn write_member(
+    out: &mut impl Write,
+    member: &ClusterMember,
+    code_dir: &Path,
+    render: &mut impl FnMut(&MemberFiles, &mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "<h3>{} {}</h3>",
+        escape(&member.repo),
+        escape(&member.commit)
+    )?;
+
+    match member_files(code_dir, member) {
+        Some(files) => {
+            if let Some(message) = &files.commit_message {
+                writeln!(
+                    out,
+                    "<pre class=\"commit-message\">{}</pre>",
+                    escape(message.trim())
+                )?;
+            }
+
+            let mut buf = vec![];
+            render(&files, &mut buf)?;
+            out.write_all(&buf)
+        }
+        None => writeln!(
+            out,
+            "<p>No before/after files in {}.</p>",
+            escape(&code_dir.display().to_string())
+        ),
+    }
+}
+
+/// Write the report for `clusters` to `out_dir`, creating it if
+/// necessary. `render` is called with each member's files and should
+/// write the HTML for its diff.
+pub fn write_report(
+    out_dir: &Path,
+    clusters: &[Cluster],
+    code_dir: &Path,
+    histograms: Option<&FeatureHistograms>,
+    mut render: impl FnMut(&MemberFiles, &mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    fs::create_dir_all(out_dir)?;
+    fs::write(
+        out_dir.join("style.css"),
+        format!("{}{}", STYLESHEET, REPORT_STYLESHEET),
+    )?;
+
+    let mut index = io::BufWriter::new(fs::File::create(out_dir.join("index.html"))?);
+    write_index(&mut index, clusters)?;
+    index.flush()?;
+
+    for cluster_i in 0..clusters.len() {
+        let mut page = io::BufWriter::new(fs::File::create(
+            out_dir.join(cluster_file_name(cluster_i)),
+        )?);
+        write_cluster_page(
+            &mut page,
+            clusters,
+            cluster_i,
+            code_dir,
+            histograms,
+            &mut render,
+        )?;
+        page.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(repo: &str, commit: &str) -> ClusterMember {
+        ClusterMember {
+            repo: repo.into(),
+            commit: commit.into(),
+        }
+    }
+
+    #[test]
+    fn test_cluster_histogram() {
+        let histograms = parse_vector_file(
+            "bat,aaa,Added,call_expression,block
+bat,aaa,Added,call_expression,block
+fd,bbb,Added,call_expression,block
+fd,bbb,TokenNgram,Added,normal:unwrap
+cargo,ccc,Deleted,let_declaration,block
+",
+        );
+        let cluster = Cluster {
+            distance_threshold: None,
+            members: vec![member("bat", "aaa"), member("fd", "bbb")],
+        };
+
+        assert_eq!(
+            cluster_histogram(&cluster, &histograms),
+            vec![
+                ("Added call_expression block".to_owned(), 3),
+                ("TokenNgram Added normal:unwrap".to_owned(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nav_links() {
+        let mut out = vec![];
+        write_nav(&mut out, 0, 2).unwrap();
+        let nav = String::from_utf8(out).unwrap();
+
+        assert!(!nav.contains("Previous"));
+        assert!(nav.contains("href=\"cluster-2.html\""));
+        assert!(nav.contains("href=\"index.html\""));
+    }
+}
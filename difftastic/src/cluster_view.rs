@@ -0,0 +1,416 @@
+//! A terminal UI for browsing clustering results.
+//!
+//! This reads the HAC results written by `cluster_vectors.py` and
+//! shows the structural diff of each cluster member, so results can
+//! be inspected on a headless machine.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    queue,
+    style::{Attribute, Print, ResetColor, SetAttribute},
+    terminal::{self, ClearType},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterMember {
+    pub repo: String,
+    pub commit: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cluster {
+    /// The `dis_thresh` value that the HAC run used, if the results
+    /// file records it.
+    pub distance_threshold: Option<u32>,
+    pub members: Vec<ClusterMember>,
+}
+
+/// Parse a line of the form `('repo', 'commit')`, as written by
+/// Python's `str()` of a tuple.
+fn parse_member(line: &str) -> Option<ClusterMember> {
+    let inner = line.trim().strip_prefix('(')?.strip_suffix(')')?;
+    let (repo, commit) = inner.split_once(',')?;
+
+    let unquote = |s: &str| {
+        let s = s.trim();
+        s.strip_prefix('\'')
+            .and_then(|s| s.strip_suffix('\''))
+            .or_else(|| s.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+            .unwrap_or(s)
+            .to_owned()
+    };
+
+    Some(ClusterMember {
+        repo: unquote(repo),
+        commit: unquote(commit),
+    })
+}
+
+/// Parse a HAC results file. The format is a `dis_thresh = N:`
+/// header, followed by clusters of members separated by lines of
+/// dashes.
+pub fn parse_hac_results(src: &str) -> Vec<Cluster> {
+    let mut clusters = vec![];
+    let mut distance_threshold = None;
+    let mut members = vec![];
+
+    for line in src.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(threshold) = line.strip_prefix("dis_thresh") {
+            distance_threshold = threshold
+                .trim_start_matches(|c: char| c == ' ' || c == '=')
+                .trim_end_matches(':')
+                .trim()
+                .parse()
+                .ok();
+        } else if line.starts_with('-') {
+            if !members.is_empty() {
+                clusters.push(Cluster {
+                    distance_threshold,
+                    members: std::mem::take(&mut members),
+                });
+            }
+        } else if let Some(member) = parse_member(line) {
+            members.push(member);
+        }
+    }
+
+    if !members.is_empty() {
+        clusters.push(Cluster {
+            distance_threshold,
+            members,
+        });
+    }
+
+    clusters
+}
+
+/// The files for a cluster member, laid out as
+/// `CODE_DIR/REPO/COMMIT/NAME/NAME_before.rs`.
+#[derive(Debug, Clone)]
+pub struct MemberFiles {
+    pub before: PathBuf,
+    pub after: PathBuf,
+    pub commit_message: Option<String>,
+}
+
+pub fn member_files(code_dir: &Path, member: &ClusterMember) -> Option<MemberFiles> {
+    let commit_dir = code_dir.join(&member.repo).join(&member.commit);
+
+    let mut names: Vec<_> = fs::read_dir(&commit_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    let name = names.into_iter().next()?;
+
+    let dir = commit_dir.join(&name);
+    let before = dir.join(format!("{}_before.rs", name));
+    let after = dir.join(format!("{}_after.rs", name));
+    if !before.is_file() || !after.is_file() {
+        return None;
+    }
+
+    Some(MemberFiles {
+        before,
+        after,
+        commit_message: fs::read_to_string(dir.join("commit_message.txt")).ok(),
+    })
+}
+
+/// Restores the terminal when the viewer exits, even on panic.
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        crossterm::execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = crossterm::execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+struct Viewer<'a> {
+    clusters: &'a [Cluster],
+    code_dir: &'a Path,
+    cluster_i: usize,
+    member_i: usize,
+    scroll: usize,
+    /// The rendered diff, and the (cluster, member, width) it was
+    /// rendered for.
+    diff_lines: Vec<String>,
+    rendered_for: Option<(usize, usize, usize)>,
+    commit_message: String,
+}
+
+const LIST_MAX_WIDTH: usize = 40;
+
+fn truncate(s: &str, width: usize) -> String {
+    let mut res: String = s.chars().take(width).collect();
+    let len = res.chars().count();
+    res.push_str(&" ".repeat(width - len));
+    res
+}
+
+impl<'a> Viewer<'a> {
+    fn selected(&self) -> &ClusterMember {
+        &self.clusters[self.cluster_i].members[self.member_i]
+    }
+
+    fn select_cluster(&mut self, cluster_i: usize) {
+        self.cluster_i = cluster_i;
+        self.member_i = 0;
+        self.scroll = 0;
+    }
+
+    fn select_member(&mut self, member_i: usize) {
+        self.member_i = member_i;
+        self.scroll = 0;
+    }
+
+    fn handle_key(&mut self, key: KeyEvent, page_height: usize) -> bool {
+        let num_members = self.clusters[self.cluster_i].members.len();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Down | KeyCode::Char('j') if self.member_i + 1 < num_members => {
+                self.select_member(self.member_i + 1);
+            }
+            KeyCode::Up | KeyCode::Char('k') if self.member_i > 0 => {
+                self.select_member(self.member_i - 1);
+            }
+            KeyCode::Right | KeyCode::Char('l') | KeyCode::Tab
+                if self.cluster_i + 1 < self.clusters.len() =>
+            {
+                self.select_cluster(self.cluster_i + 1);
+            }
+            KeyCode::Left | KeyCode::Char('h') | KeyCode::BackTab if self.cluster_i > 0 => {
+                self.select_cluster(self.cluster_i - 1);
+            }
+            KeyCode::PageDown | KeyCode::Char(' ') | KeyCode::Char('d') => {
+                let max_scroll = self.diff_lines.len().saturating_sub(page_height);
+                self.scroll = std::cmp::min(self.scroll + page_height, max_scroll);
+            }
+            KeyCode::PageUp | KeyCode::Char('u') => {
+                self.scroll = self.scroll.saturating_sub(page_height);
+            }
+            KeyCode::Home | KeyCode::Char('g') => {
+                self.scroll = 0;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn ensure_rendered(
+        &mut self,
+        diff_width: usize,
+        render: &mut impl FnMut(&MemberFiles, usize) -> Vec<String>,
+    ) {
+        let key = (self.cluster_i, self.member_i, diff_width);
+        if self.rendered_for == Some(key) {
+            return;
+        }
+
+        let member = self.selected().clone();
+        match member_files(self.code_dir, &member) {
+            Some(files) => {
+                self.diff_lines = render(&files, diff_width);
+                self.commit_message = files
+                    .commit_message
+                    .as_deref()
+                    .and_then(|message| message.lines().next())
+                    .unwrap_or("")
+                    .to_owned();
+            }
+            None => {
+                self.diff_lines = vec![format!(
+                    "No before/after files for {} {} in {}",
+                    member.repo,
+                    member.commit,
+                    self.code_dir.display()
+                )];
+                self.commit_message = String::new();
+            }
+        }
+        self.rendered_for = Some(key);
+    }
+
+    fn draw(&self, out: &mut impl Write, cols: usize, rows: usize) -> io::Result<()> {
+        let list_width = std::cmp::min(LIST_MAX_WIDTH, cols / 4);
+        let body_height = rows.saturating_sub(2);
+        let cluster = &self.clusters[self.cluster_i];
+
+        queue!(out, terminal::Clear(ClearType::All))?;
+
+        let threshold = match cluster.distance_threshold {
+            Some(threshold) => format!(" dis_thresh={}", threshold),
+            None => String::new(),
+        };
+        let title = format!(
+            "Cluster {}/{}{} | member {}/{} | j/k: member  h/l: cluster  PgUp/PgDn: scroll  q: quit",
+            self.cluster_i + 1,
+            self.clusters.len(),
+            threshold,
+            self.member_i + 1,
+            cluster.members.len()
+        );
+        queue!(
+            out,
+            cursor::MoveTo(0, 0),
+            SetAttribute(Attribute::Reverse),
+            Print(truncate(&title, cols)),
+            SetAttribute(Attribute::Reset)
+        )?;
+
+        // Keep the selected member visible in the list.
+        let list_start = (self.member_i + 1).saturating_sub(body_height);
+        for row in 0..body_height {
+            let y = (row + 1) as u16;
+            queue!(out, cursor::MoveTo(0, y))?;
+
+            if let Some(member) = cluster.members.get(list_start + row) {
+                let label = format!("{} {}", member.repo, member.commit);
+                if list_start + row == self.member_i {
+                    queue!(
+                        out,
+                        SetAttribute(Attribute::Reverse),
+                        Print(truncate(&label, list_width)),
+                        SetAttribute(Attribute::Reset)
+                    )?;
+                } else {
+                    queue!(out, Print(truncate(&label, list_width)))?;
+                }
+            }
+
+            queue!(out, cursor::MoveTo(list_width as u16, y), Print("|"))?;
+            if let Some(line) = self.diff_lines.get(self.scroll + row) {
+                queue!(out, Print(line), ResetColor)?;
+            }
+        }
+
+        queue!(
+            out,
+            cursor::MoveTo(0, rows.saturating_sub(1) as u16),
+            Print(truncate(&self.commit_message, cols))
+        )?;
+        out.flush()
+    }
+}
+
+/// Browse `clusters` interactively. `render` is called with the
+/// member's files and the available width, and should return the
+/// lines of the diff to display.
+pub fn run(
+    clusters: &[Cluster],
+    code_dir: &Path,
+    mut render: impl FnMut(&MemberFiles, usize) -> Vec<String>,
+) -> io::Result<()> {
+    if clusters.is_empty() {
+        eprintln!("No clusters found.");
+        return Ok(());
+    }
+
+    let _guard = TerminalGuard::new()?;
+    let mut viewer = Viewer {
+        clusters,
+        code_dir,
+        cluster_i: 0,
+        member_i: 0,
+        scroll: 0,
+        diff_lines: vec![],
+        rendered_for: None,
+        commit_message: String::new(),
+    };
+
+    let stdout = io::stdout();
+    loop {
+        let (cols, rows) = terminal::size()?;
+        let (cols, rows) = (cols as usize, rows as usize);
+        let list_width = std::cmp::min(LIST_MAX_WIDTH, cols / 4);
+        let diff_width = cols.saturating_sub(list_width + 1);
+
+        viewer.ensure_rendered(diff_width, &mut render);
+        viewer.draw(&mut stdout.lock(), cols, rows)?;
+
+        let keep_running = match event::read()? {
+            Event::Key(key) => viewer.handle_key(key, rows.saturating_sub(2)),
+            // The next iteration re-renders at the new size.
+            _ => true,
+        };
+        if !keep_running {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_member() {
+        assert_eq!(
+            parse_member("('bat', '1a2b3c')"),
+            Some(ClusterMember {
+                repo: "bat".into(),
+                commit: "1a2b3c".into()
+            })
+        );
+        assert_eq!(parse_member("not a member"), None);
+    }
+
+    #[test]
+    fn test_parse_hac_results() {
+        let src = "dis_thresh = 2:
+('bat', 'aaa')
+('fd', 'bbb')
+-----------------------------
+('cargo', 'ccc')
+-----------------------------
+";
+        let clusters = parse_hac_results(src);
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].distance_threshold, Some(2));
+        assert_eq!(clusters[0].members.len(), 2);
+        assert_eq!(clusters[1].members[0].repo, "cargo");
+    }
+
+    #[test]
+    fn test_draw_empty_terminal() {
+        let clusters = parse_hac_results("('bat', 'aaa')\n");
+        let viewer = Viewer {
+            clusters: &clusters,
+            code_dir: Path::new("."),
+            cluster_i: 0,
+            member_i: 0,
+            scroll: 0,
+            diff_lines: vec![],
+            rendered_for: None,
+            commit_message: String::new(),
+        };
+
+        let mut out = vec![];
+        viewer.draw(&mut out, 0, 0).unwrap();
+    }
+}
@@ -10,6 +10,10 @@ use crate::parse::syntax::Syntax;
 pub enum ChangeKind<'a> {
     Unchanged(&'a Syntax<'a>),
     ReplacedComment(&'a Syntax<'a>, &'a Syntax<'a>),
+    /// An atom replaced by a similar atom of the same kind, e.g. a
+    /// renamed identifier. The first node is this one, the second is
+    /// the node on the other side.
+    ReplacedAtom(&'a Syntax<'a>, &'a Syntax<'a>),
     Novel,
 }
 
@@ -26,6 +30,12 @@ impl<'a> ChangeMap<'a> {
     pub fn get(&self, node: &Syntax<'a>) -> Option<ChangeKind<'a>> {
         self.changes.get(&node.id()).copied()
     }
+
+    /// Add all the changes in `other`, overwriting any existing
+    /// changes for the same nodes.
+    pub fn extend(&mut self, other: ChangeMap<'a>) {
+        self.changes.extend(other.changes);
+    }
 }
 
 pub fn insert_deep_unchanged<'a>(
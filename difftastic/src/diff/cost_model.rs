@@ -0,0 +1,162 @@
+//! The costs of each kind of edge in the diff graph.
+//!
+//! The defaults produce the most readable diffs for humans. When
+//! mining, it can be useful to bias the alignment, e.g. raising
+//! `novel_delimiter` makes the search less willing to split a call
+//! expression by marking its delimiters as novel.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    /// An unchanged node costs its depth difference plus one, up to
+    /// this value.
+    pub unchanged_max_depth_penalty: u32,
+    /// The cost of entering a list whose delimiters are unchanged,
+    /// before adding the depth difference.
+    pub enter_unchanged_delimiter: u32,
+    /// The cost of a replaced comment, before adding the percentage
+    /// of the content that differs.
+    pub replaced_comment: u32,
+    /// The cost of replacing an atom with a similar atom of the same
+    /// kind, before adding the percentage of the content that
+    /// differs.
+    pub replaced_atom: u32,
+    /// How similar, as a percentage, two atoms must be to be treated
+    /// as a replacement rather than a removal and an addition.
+    pub replaced_atom_min_similarity: u32,
+    pub novel_atom: u32,
+    pub novel_delimiter: u32,
+    /// Added to novel atoms and delimiters that aren't next to the
+    /// previous novel node.
+    pub not_contiguous_penalty: u32,
+    /// Subtracted from novel punctuation atoms. It's better to have
+    /// novel punctuation than novel variable names.
+    pub punctuation_discount: u32,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            unchanged_max_depth_penalty: 40,
+            enter_unchanged_delimiter: 100,
+            replaced_comment: 150,
+            replaced_atom: 250,
+            replaced_atom_min_similarity: 50,
+            novel_atom: 300,
+            novel_delimiter: 300,
+            not_contiguous_penalty: 50,
+            punctuation_discount: 10,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CostModelError(pub String);
+
+impl fmt::Display for CostModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl CostModel {
+    /// The lowest cost of any edge that marks a node as novel. The A*
+    /// heuristic relies on this being a lower bound.
+    pub fn min_novel_cost(&self) -> u32 {
+        std::cmp::min(
+            self.novel_atom.saturating_sub(self.punctuation_discount),
+            self.novel_delimiter,
+        )
+    }
+
+    /// Set the cost called `name` to `value`.
+    pub fn set(&mut self, name: &str, value: u32) -> Result<(), CostModelError> {
+        let field = match name {
+            "unchanged_max_depth_penalty" => &mut self.unchanged_max_depth_penalty,
+            "enter_unchanged_delimiter" => &mut self.enter_unchanged_delimiter,
+            "replaced_comment" => &mut self.replaced_comment,
+            "replaced_atom" => &mut self.replaced_atom,
+            "replaced_atom_min_similarity" => &mut self.replaced_atom_min_similarity,
+            "novel_atom" => &mut self.novel_atom,
+            "novel_delimiter" => &mut self.novel_delimiter,
+            "not_contiguous_penalty" => &mut self.not_contiguous_penalty,
+            "punctuation_discount" => &mut self.punctuation_discount,
+            _ => return Err(CostModelError(format!("Unknown cost: {}", name))),
+        };
+        *field = value;
+        Ok(())
+    }
+
+    /// Apply a `name=value` override, as given on the command line.
+    pub fn set_from_str(&mut self, assignment: &str) -> Result<(), CostModelError> {
+        let (name, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| CostModelError(format!("Expected NAME=VALUE, got: {}", assignment)))?;
+
+        let value = value.trim().parse::<u32>().map_err(|_| {
+            CostModelError(format!(
+                "Expected a non-negative integer for {}, got: {}",
+                name.trim(),
+                value.trim()
+            ))
+        })?;
+        self.set(name.trim(), value)
+    }
+
+    /// Parse a cost model file. Each line is `name = value`, and
+    /// blank lines and lines starting with `#` are ignored. Costs not
+    /// mentioned keep their default values.
+    pub fn from_file_content(src: &str) -> Result<Self, CostModelError> {
+        let mut res = Self::default();
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            res.set_from_str(line)
+                .map_err(|CostModelError(e)| CostModelError(format!("line {}: {}", i + 1, e)))?;
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_file_content() {
+        let model = CostModel::from_file_content(
+            "# Don't split calls.
+novel_delimiter = 500
+
+novel_atom=250
+",
+        )
+        .unwrap();
+
+        assert_eq!(model.novel_delimiter, 500);
+        assert_eq!(model.novel_atom, 250);
+        assert_eq!(
+            model.replaced_comment,
+            CostModel::default().replaced_comment
+        );
+    }
+
+    #[test]
+    fn test_unknown_cost() {
+        let res = CostModel::from_file_content("novel_atom = 1\nnovel_thing = 2");
+        assert_eq!(
+            res,
+            Err(CostModelError("line 2: Unknown cost: novel_thing".into()))
+        );
+    }
+
+    #[test]
+    fn test_min_novel_cost() {
+        let model = CostModel::default();
+        assert_eq!(model.min_novel_cost(), 290);
+    }
+}
@@ -1,48 +1,274 @@
 //! Implements Dijkstra's algorithm for shortest path, to find an
 //! optimal and readable diff between two ASTs.
 
-use std::{cmp::Reverse, env};
+use std::{
+    cmp::Reverse,
+    env,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
     diff::changes::ChangeMap,
+    diff::cost_model::CostModel,
     diff::graph::{get_set_neighbours, populate_change_map, Edge, Vertex},
-    parse::syntax::Syntax,
+    parse::syntax::{Syntax, SyntaxId},
 };
 use bumpalo::Bump;
 use itertools::Itertools;
 use radix_heap::RadixHeapMap;
 use rustc_hash::FxHashMap;
 
-#[derive(Debug)]
-pub struct ExceededGraphLimit {}
+pub use crate::diff::graph::EDGE_KINDS;
+
+/// Why the search gave up before reaching the end vertex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceededGraphLimit {
+    /// We saw more than `graph_limit` vertices.
+    Vertices,
+    /// The vertex arenas and `seen` maps of all the searches running
+    /// with a memory budget used more than the budget.
+    Memory { peak_bytes: usize },
+}
+
+/// What the search did to find a route, reported by `--stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    /// The number of vertices seen before reaching the end vertex.
+    pub vertices: usize,
+    /// The total cost of the route.
+    pub cost: u64,
+    /// The number of edges of each kind on the route, indexed by
+    /// `Edge::kind_index`.
+    pub edge_counts: [usize; EDGE_KINDS.len()],
+}
+
+impl SearchStats {
+    /// Add the stats of another search, e.g. for another section of
+    /// the same file.
+    pub fn add(&mut self, other: &SearchStats) {
+        self.vertices += other.vertices;
+        self.cost += other.cost;
+        for (count, other_count) in self.edge_counts.iter_mut().zip(other.edge_counts) {
+            *count += other_count;
+        }
+    }
+}
+
+type SeenMap<'a, 'b> = FxHashMap<&'b Vertex<'a, 'b>, Vec<&'b Vertex<'a, 'b>>>;
+
+/// The size of an entry in the `seen` map, not counting the vec of
+/// alternatives.
+fn seen_entry_bytes() -> usize {
+    std::mem::size_of::<(&Vertex, Vec<&Vertex>)>()
+}
+
+/// An estimate of the bytes used by the search: the vertex arena, the
+/// `seen` table, and the vec of alternatives for each vertex, which
+/// has at most two entries.
+fn search_bytes(vertex_arena: &Bump, seen: &SeenMap) -> usize {
+    // Each bucket in the table also has a control byte.
+    let table_bytes = seen.capacity() * (seen_entry_bytes() + 1);
+    let alternatives_bytes = seen.len() * 2 * std::mem::size_of::<&Vertex>();
+    vertex_arena.allocated_bytes() + table_bytes + alternatives_bytes
+}
+
+/// The bytes used by all the searches running with a memory budget.
+/// Sections and files are diffed in parallel, so the budget applies
+/// to all of them together rather than to each search.
+static BUDGETED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The bytes that one search has added to `BUDGETED_BYTES`. They're
+/// released when the search finishes.
+#[derive(Default)]
+struct BudgetReservation {
+    bytes: usize,
+}
+
+impl BudgetReservation {
+    /// Grow the reservation to `bytes`, and return the bytes used by
+    /// all the searches.
+    fn update(&mut self, bytes: usize) -> usize {
+        if bytes > self.bytes {
+            let extra = bytes - self.bytes;
+            self.bytes = bytes;
+            BUDGETED_BYTES.fetch_add(extra, Ordering::Relaxed) + extra
+        } else {
+            BUDGETED_BYTES.load(Ordering::Relaxed)
+        }
+    }
+}
+
+impl Drop for BudgetReservation {
+    fn drop(&mut self) {
+        BUDGETED_BYTES.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// How to search the diff graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchAlgorithm {
+    Dijkstra,
+    /// Dijkstra guided by a lower bound on the remaining cost. When
+    /// the two sides differ a lot in size, this visits fewer vertices,
+    /// so larger sections fit in `DFT_GRAPH_LIMIT`.
+    ///
+    /// The graph is path dependent (see `Vertex::eq`): the first route
+    /// to reach a vertex decides its delimiter stack. A* reaches
+    /// vertices in a different order, so it can find a more expensive
+    /// route than Dijkstra. See `bench_search` for a comparison.
+    AStar,
+}
+
+impl Default for SearchAlgorithm {
+    fn default() -> Self {
+        SearchAlgorithm::Dijkstra
+    }
+}
+
+/// The number of nodes left on each side of a vertex, used as the
+/// A* heuristic.
+///
+/// Every edge that consumes nodes on both sides consumes the same
+/// number on each side (unchanged subtrees have identical shapes), so
+/// the difference in remaining node counts can only be closed by
+/// novel edges, each of which costs at least
+/// `CostModel::min_novel_cost`. That makes the heuristic admissible,
+/// as long as the counts include the nodes after the enclosing lists
+/// when a side has reached the end of a list.
+///
+/// We can't use `SyntaxInfo::num_after` here: it only counts
+/// siblings, and it counts siblings in later sections too.
+struct RemainingNodes {
+    lhs: PreorderPositions,
+    rhs: PreorderPositions,
+    min_novel_cost: u32,
+}
+
+/// The position of each node in a pre-order traversal of a section,
+/// and the position just after its descendants.
+struct PreorderPositions {
+    positions: FxHashMap<SyntaxId, (u32, u32)>,
+    total: u32,
+}
+
+impl PreorderPositions {
+    fn new(root: Option<&Syntax>) -> Self {
+        fn walk(node: &Syntax, positions: &mut FxHashMap<SyntaxId, (u32, u32)>, next: &mut u32) {
+            let position = *next;
+            *next += 1;
+            if let Syntax::List { children, .. } = node {
+                for child in children {
+                    walk(child, positions, next);
+                }
+            }
+            positions.insert(node.id(), (position, *next));
+        }
+
+        let mut positions = FxHashMap::default();
+        let mut next = 0;
+        let mut node = root;
+        while let Some(current) = node {
+            walk(current, &mut positions, &mut next);
+            node = current.next_sibling();
+        }
+
+        Self {
+            positions,
+            total: next,
+        }
+    }
+
+    /// The number of nodes from `node` onwards. If we're at the end of
+    /// a list, that's the number of nodes after the innermost list
+    /// that we haven't exited yet, `parent_id`.
+    fn remaining(&self, node: Option<&Syntax>, parent_id: Option<SyntaxId>) -> u32 {
+        let start = match (node, parent_id) {
+            (Some(node), _) => self
+                .positions
+                .get(&node.id())
+                .map(|(position, _)| *position),
+            (None, Some(parent_id)) => self.positions.get(&parent_id).map(|(_, end)| *end),
+            (None, None) => None,
+        };
+        start.map_or(0, |start| self.total - start)
+    }
+}
+
+impl RemainingNodes {
+    fn new(start: &Vertex, cost_model: &CostModel) -> Self {
+        Self {
+            lhs: PreorderPositions::new(start.lhs_syntax),
+            rhs: PreorderPositions::new(start.rhs_syntax),
+            min_novel_cost: cost_model.min_novel_cost(),
+        }
+    }
+
+    fn lower_bound(&self, vertex: &Vertex) -> u32 {
+        let lhs = self.lhs.remaining(vertex.lhs_syntax, vertex.lhs_parent_id);
+        let rhs = self.rhs.remaining(vertex.rhs_syntax, vertex.rhs_parent_id);
+
+        let difference = if lhs > rhs { lhs - rhs } else { rhs - lhs };
+        difference.saturating_mul(self.min_novel_cost)
+    }
+}
 
-/// Return the shortest route from `start` to the end vertex.
+/// Return the shortest route from `start` to the end vertex, and the
+/// number of vertices seen.
 fn shortest_vertex_path<'a, 'b>(
     start: &'b Vertex<'a, 'b>,
     vertex_arena: &'b Bump,
     size_hint: usize,
     graph_limit: usize,
-) -> Result<Vec<&'b Vertex<'a, 'b>>, ExceededGraphLimit> {
-    // We want to visit nodes with the shortest distance first, but
-    // RadixHeapMap is a max-heap. Ensure nodes are wrapped with
-    // Reverse to flip comparisons.
+    memory_budget: Option<usize>,
+    algorithm: SearchAlgorithm,
+    cost_model: &CostModel,
+) -> Result<(Vec<&'b Vertex<'a, 'b>>, usize), ExceededGraphLimit> {
+    let remaining_nodes = match algorithm {
+        SearchAlgorithm::Dijkstra => None,
+        SearchAlgorithm::AStar => Some(RemainingNodes::new(start, cost_model)),
+    };
+    let lower_bound = |vertex: &Vertex| match &remaining_nodes {
+        Some(remaining_nodes) => remaining_nodes.lower_bound(vertex),
+        None => 0,
+    };
+
+    // We want to visit nodes with the lowest estimated total cost
+    // first, but RadixHeapMap is a max-heap. Ensure nodes are wrapped
+    // with Reverse to flip comparisons.
+    //
+    // For Dijkstra the estimate is just the distance so far.
     let mut heap: RadixHeapMap<Reverse<_>, &'b Vertex<'a, 'b>> = RadixHeapMap::new();
 
-    heap.push(Reverse(0), start);
+    heap.push(Reverse(lower_bound(start)), start);
 
-    let mut seen = FxHashMap::default();
-    seen.reserve(size_hint);
+    // We give up once we've seen `graph_limit` vertices, or used
+    // `memory_budget` bytes, so don't reserve more than that. The
+    // budget is shared with the other threads, so only reserve a
+    // share of it up front.
+    let mut capacity = std::cmp::min(size_hint, graph_limit);
+    if let Some(memory_budget) = memory_budget {
+        let share = memory_budget / rayon::current_num_threads();
+        capacity = std::cmp::min(capacity, share / (seen_entry_bytes() + 1));
+    }
+    let mut seen: SeenMap = FxHashMap::default();
+    seen.reserve(capacity);
+    let mut reservation = BudgetReservation::default();
+    let mut peak_bytes = 0;
 
     let end: &'b Vertex<'a, 'b> = loop {
         match heap.pop() {
-            Some((Reverse(distance), current)) => {
+            Some((Reverse(estimate), current)) => {
                 if current.is_end() {
                     break current;
                 }
 
-                for neighbour in &get_set_neighbours(current, vertex_arena, &mut seen) {
+                let distance = current
+                    .predecessor
+                    .get()
+                    .map_or(0, |(distance, _)| distance);
+                for neighbour in &get_set_neighbours(current, vertex_arena, &mut seen, cost_model) {
                     let (edge, next) = neighbour;
-                    let distance_to_next = distance + edge.cost();
+                    let distance_to_next = distance + edge.cost(cost_model);
 
                     let found_shorter_route = match next.predecessor.get() {
                         Some((prev_shortest, _)) => distance_to_next < prev_shortest,
@@ -51,12 +277,27 @@ fn shortest_vertex_path<'a, 'b>(
 
                     if found_shorter_route {
                         next.predecessor.replace(Some((distance_to_next, current)));
-                        heap.push(Reverse(distance_to_next), next);
+                        // The heuristic is admissible but not always
+                        // consistent, so never let the estimate
+                        // decrease along a route (the 'pathmax'
+                        // rule). The radix heap also requires that we
+                        // never push a key smaller than the last one
+                        // popped.
+                        let next_estimate =
+                            std::cmp::max(estimate, distance_to_next + lower_bound(next));
+                        heap.push(Reverse(next_estimate), next);
                     }
                 }
 
                 if seen.len() > graph_limit {
-                    return Err(ExceededGraphLimit {});
+                    return Err(ExceededGraphLimit::Vertices);
+                }
+                if let Some(memory_budget) = memory_budget {
+                    let budgeted_bytes = reservation.update(search_bytes(vertex_arena, &seen));
+                    peak_bytes = std::cmp::max(peak_bytes, budgeted_bytes);
+                    if peak_bytes > memory_budget {
+                        return Err(ExceededGraphLimit::Memory { peak_bytes });
+                    }
                 }
             }
             None => panic!("Ran out of graph nodes before reaching end"),
@@ -64,10 +305,11 @@ fn shortest_vertex_path<'a, 'b>(
     };
 
     info!(
-        "Saw {} vertices (a Vertex is {} bytes), with {} left on heap.",
+        "Saw {} vertices (a Vertex is {} bytes), with {} left on heap, using {} bytes.",
         seen.len(),
         std::mem::size_of::<Vertex>(),
         heap.len(),
+        search_bytes(vertex_arena, &seen),
     );
 
     let mut current = Some((0, end));
@@ -78,27 +320,31 @@ fn shortest_vertex_path<'a, 'b>(
     }
 
     vertex_route.reverse();
-    Ok(vertex_route)
+    Ok((vertex_route, seen.len()))
 }
 
+/// Return the edges of `route`, and stats with the cost of the route
+/// and the number of edges of each kind.
 fn shortest_path_with_edges<'a, 'b>(
     route: &[&'b Vertex<'a, 'b>],
-) -> Vec<(Edge, &'b Vertex<'a, 'b>)> {
+    cost_model: &CostModel,
+) -> (Vec<(Edge, &'b Vertex<'a, 'b>)>, SearchStats) {
     let mut prev = route.first().expect("Expected non-empty route");
 
-    let mut cost = 0;
+    let mut stats = SearchStats::default();
     let mut res = vec![];
 
     for vertex in route.iter().skip(1) {
-        let edge = edge_between(prev, vertex);
+        let edge = edge_between(prev, vertex, cost_model);
         res.push((edge, *prev));
-        cost += edge.cost();
+        stats.cost += u64::from(edge.cost(cost_model));
+        stats.edge_counts[edge.kind_index()] += 1;
 
         prev = vertex;
     }
-    debug!("Found a path of {} with cost {}.", route.len(), cost);
+    debug!("Found a path of {} with cost {}.", route.len(), stats.cost);
 
-    res
+    (res, stats)
 }
 
 /// Return the shortest route from the `start` to the end vertex.
@@ -110,13 +356,30 @@ fn shortest_path<'a, 'b>(
     vertex_arena: &'b Bump,
     size_hint: usize,
     graph_limit: usize,
-) -> Result<Vec<(Edge, &'b Vertex<'a, 'b>)>, ExceededGraphLimit> {
+    memory_budget: Option<usize>,
+    algorithm: SearchAlgorithm,
+    cost_model: &CostModel,
+) -> Result<(Vec<(Edge, &'b Vertex<'a, 'b>)>, SearchStats), ExceededGraphLimit> {
     let start: &'b Vertex<'a, 'b> = vertex_arena.alloc(start);
-    let vertex_path = shortest_vertex_path(start, vertex_arena, size_hint, graph_limit)?;
-    Ok(shortest_path_with_edges(&vertex_path))
+    let (vertex_path, vertices) = shortest_vertex_path(
+        start,
+        vertex_arena,
+        size_hint,
+        graph_limit,
+        memory_budget,
+        algorithm,
+        cost_model,
+    )?;
+    let (route, mut stats) = shortest_path_with_edges(&vertex_path, cost_model);
+    stats.vertices = vertices;
+    Ok((route, stats))
 }
 
-fn edge_between<'a, 'b>(before: &Vertex<'a, 'b>, after: &Vertex<'a, 'b>) -> Edge {
+fn edge_between<'a, 'b>(
+    before: &Vertex<'a, 'b>,
+    after: &Vertex<'a, 'b>,
+    cost_model: &CostModel,
+) -> Edge {
     assert_ne!(before, after);
 
     let mut shortest_edge: Option<Edge> = None;
@@ -127,7 +390,7 @@ fn edge_between<'a, 'b>(before: &Vertex<'a, 'b>, after: &Vertex<'a, 'b>) -> Edge
             // prefer the shortest.
             if *next == *after {
                 let is_shorter = match shortest_edge {
-                    Some(prev_edge) => edge.cost() < prev_edge.cost(),
+                    Some(prev_edge) => edge.cost(cost_model) < prev_edge.cost(cost_model),
                     None => true,
                 };
 
@@ -184,10 +447,13 @@ pub fn mark_syntax<'a>(
     rhs_syntax: Option<&'a Syntax<'a>>,
     change_map: &mut ChangeMap<'a>,
     graph_limit: usize,
-) -> Result<(), ExceededGraphLimit> {
+    memory_budget: Option<usize>,
+    algorithm: SearchAlgorithm,
+    cost_model: &CostModel,
+) -> Result<SearchStats, ExceededGraphLimit> {
     let lhs_node_count = node_count(lhs_syntax) as usize;
     let rhs_node_count = node_count(rhs_syntax) as usize;
-    println!(
+    info!(
         "LHS nodes: {} ({} toplevel), RHS nodes: {} ({} toplevel)",
         lhs_node_count,
         tree_count(lhs_syntax),
@@ -204,7 +470,15 @@ pub fn mark_syntax<'a>(
     let start = Vertex::new(lhs_syntax, rhs_syntax);
     let vertex_arena = Bump::new();
 
-    let route = shortest_path(start, &vertex_arena, size_hint, graph_limit)?;
+    let (route, stats) = shortest_path(
+        start,
+        &vertex_arena,
+        size_hint,
+        graph_limit,
+        memory_budget,
+        algorithm,
+        cost_model,
+    )?;
 
     let print_length = if env::var("DFT_VERBOSE").is_ok() {
         50
@@ -223,7 +497,7 @@ pub fn mark_syntax<'a>(
                         .map_or_else(|| "None".into(), Syntax::dbg_content),
                     x.1.rhs_syntax
                         .map_or_else(|| "None".into(), Syntax::dbg_content),
-                    x.0.cost(),
+                    x.0.cost(cost_model),
                     x.0,
                 )
             })
@@ -232,7 +506,7 @@ pub fn mark_syntax<'a>(
     );
 
     populate_change_map(&route, change_map);
-    Ok(())
+    Ok(stats)
 }
 
 #[cfg(test)]
@@ -242,6 +516,10 @@ mod tests {
         diff::changes::ChangeKind,
         diff::graph::Edge::*,
         options::DEFAULT_GRAPH_LIMIT,
+        parse::{
+            guess_language,
+            tree_sitter_parser::{from_language, parse},
+        },
         positions::SingleLineSpan,
         syntax::{init_all_info, AtomKind},
     };
@@ -276,7 +554,16 @@ mod tests {
 
         let start = Vertex::new(Some(lhs), Some(rhs));
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -317,7 +604,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -362,7 +658,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -414,7 +719,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -452,7 +766,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -496,7 +819,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -537,7 +869,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -577,7 +918,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -609,7 +959,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -649,7 +1008,16 @@ mod tests {
 
         let start = Vertex::new(lhs.get(0).copied(), rhs.get(0).copied());
         let vertex_arena = Bump::new();
-        let route = shortest_path(start, &vertex_arena, 0, DEFAULT_GRAPH_LIMIT).unwrap();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         let actions = route.iter().map(|(action, _)| *action).collect_vec();
         assert_eq!(
@@ -674,12 +1042,54 @@ mod tests {
         init_all_info(&[lhs], &[rhs]);
 
         let mut change_map = ChangeMap::default();
-        mark_syntax(Some(lhs), Some(rhs), &mut change_map, DEFAULT_GRAPH_LIMIT).unwrap();
+        mark_syntax(
+            Some(lhs),
+            Some(rhs),
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
 
         assert_eq!(change_map.get(lhs), Some(ChangeKind::Unchanged(rhs)));
         assert_eq!(change_map.get(rhs), Some(ChangeKind::Unchanged(lhs)));
     }
 
+    #[test]
+    fn mark_syntax_stats() {
+        let arena = Arena::new();
+        let lhs = [Syntax::new_atom(
+            &arena,
+            col_helper(1, 0),
+            "foo",
+            AtomKind::Normal,
+        )];
+        let rhs = [
+            Syntax::new_atom(&arena, col_helper(1, 0), "foo", AtomKind::Normal),
+            Syntax::new_atom(&arena, col_helper(1, 4), "bar", AtomKind::Normal),
+        ];
+        init_all_info(&lhs, &rhs);
+
+        let mut change_map = ChangeMap::default();
+        let stats = mark_syntax(
+            lhs.first().copied(),
+            rhs.first().copied(),
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
+
+        assert!(stats.vertices > 0);
+        // One unchanged node and one novel atom on the RHS.
+        assert_eq!(stats.edge_counts, [1, 0, 0, 0, 0, 1, 0, 0]);
+        assert!(stats.cost > u64::from(CostModel::default().novel_atom));
+    }
+
     #[test]
     fn mark_syntax_different_atoms() {
         let arena = Arena::new();
@@ -688,8 +1098,236 @@ mod tests {
         init_all_info(&[lhs], &[rhs]);
 
         let mut change_map = ChangeMap::default();
-        mark_syntax(Some(lhs), Some(rhs), &mut change_map, DEFAULT_GRAPH_LIMIT).unwrap();
+        mark_syntax(
+            Some(lhs),
+            Some(rhs),
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
+        assert_eq!(change_map.get(lhs), Some(ChangeKind::Novel));
+        assert_eq!(change_map.get(rhs), Some(ChangeKind::Novel));
+    }
+
+    #[test]
+    fn replaced_similar_atoms() {
+        let arena = Arena::new();
+        let lhs = Syntax::new_atom(&arena, pos_helper(0), "user_name", AtomKind::Normal);
+        let rhs = Syntax::new_atom(&arena, pos_helper(0), "user_names", AtomKind::Normal);
+        init_all_info(&[lhs], &[rhs]);
+
+        let mut change_map = ChangeMap::default();
+        mark_syntax(
+            Some(lhs),
+            Some(rhs),
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            change_map.get(lhs),
+            Some(ChangeKind::ReplacedAtom(lhs, rhs))
+        );
+        assert_eq!(
+            change_map.get(rhs),
+            Some(ChangeKind::ReplacedAtom(rhs, lhs))
+        );
+    }
+
+    #[test]
+    fn replaced_atoms_different_kinds() {
+        let arena = Arena::new();
+        let lhs = Syntax::new_atom(&arena, pos_helper(0), "\"user\"", AtomKind::String);
+        let rhs = Syntax::new_atom(&arena, pos_helper(0), "user", AtomKind::Normal);
+        init_all_info(&[lhs], &[rhs]);
+
+        let mut change_map = ChangeMap::default();
+        mark_syntax(
+            Some(lhs),
+            Some(rhs),
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+        )
+        .unwrap();
+
         assert_eq!(change_map.get(lhs), Some(ChangeKind::Novel));
         assert_eq!(change_map.get(rhs), Some(ChangeKind::Novel));
     }
+
+    #[test]
+    fn replaced_comment_too_expensive() {
+        let arena = Arena::new();
+        let lhs = Syntax::new_atom(&arena, pos_helper(0), "// foo bar", AtomKind::Comment);
+        let rhs = Syntax::new_atom(&arena, pos_helper(0), "// foo baz", AtomKind::Comment);
+        init_all_info(&[lhs], &[rhs]);
+
+        let cost_model = CostModel {
+            replaced_comment: 1000,
+            ..CostModel::default()
+        };
+
+        let start = Vertex::new(Some(lhs), Some(rhs));
+        let vertex_arena = Bump::new();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &cost_model,
+        )
+        .unwrap();
+
+        let actions = route.iter().map(|(action, _)| *action).collect_vec();
+        assert_eq!(
+            actions,
+            vec![
+                NovelAtomRHS {
+                    contiguous: false,
+                    probably_punctuation: false
+                },
+                NovelAtomLHS {
+                    contiguous: false,
+                    probably_punctuation: false
+                },
+            ]
+        );
+    }
+
+    fn route_cost<'a>(
+        lhs: &[&'a Syntax<'a>],
+        rhs: &[&'a Syntax<'a>],
+        graph_limit: usize,
+        algorithm: SearchAlgorithm,
+    ) -> Result<u32, ExceededGraphLimit> {
+        let start = Vertex::new(lhs.first().copied(), rhs.first().copied());
+        let vertex_arena = Bump::new();
+        let cost_model = CostModel::default();
+        let (route, _) = shortest_path(
+            start,
+            &vertex_arena,
+            0,
+            graph_limit,
+            None,
+            algorithm,
+            &cost_model,
+        )?;
+        Ok(route.iter().map(|(edge, _)| edge.cost(&cost_model)).sum())
+    }
+
+    /// JavaScript with `num_lines` statements. On the RHS, every third
+    /// statement is changed, and if `insert` is set, a new statement
+    /// is added after every second one.
+    fn changed_statements(num_lines: usize, insert: bool) -> (String, String) {
+        let mut lhs_src = String::new();
+        let mut rhs_src = String::new();
+        for i in 0..num_lines {
+            lhs_src.push_str(&format!("foo({}, bar[{}]);\n", i, i));
+            if i % 3 == 0 {
+                rhs_src.push_str(&format!("foo(baz({}), [{}]);\n", i, i + 1));
+            } else {
+                rhs_src.push_str(&format!("foo({}, bar[{}]);\n", i, i));
+            }
+            if insert && i % 2 == 0 {
+                rhs_src.push_str(&format!("if (x) {{ qux({}); }}\n", i));
+            }
+        }
+        (lhs_src, rhs_src)
+    }
+
+    #[test]
+    fn astar_same_cost_as_dijkstra() {
+        let config = from_language(guess_language::Language::JavaScript);
+        let examples = [
+            ("a; b; c;", "b; c; d;"),
+            ("f(x, [1, 2]);", "f([x, 1], 2);"),
+            ("{ x; y; }", "x; { y; }"),
+            // Routes that reach the end of an inner list on one side
+            // while nodes remain after it.
+            ("f([1], 2, 3, 4, 5);", "f([1, 9], 2, 3, 4, 5);"),
+            ("f([1, 9], 2, 3, 4, 5);", "f([1], 2, 3, 4, 5);"),
+            ("g({ a: [x] }, y, z);", "g({ a: [x, w], b: v }, y, z);"),
+        ];
+
+        for (lhs_src, rhs_src) in examples.iter() {
+            let arena = Arena::new();
+            let lhs = parse(&arena, lhs_src, &config, false);
+            let rhs = parse(&arena, rhs_src, &config, false);
+            init_all_info(&lhs, &rhs);
+
+            let dijkstra_cost =
+                route_cost(&lhs, &rhs, DEFAULT_GRAPH_LIMIT, SearchAlgorithm::Dijkstra).unwrap();
+            let astar_cost =
+                route_cost(&lhs, &rhs, DEFAULT_GRAPH_LIMIT, SearchAlgorithm::AStar).unwrap();
+            assert_eq!(dijkstra_cost, astar_cost, "{} vs {}", lhs_src, rhs_src);
+        }
+    }
+
+    #[test]
+    fn test_budget_shared_between_searches() {
+        let mut first = BudgetReservation::default();
+        let mut second = BudgetReservation::default();
+
+        assert!(first.update(1000) >= 1000);
+        assert!(second.update(500) >= 1500);
+        // Shrinking doesn't release anything until the search finishes.
+        assert!(first.update(10) >= 1500);
+    }
+
+    /// Compare the two searches on a larger input. Run with:
+    ///
+    /// ```text
+    /// cargo test --release bench_search -- --ignored --nocapture
+    /// ```
+    #[test]
+    #[ignore]
+    fn bench_search() {
+        let config = from_language(guess_language::Language::JavaScript);
+
+        for (insert, algorithm) in [
+            (false, SearchAlgorithm::Dijkstra),
+            (false, SearchAlgorithm::AStar),
+            (true, SearchAlgorithm::Dijkstra),
+            (true, SearchAlgorithm::AStar),
+        ] {
+            let (lhs_src, rhs_src) = changed_statements(60, insert);
+            let arena = Arena::new();
+            let lhs = parse(&arena, &lhs_src, &config, false);
+            let rhs = parse(&arena, &rhs_src, &config, false);
+            init_all_info(&lhs, &rhs);
+
+            let start = std::time::Instant::now();
+            let vertex_arena = Bump::new();
+            let cost_model = CostModel::default();
+            let (_, stats) = shortest_path(
+                Vertex::new(lhs.first().copied(), rhs.first().copied()),
+                &vertex_arena,
+                0,
+                usize::MAX,
+                None,
+                algorithm,
+                &cost_model,
+            )
+            .unwrap();
+            println!(
+                "{:?} (insertions: {}): cost {}, {} vertices, took {:?}",
+                algorithm,
+                insert,
+                stats.cost,
+                stats.vertices,
+                start.elapsed()
+            );
+        }
+    }
 }
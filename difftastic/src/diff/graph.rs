@@ -13,6 +13,7 @@ use strsim::normalized_levenshtein;
 use crate::{
     diff::{
         changes::{insert_deep_unchanged, ChangeKind, ChangeMap},
+        cost_model::CostModel,
         stack::Stack,
     },
     parse::syntax::{AtomKind, Syntax, SyntaxId},
@@ -56,8 +57,9 @@ pub struct Vertex<'a, 'b> {
     pub lhs_syntax: Option<&'a Syntax<'a>>,
     pub rhs_syntax: Option<&'a Syntax<'a>>,
     parents: Stack<EnteredDelimiter<'a>>,
-    lhs_parent_id: Option<SyntaxId>,
-    rhs_parent_id: Option<SyntaxId>,
+    /// The innermost list on each side that we haven't exited yet.
+    pub lhs_parent_id: Option<SyntaxId>,
+    pub rhs_parent_id: Option<SyntaxId>,
 }
 
 impl<'a, 'b> PartialEq for Vertex<'a, 'b> {
@@ -283,6 +285,9 @@ pub enum Edge {
     ReplacedComment {
         levenshtein_pct: u8,
     },
+    ReplacedAtom {
+        similarity_pct: u8,
+    },
     NovelAtomLHS {
         contiguous: bool,
         probably_punctuation: bool,
@@ -301,18 +306,54 @@ pub enum Edge {
     },
 }
 
-const NOT_CONTIGUOUS_PENALTY: u32 = 50;
+/// The name of each kind of edge, indexed by [`Edge::kind_index`].
+pub const EDGE_KINDS: [&str; 8] = [
+    "UnchangedNode",
+    "EnterUnchangedDelimiter",
+    "ReplacedComment",
+    "ReplacedAtom",
+    "NovelAtomLHS",
+    "NovelAtomRHS",
+    "EnterNovelDelimiterLHS",
+    "EnterNovelDelimiterRHS",
+];
 
 impl Edge {
-    pub fn cost(self) -> u32 {
+    /// The position of this kind of edge in [`EDGE_KINDS`].
+    pub fn kind_index(self) -> usize {
+        match self {
+            UnchangedNode { .. } => 0,
+            EnterUnchangedDelimiter { .. } => 1,
+            ReplacedComment { .. } => 2,
+            ReplacedAtom { .. } => 3,
+            NovelAtomLHS { .. } => 4,
+            NovelAtomRHS { .. } => 5,
+            EnterNovelDelimiterLHS { .. } => 6,
+            EnterNovelDelimiterRHS { .. } => 7,
+        }
+    }
+
+    pub fn cost(self, cost_model: &CostModel) -> u32 {
         match self {
             // Matching nodes is always best.
-            UnchangedNode { depth_difference } => min(40, depth_difference + 1),
+            UnchangedNode { depth_difference } => {
+                min(cost_model.unchanged_max_depth_penalty, depth_difference + 1)
+            }
             // Matching an outer delimiter is good.
-            EnterUnchangedDelimiter { depth_difference } => 100 + min(40, depth_difference),
+            EnterUnchangedDelimiter { depth_difference } => {
+                cost_model.enter_unchanged_delimiter
+                    + min(cost_model.unchanged_max_depth_penalty, depth_difference)
+            }
 
             // Replacing a comment is better than treating it as novel.
-            ReplacedComment { levenshtein_pct } => 150 + u32::from(100 - levenshtein_pct),
+            ReplacedComment { levenshtein_pct } => {
+                cost_model.replaced_comment + u32::from(100 - levenshtein_pct)
+            }
+            // Replacing an atom with a similar one is better than
+            // treating both as novel.
+            ReplacedAtom { similarity_pct } => {
+                cost_model.replaced_atom + u32::from(100 - similarity_pct)
+            }
 
             // Otherwise, we've added/removed a node.
             NovelAtomLHS {
@@ -323,20 +364,20 @@ impl Edge {
                 contiguous,
                 probably_punctuation,
             } => {
-                let mut cost = 300;
+                let mut cost = cost_model.novel_atom;
                 if !contiguous {
-                    cost += NOT_CONTIGUOUS_PENALTY;
+                    cost += cost_model.not_contiguous_penalty;
                 }
                 // If it's only punctuation, decrease the cost
                 // slightly. It's better to have novel punctuation
                 // than novel variable names.
                 if probably_punctuation {
-                    cost -= 10;
+                    cost = cost.saturating_sub(cost_model.punctuation_discount);
                 }
                 cost
             }
             EnterNovelDelimiterLHS { contiguous } | EnterNovelDelimiterRHS { contiguous } => {
-                let mut cost = 300;
+                let mut cost = cost_model.novel_delimiter;
                 if !contiguous {
                     // This needs to be more than 40 greater than the
                     // contiguous case. Otherwise, we end up choosing
@@ -344,7 +385,7 @@ impl Edge {
                     // because it has a better depth difference.
                     //
                     // TODO: write a test for this case.
-                    cost += NOT_CONTIGUOUS_PENALTY;
+                    cost += cost_model.not_contiguous_penalty;
                 }
                 cost
             }
@@ -479,6 +520,7 @@ pub fn get_set_neighbours<'syn, 'b>(
     v: &Vertex<'syn, 'b>,
     alloc: &'b Bump,
     seen: &mut FxHashMap<&Vertex<'syn, 'b>, Vec<&'b Vertex<'syn, 'b>>>,
+    cost_model: &CostModel,
 ) -> Vec<(Edge, &'b Vertex<'syn, 'b>)> {
     match &*v.neighbours.borrow() {
         Some(neighbours) => return neighbours.clone(),
@@ -592,10 +634,20 @@ pub fn get_set_neighbours<'syn, 'b>(
         {
             // Both sides are comments and their content is reasonably
             // similar.
-            if lhs_content != rhs_content {
+            let replaced_edge = if lhs_content != rhs_content {
                 let levenshtein_pct =
                     (normalized_levenshtein(lhs_content, rhs_content) * 100.0).round() as u8;
-
+                Some(ReplacedComment { levenshtein_pct })
+            } else {
+                None
+            };
+
+            // Don't bother with a replacement that costs more than
+            // marking both comments as novel, which can happen with
+            // a custom cost model.
+            if let Some(edge) =
+                replaced_edge.filter(|edge| edge.cost(cost_model) < 2 * cost_model.novel_atom)
+            {
                 let (lhs_syntax, rhs_syntax, lhs_parent_id, rhs_parent_id, parents) =
                     pop_all_parents(
                         lhs_syntax.next_sibling(),
@@ -605,7 +657,7 @@ pub fn get_set_neighbours<'syn, 'b>(
                         &v.parents,
                     );
                 res.push((
-                    ReplacedComment { levenshtein_pct },
+                    edge,
                     allocate_if_new(
                         Vertex {
                             neighbours: RefCell::new(None),
@@ -622,6 +674,65 @@ pub fn get_set_neighbours<'syn, 'b>(
                 ));
             }
         }
+
+        if let (
+            Syntax::Atom {
+                content: lhs_content,
+                kind: lhs_kind,
+                ..
+            },
+            Syntax::Atom {
+                content: rhs_content,
+                kind: rhs_kind,
+                ..
+            },
+        ) = (lhs_syntax, rhs_syntax)
+        {
+            // Both sides are atoms of the same kind, such as a
+            // renamed identifier or an edited string literal.
+            // Comments are handled above.
+            if lhs_kind == rhs_kind
+                && *lhs_kind != AtomKind::Comment
+                && lhs_content != rhs_content
+                && !looks_like_punctuation(lhs_content)
+                && !looks_like_punctuation(rhs_content)
+            {
+                let similarity_pct =
+                    (normalized_levenshtein(lhs_content, rhs_content) * 100.0).round() as u8;
+                let edge = ReplacedAtom { similarity_pct };
+
+                // Showing dissimilar atoms as a replacement would
+                // just be noise.
+                if u32::from(similarity_pct) >= cost_model.replaced_atom_min_similarity
+                    && edge.cost(cost_model) < 2 * cost_model.novel_atom
+                {
+                    let (lhs_syntax, rhs_syntax, lhs_parent_id, rhs_parent_id, parents) =
+                        pop_all_parents(
+                            lhs_syntax.next_sibling(),
+                            rhs_syntax.next_sibling(),
+                            v.lhs_parent_id,
+                            v.rhs_parent_id,
+                            &v.parents,
+                        );
+                    res.push((
+                        edge,
+                        allocate_if_new(
+                            Vertex {
+                                neighbours: RefCell::new(None),
+                                predecessor: Cell::new(None),
+                                lhs_syntax,
+                                rhs_syntax,
+                                parents,
+                                lhs_parent_id,
+                                rhs_parent_id,
+                            },
+                            alloc,
+                            seen,
+                        ),
+                    ));
+                }
+            }
+        }
     }
 
     if let Some(lhs_syntax) = &v.lhs_syntax {
@@ -810,6 +921,12 @@ pub fn populate_change_map<'a, 'b>(
                     change_map.insert(rhs, ChangeKind::Novel);
                 }
             }
+            ReplacedAtom { .. } => {
+                let lhs = v.lhs_syntax.unwrap();
+                let rhs = v.rhs_syntax.unwrap();
+                change_map.insert(lhs, ChangeKind::ReplacedAtom(lhs, rhs));
+                change_map.insert(rhs, ChangeKind::ReplacedAtom(rhs, lhs));
+            }
             NovelAtomLHS { .. } | EnterNovelDelimiterLHS { .. } => {
                 let lhs = v.lhs_syntax.unwrap();
                 change_map.insert(lhs, ChangeKind::Novel);
@@ -1,7 +1,9 @@
 pub mod changes;
+pub mod cost_model;
 pub mod dijkstra;
 mod graph;
 pub mod myers_diff;
+pub mod sections;
 pub mod sliders;
 mod stack;
 pub mod unchanged;
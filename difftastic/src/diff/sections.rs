@@ -0,0 +1,868 @@
+//! Diffing the changed sections of a file independently.
+//!
+//! `unchanged::mark_unchanged` splits a file into sections that may
+//! contain changes. The sections don't share any nodes, so we diff
+//! them in parallel. If a section is too big for the graph search, we
+//! fall back to a line diff for the lines it covers, rather than
+//! giving up on structural diffing for the whole file. Alternatively,
+//! `FallbackMode::Myers` gives a coarser diff of the section's syntax
+//! nodes.
+
+use rayon::prelude::*;
+use rustc_hash::FxHashMap;
+
+use crate::{
+    diff::{
+        changes::{insert_deep_novel, insert_deep_unchanged, ChangeKind, ChangeMap},
+        cost_model::CostModel,
+        dijkstra::{mark_syntax, ExceededGraphLimit, SearchAlgorithm, SearchStats},
+        myers_diff,
+    },
+    line_parser,
+    lines::LineNumber,
+    parse::syntax::{init_next_prev, MatchKind, MatchedPos, Syntax},
+    positions::SingleLineSpan,
+};
+
+/// How to diff a section that exceeds the graph limit or memory
+/// budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackMode {
+    /// Use a line diff for the lines the section covers.
+    Line,
+    /// Use Myers' diff on the content ids of the section's top-level
+    /// nodes, then recurse into lists with the same delimiters. This
+    /// is coarser than the graph search, but still syntax aware.
+    Myers,
+}
+
+impl Default for FallbackMode {
+    fn default() -> Self {
+        FallbackMode::Line
+    }
+}
+
+/// A section that exceeded the graph limit or memory budget, the
+/// lines it spans on each side, and the positions of its tokens. A
+/// side may be empty if the section only contains additions or
+/// removals.
+///
+/// Another section can share the first or last line, so the token
+/// positions say which parts of those lines belong to this section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DegradedSection {
+    pub lhs_lines: Option<(LineNumber, LineNumber)>,
+    pub rhs_lines: Option<(LineNumber, LineNumber)>,
+    pub lhs_spans: Vec<SingleLineSpan>,
+    pub rhs_spans: Vec<SingleLineSpan>,
+    pub cause: ExceededGraphLimit,
+    pub fallback: FallbackMode,
+}
+
+fn line_range(nodes: &[&Syntax]) -> Option<(LineNumber, LineNumber)> {
+    let first_line = nodes.first()?.first_line()?;
+    let last_line = nodes.last()?.last_line()?;
+    Some((first_line, last_line))
+}
+
+/// The positions of the atoms and delimiters in `nodes`.
+fn token_spans(nodes: &[&Syntax]) -> Vec<SingleLineSpan> {
+    fn walk(node: &Syntax, spans: &mut Vec<SingleLineSpan>) {
+        match node {
+            Syntax::List {
+                open_position,
+                children,
+                close_position,
+                ..
+            } => {
+                spans.extend(open_position);
+                for child in children {
+                    walk(child, spans);
+                }
+                spans.extend(close_position);
+            }
+            Syntax::Atom { position, .. } => spans.extend(position),
+        }
+    }
+
+    let mut spans = vec![];
+    for node in nodes {
+        walk(node, &mut spans);
+    }
+    spans
+}
+
+/// The nodes of a section, sent to the thread that diffs it.
+///
+/// Syntax nodes aren't `Send` or `Sync`, because `SyntaxInfo` stores
+/// its links in `Cell`s. Those are only written by `init_all_info`
+/// and `init_next_prev`, which have finished before any section is
+/// diffed.
+struct SectionNodes<'a>(Vec<&'a Syntax<'a>>, Vec<&'a Syntax<'a>>);
+
+// SAFETY: the thread diffing a section only reads its nodes, and the
+// `Cell`s in their `SyntaxInfo` aren't written until every thread has
+// finished. Sections don't share nodes, and the nodes outlive the
+// parallel iterator, which joins its threads before returning.
+unsafe impl<'a> Send for SectionNodes<'a> {}
+
+/// The result of diffing a section, returned from the thread that
+/// diffed it.
+struct SectionResult<'a>(ChangeMap<'a>, Option<DegradedSection>, SearchStats);
+
+// SAFETY: the change map only holds references to the section's
+// nodes, which are safe to send for the reasons given on
+// `SectionNodes`. The other fields are plain data.
+unsafe impl<'a> Send for SectionResult<'a> {}
+
+/// Pair up the lists in a run of novel nodes that have the same
+/// delimiters, mark their delimiters as unchanged, and diff their
+/// children. Everything else in the run is novel.
+fn mark_novel_run<'a>(
+    lhs_nodes: &[&'a Syntax<'a>],
+    rhs_nodes: &[&'a Syntax<'a>],
+    change_map: &mut ChangeMap<'a>,
+) {
+    fn delimiters<'b>(node: &'b Syntax<'b>) -> Option<(&'b str, &'b str)> {
+        match node {
+            Syntax::List {
+                open_content,
+                close_content,
+                ..
+            } => Some((open_content, close_content)),
+            Syntax::Atom { .. } => None,
+        }
+    }
+
+    let lhs_lists: Vec<&'a Syntax<'a>> = lhs_nodes
+        .iter()
+        .copied()
+        .filter(|node| delimiters(node).is_some())
+        .collect();
+    let rhs_lists: Vec<&'a Syntax<'a>> = rhs_nodes
+        .iter()
+        .copied()
+        .filter(|node| delimiters(node).is_some())
+        .collect();
+    let lhs_kinds: Vec<_> = lhs_lists.iter().map(|node| delimiters(node)).collect();
+    let rhs_kinds: Vec<_> = rhs_lists.iter().map(|node| delimiters(node)).collect();
+
+    for node in lhs_nodes.iter().chain(rhs_nodes) {
+        insert_deep_novel(node, change_map);
+    }
+
+    // The results are in order, so walk both sides with indexes
+    // rather than relying on the references returned, which point to
+    // the first item with the same value.
+    let mut lhs_i = 0;
+    let mut rhs_i = 0;
+    for diff_res in myers_diff::slice_by_hash(&lhs_kinds, &rhs_kinds) {
+        match diff_res {
+            myers_diff::DiffResult::Left(_) => lhs_i += 1,
+            myers_diff::DiffResult::Right(_) => rhs_i += 1,
+            myers_diff::DiffResult::Both(_, _) => {
+                let (lhs, rhs) = (lhs_lists[lhs_i], rhs_lists[rhs_i]);
+                change_map.insert(lhs, ChangeKind::Unchanged(rhs));
+                change_map.insert(rhs, ChangeKind::Unchanged(lhs));
+                if let (
+                    Syntax::List {
+                        children: lhs_children,
+                        ..
+                    },
+                    Syntax::List {
+                        children: rhs_children,
+                        ..
+                    },
+                ) = (lhs, rhs)
+                {
+                    mark_myers(lhs_children, rhs_children, change_map);
+                }
+                lhs_i += 1;
+                rhs_i += 1;
+            }
+        }
+    }
+}
+
+/// Diff `lhs_nodes` and `rhs_nodes` with Myers' diff on their content
+/// ids. Nodes with the same content are unchanged, and the runs of
+/// nodes between them are diffed by `mark_novel_run`.
+fn mark_myers<'a>(
+    lhs_nodes: &[&'a Syntax<'a>],
+    rhs_nodes: &[&'a Syntax<'a>],
+    change_map: &mut ChangeMap<'a>,
+) {
+    let lhs_ids: Vec<u32> = lhs_nodes.iter().map(|node| node.content_id()).collect();
+    let rhs_ids: Vec<u32> = rhs_nodes.iter().map(|node| node.content_id()).collect();
+
+    let mut lhs_i = 0;
+    let mut rhs_i = 0;
+    let mut run_start = (0, 0);
+    for diff_res in myers_diff::slice_by_hash(&lhs_ids, &rhs_ids) {
+        match diff_res {
+            myers_diff::DiffResult::Left(_) => lhs_i += 1,
+            myers_diff::DiffResult::Right(_) => rhs_i += 1,
+            myers_diff::DiffResult::Both(_, _) => {
+                mark_novel_run(
+                    &lhs_nodes[run_start.0..lhs_i],
+                    &rhs_nodes[run_start.1..rhs_i],
+                    change_map,
+                );
+                insert_deep_unchanged(lhs_nodes[lhs_i], rhs_nodes[rhs_i], change_map);
+                insert_deep_unchanged(rhs_nodes[rhs_i], lhs_nodes[lhs_i], change_map);
+                lhs_i += 1;
+                rhs_i += 1;
+                run_start = (lhs_i, rhs_i);
+            }
+        }
+    }
+    mark_novel_run(
+        &lhs_nodes[run_start.0..],
+        &rhs_nodes[run_start.1..],
+        change_map,
+    );
+}
+
+/// Diff a single section, returning its changes, whether it exceeded
+/// `graph_limit` or `memory_budget`, and the stats of the search if
+/// it didn't.
+fn mark_section<'a>(
+    lhs_section_nodes: &[&'a Syntax<'a>],
+    rhs_section_nodes: &[&'a Syntax<'a>],
+    graph_limit: usize,
+    memory_budget: Option<usize>,
+    algorithm: SearchAlgorithm,
+    cost_model: &CostModel,
+    fallback: FallbackMode,
+) -> (ChangeMap<'a>, Option<DegradedSection>, SearchStats) {
+    let mut change_map = ChangeMap::default();
+    match mark_syntax(
+        lhs_section_nodes.first().copied(),
+        rhs_section_nodes.first().copied(),
+        &mut change_map,
+        graph_limit,
+        memory_budget,
+        algorithm,
+        cost_model,
+    ) {
+        Ok(stats) => (change_map, None, stats),
+        Err(cause) => {
+            match fallback {
+                FallbackMode::Line => {
+                    for node in lhs_section_nodes.iter().chain(rhs_section_nodes.iter()) {
+                        insert_deep_novel(node, &mut change_map);
+                    }
+                }
+                FallbackMode::Myers => {
+                    mark_myers(lhs_section_nodes, rhs_section_nodes, &mut change_map);
+                }
+            }
+            let degraded = DegradedSection {
+                lhs_lines: line_range(lhs_section_nodes),
+                rhs_lines: line_range(rhs_section_nodes),
+                lhs_spans: token_spans(lhs_section_nodes),
+                rhs_spans: token_spans(rhs_section_nodes),
+                cause,
+                fallback,
+            };
+            (change_map, Some(degraded), SearchStats::default())
+        }
+    }
+}
+
+/// Run `mark_syntax` on every section, in parallel. Sections that
+/// exceed `graph_limit` or `memory_budget` are diffed according to
+/// `fallback`, so every node still has a change set, and are
+/// returned. With `FallbackMode::Line`, their positions can then be
+/// replaced with a line diff by `apply_line_fallback`.
+///
+/// The limits apply to each search, and one search runs per thread,
+/// so peak memory grows with the number of threads.
+///
+/// Also returns the stats of the searches that finished, summed over
+/// sections.
+pub fn mark_sections<'a>(
+    sections: Vec<(Vec<&'a Syntax<'a>>, Vec<&'a Syntax<'a>>)>,
+    change_map: &mut ChangeMap<'a>,
+    graph_limit: usize,
+    memory_budget: Option<usize>,
+    algorithm: SearchAlgorithm,
+    cost_model: &CostModel,
+    fallback: FallbackMode,
+) -> (Vec<DegradedSection>, SearchStats) {
+    for (lhs_section_nodes, rhs_section_nodes) in &sections {
+        init_next_prev(lhs_section_nodes);
+        init_next_prev(rhs_section_nodes);
+    }
+
+    let section_results: Vec<SectionResult> = sections
+        .into_iter()
+        .map(|(lhs_section_nodes, rhs_section_nodes)| {
+            SectionNodes(lhs_section_nodes, rhs_section_nodes)
+        })
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|SectionNodes(lhs_section_nodes, rhs_section_nodes)| {
+            let (section_change_map, section_degraded, section_stats) = mark_section(
+                &lhs_section_nodes,
+                &rhs_section_nodes,
+                graph_limit,
+                memory_budget,
+                algorithm,
+                cost_model,
+                fallback,
+            );
+            SectionResult(section_change_map, section_degraded, section_stats)
+        })
+        .collect();
+
+    let mut degraded = vec![];
+    let mut stats = SearchStats::default();
+    for SectionResult(section_change_map, section_degraded, section_stats) in section_results {
+        change_map.extend(section_change_map);
+        degraded.extend(section_degraded);
+        stats.add(&section_stats);
+    }
+    (degraded, stats)
+}
+
+/// The columns that a section's tokens cover on each line.
+struct SectionColumns(FxHashMap<LineNumber, Vec<(u32, u32)>>);
+
+impl SectionColumns {
+    fn new(spans: &[SingleLineSpan]) -> Self {
+        let mut columns: FxHashMap<LineNumber, Vec<(u32, u32)>> = FxHashMap::default();
+        for span in spans {
+            columns
+                .entry(span.line)
+                .or_default()
+                .push((span.start_col, span.end_col));
+        }
+        Self(columns)
+    }
+
+    /// Is `span` inside one of the section's tokens?
+    fn in_token(&self, span: &SingleLineSpan) -> bool {
+        self.0.get(&span.line).map_or(false, |columns| {
+            columns
+                .iter()
+                .any(|(start, end)| *start <= span.start_col && span.end_col <= *end)
+        })
+    }
+
+    /// Is `span` between the first and last of the section's tokens
+    /// on its line?
+    fn in_extent(&self, span: &SingleLineSpan) -> bool {
+        self.0.get(&span.line).map_or(false, |columns| {
+            let first = columns.iter().map(|(start, _)| *start).min();
+            let last = columns.iter().map(|(_, end)| *end).max();
+            match (first, last) {
+                (Some(first), Some(last)) => first <= span.start_col && span.end_col <= last,
+                _ => false,
+            }
+        })
+    }
+}
+
+/// The text of the lines in `range`, or the empty string if there is
+/// no range.
+fn lines_text(src: &str, range: Option<(LineNumber, LineNumber)>) -> String {
+    match range {
+        Some((first, last)) => src
+            .split('\n')
+            .skip(first.0 as usize)
+            .take((last.0 - first.0) as usize + 1)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::new(),
+    }
+}
+
+fn shift_span(span: SingleLineSpan, offset: u32) -> SingleLineSpan {
+    SingleLineSpan {
+        line: (span.line.0 + offset).into(),
+        ..span
+    }
+}
+
+/// Move positions from a line diff of a substring so they're relative
+/// to the whole file.
+fn shift_positions(mps: &mut [MatchedPos], self_offset: u32, opposite_offset: u32) {
+    for mp in mps {
+        mp.pos = shift_span(mp.pos, self_offset);
+        match &mut mp.kind {
+            MatchKind::UnchangedToken {
+                self_pos,
+                opposite_pos,
+                ..
+            }
+            | MatchKind::Replaced {
+                self_pos,
+                opposite_pos,
+                ..
+            } => {
+                for span in self_pos.iter_mut() {
+                    *span = shift_span(*span, self_offset);
+                }
+                for span in opposite_pos.iter_mut() {
+                    *span = shift_span(*span, opposite_offset);
+                }
+            }
+            MatchKind::NovelLinePart {
+                self_pos,
+                opposite_pos,
+                ..
+            } => {
+                *self_pos = shift_span(*self_pos, self_offset);
+                for span in opposite_pos.iter_mut() {
+                    *span = shift_span(*span, opposite_offset);
+                }
+            }
+            MatchKind::Novel { .. } | MatchKind::NovelWord { .. } | MatchKind::Ignored { .. } => {}
+        }
+    }
+}
+
+/// Replace the positions of each degraded section's tokens with the
+/// positions from a line diff of its lines. Parts of those lines that
+/// belong to another section keep their positions. Sections that used
+/// `FallbackMode::Myers` keep their positions.
+pub fn apply_line_fallback(
+    lhs_src: &str,
+    rhs_src: &str,
+    lhs_positions: &mut Vec<MatchedPos>,
+    rhs_positions: &mut Vec<MatchedPos>,
+    degraded: &[DegradedSection],
+) {
+    if degraded.is_empty() {
+        return;
+    }
+
+    for section in degraded {
+        if section.fallback == FallbackMode::Myers {
+            continue;
+        }
+
+        let lhs_columns = SectionColumns::new(&section.lhs_spans);
+        let rhs_columns = SectionColumns::new(&section.rhs_spans);
+        lhs_positions.retain(|mp| !lhs_columns.in_token(&mp.pos));
+        rhs_positions.retain(|mp| !rhs_columns.in_token(&mp.pos));
+
+        let lhs_text = lines_text(lhs_src, section.lhs_lines);
+        let rhs_text = lines_text(rhs_src, section.rhs_lines);
+        let lhs_offset = section.lhs_lines.map_or(0, |(first, _)| first.0);
+        let rhs_offset = section.rhs_lines.map_or(0, |(first, _)| first.0);
+
+        // An empty side has no lines to report, so skip it rather
+        // than adding a position for a line that isn't in the section.
+        if section.lhs_lines.is_some() {
+            let mut section_lhs = line_parser::change_positions(&lhs_text, &rhs_text);
+            shift_positions(&mut section_lhs, lhs_offset, rhs_offset);
+            lhs_positions.extend(
+                section_lhs
+                    .into_iter()
+                    .filter(|mp| lhs_columns.in_extent(&mp.pos)),
+            );
+        }
+        if section.rhs_lines.is_some() {
+            let mut section_rhs = line_parser::change_positions(&rhs_text, &lhs_text);
+            shift_positions(&mut section_rhs, rhs_offset, lhs_offset);
+            rhs_positions.extend(
+                section_rhs
+                    .into_iter()
+                    .filter(|mp| rhs_columns.in_extent(&mp.pos)),
+            );
+        }
+    }
+
+    lhs_positions.sort_by_key(|mp| mp.pos);
+    rhs_positions.sort_by_key(|mp| mp.pos);
+}
+
+fn line_ranges(degraded: &[&DegradedSection]) -> String {
+    let ranges: Vec<String> = degraded
+        .iter()
+        .filter_map(|section| section.lhs_lines.or(section.rhs_lines))
+        .map(|(first, last)| {
+            if first == last {
+                first.one_indexed().to_string()
+            } else {
+                format!("{}-{}", first.one_indexed(), last.one_indexed())
+            }
+        })
+        .collect();
+    ranges.join(", ")
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const KIB: f64 = 1024.0;
+    let bytes_f = bytes as f64;
+    if bytes_f >= KIB * KIB * KIB {
+        format!("{:.1} GiB", bytes_f / (KIB * KIB * KIB))
+    } else if bytes_f >= KIB * KIB {
+        format!("{:.1} MiB", bytes_f / (KIB * KIB))
+    } else if bytes_f >= KIB {
+        format!("{:.1} KiB", bytes_f / KIB)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+/// Describe the degraded sections for `FileFormat::TextFallback`,
+/// e.g. `exceeded DFT_GRAPH_LIMIT for 2/5 sections (lines 3-10, 40-52)`.
+pub fn fallback_reason(degraded: &[DegradedSection], num_sections: usize) -> String {
+    let describe_sections = |num_degraded: usize| {
+        format!(
+            "{}/{} section{}",
+            num_degraded,
+            num_sections,
+            if num_sections == 1 { "" } else { "s" }
+        )
+    };
+
+    let (over_memory, over_vertices): (Vec<&DegradedSection>, Vec<&DegradedSection>) = degraded
+        .iter()
+        .partition(|section| matches!(section.cause, ExceededGraphLimit::Memory { .. }));
+
+    let mut reasons = vec![];
+    if !over_vertices.is_empty() {
+        reasons.push(format!(
+            "exceeded DFT_GRAPH_LIMIT for {} (lines {})",
+            describe_sections(over_vertices.len()),
+            line_ranges(&over_vertices)
+        ));
+    }
+    if !over_memory.is_empty() {
+        let peak_bytes = over_memory
+            .iter()
+            .filter_map(|section| match section.cause {
+                ExceededGraphLimit::Memory { peak_bytes } => Some(peak_bytes),
+                ExceededGraphLimit::Vertices => None,
+            })
+            .max()
+            .unwrap_or(0);
+        reasons.push(format!(
+            "exceeded DFT_MEMORY_BUDGET for {} (lines {}, peak {})",
+            describe_sections(over_memory.len()),
+            line_ranges(&over_memory),
+            format_bytes(peak_bytes)
+        ));
+    }
+    if degraded
+        .iter()
+        .any(|section| section.fallback == FallbackMode::Myers)
+    {
+        reasons.push("used a Myers diff of the syntax nodes".into());
+    }
+    reasons.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diff::unchanged::mark_unchanged,
+        options::DEFAULT_GRAPH_LIMIT,
+        parse::{
+            guess_language,
+            syntax::{change_positions, init_all_info, AtomKind, TokenKind},
+            tree_sitter_parser::{from_language, parse},
+        },
+    };
+    use typed_arena::Arena;
+
+    #[test]
+    fn test_degraded_section_has_changes() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::JavaScript);
+        let lhs = parse(&arena, "a;\nfoo(1, 2);\nb;", &config, false);
+        let rhs = parse(&arena, "a;\nfoo(3, 4);\nb;", &config, false);
+        init_all_info(&lhs, &rhs);
+
+        let mut change_map = ChangeMap::default();
+        let sections = mark_unchanged(&lhs, &rhs, &mut change_map);
+        let num_sections = sections.len();
+        let (degraded, _) = mark_sections(
+            sections,
+            &mut change_map,
+            0,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+            FallbackMode::Line,
+        );
+
+        assert_eq!(degraded.len(), num_sections);
+        // Every node has a change set, so this doesn't panic.
+        change_positions(&lhs, &change_map);
+        change_positions(&rhs, &change_map);
+    }
+
+    #[test]
+    fn test_myers_fallback() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::JavaScript);
+        let lhs = parse(&arena, "foo(1, 2, 3);", &config, false);
+        let rhs = parse(&arena, "foo(1, 5, 3);", &config, false);
+        init_all_info(&lhs, &rhs);
+
+        let mut change_map = ChangeMap::default();
+        let sections = mark_unchanged(&lhs, &rhs, &mut change_map);
+        let (degraded, _) = mark_sections(
+            sections,
+            &mut change_map,
+            0,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+            FallbackMode::Myers,
+        );
+        assert!(!degraded.is_empty());
+
+        // Only the changed argument is novel.
+        let novel_cols: Vec<u32> = change_positions(&lhs, &change_map)
+            .iter()
+            .filter(|mp| mp.kind.is_novel())
+            .map(|mp| mp.pos.start_col)
+            .collect();
+        assert_eq!(novel_cols, vec![7]);
+
+        // The structural positions are kept, rather than replaced by
+        // a line diff.
+        let mut lhs_positions = change_positions(&lhs, &change_map);
+        let mut rhs_positions = change_positions(&rhs, &change_map);
+        let num_lhs_positions = lhs_positions.len();
+        apply_line_fallback(
+            "foo(1, 2, 3);",
+            "foo(1, 5, 3);",
+            &mut lhs_positions,
+            &mut rhs_positions,
+            &degraded,
+        );
+        assert_eq!(lhs_positions.len(), num_lhs_positions);
+    }
+
+    #[test]
+    fn test_memory_budget_exceeded() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::JavaScript);
+        let lhs = parse(&arena, "foo(1, 2);", &config, false);
+        let rhs = parse(&arena, "foo(3, 4);", &config, false);
+        init_all_info(&lhs, &rhs);
+
+        let mut change_map = ChangeMap::default();
+        let sections = mark_unchanged(&lhs, &rhs, &mut change_map);
+        let (degraded, _) = mark_sections(
+            sections,
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            Some(1),
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+            FallbackMode::Line,
+        );
+
+        assert!(!degraded.is_empty());
+        assert!(degraded.iter().all(|section| matches!(
+            section.cause,
+            ExceededGraphLimit::Memory { peak_bytes } if peak_bytes > 1
+        )));
+    }
+
+    #[test]
+    fn test_mark_sections_merges_changes() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::JavaScript);
+
+        let mut lhs_src = String::new();
+        let mut rhs_src = String::new();
+        for i in 0..30 {
+            // Large enough that unchanged statements aren't treated
+            // as tiny trees.
+            let stmt = format!("foo({}, [1, 2, 3], {{ a: 1, b: 2 }});\n", i);
+            lhs_src.push_str(&stmt);
+            if i % 10 == 5 {
+                rhs_src.push_str(&stmt.replace("foo", "bar"));
+            } else {
+                rhs_src.push_str(&stmt);
+            }
+        }
+        let lhs = parse(&arena, &lhs_src, &config, false);
+        let rhs = parse(&arena, &rhs_src, &config, false);
+        init_all_info(&lhs, &rhs);
+
+        let mut change_map = ChangeMap::default();
+        let sections = mark_unchanged(&lhs, &rhs, &mut change_map);
+        assert!(sections.len() > 1);
+        let (degraded, stats) = mark_sections(
+            sections,
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+            FallbackMode::Line,
+        );
+        assert!(degraded.is_empty());
+        // The stats cover every section: each has a novel `foo` and
+        // a novel `bar`.
+        assert!(stats.cost >= 6 * u64::from(CostModel::default().novel_atom));
+
+        let mut novel_lines: Vec<u32> = change_positions(&lhs, &change_map)
+            .iter()
+            .filter(|mp| mp.kind.is_novel())
+            .map(|mp| mp.pos.line.0)
+            .collect();
+        novel_lines.dedup();
+        assert_eq!(novel_lines, vec![5, 15, 25]);
+    }
+
+    #[test]
+    fn test_apply_line_fallback() {
+        let lhs_src = "a\nb\nc\n";
+        let rhs_src = "a\nx\nc\n";
+        let span = SingleLineSpan {
+            line: 1.into(),
+            start_col: 0,
+            end_col: 1,
+        };
+        let section = DegradedSection {
+            lhs_lines: Some((1.into(), 1.into())),
+            rhs_lines: Some((1.into(), 1.into())),
+            lhs_spans: vec![span],
+            rhs_spans: vec![span],
+            cause: ExceededGraphLimit::Vertices,
+            fallback: FallbackMode::Line,
+        };
+
+        let mut lhs_positions = vec![];
+        let mut rhs_positions = vec![];
+        apply_line_fallback(
+            lhs_src,
+            rhs_src,
+            &mut lhs_positions,
+            &mut rhs_positions,
+            &[section],
+        );
+
+        assert!(!lhs_positions.is_empty());
+        assert!(lhs_positions
+            .iter()
+            .all(|mp| mp.pos.line == 1.into() && mp.kind.is_novel()));
+        assert!(rhs_positions
+            .iter()
+            .all(|mp| mp.pos.line == 1.into() && mp.kind.is_novel()));
+    }
+
+    #[test]
+    fn test_apply_line_fallback_shared_line() {
+        // Two sections on one line: `a(1);` diffed structurally, and
+        // `b(2);` degraded to a line diff.
+        let lhs_src = "a(1); b(2);";
+        let rhs_src = "a(3); b(4);";
+        let span = |start_col, end_col| SingleLineSpan {
+            line: 0.into(),
+            start_col,
+            end_col,
+        };
+        let novel = |pos| MatchedPos {
+            kind: MatchKind::Novel {
+                highlight: TokenKind::Atom(AtomKind::Normal),
+            },
+            pos,
+        };
+        // The section's tokens: `b`, `(`, `2`, `)` and `;`.
+        let section_spans: Vec<_> = (6..11).map(|col| span(col, col + 1)).collect();
+        let section = DegradedSection {
+            lhs_lines: Some((0.into(), 0.into())),
+            rhs_lines: Some((0.into(), 0.into())),
+            lhs_spans: section_spans.clone(),
+            rhs_spans: section_spans,
+            cause: ExceededGraphLimit::Vertices,
+            fallback: FallbackMode::Line,
+        };
+
+        let mut lhs_positions = vec![novel(span(2, 3)), novel(span(8, 9))];
+        let mut rhs_positions = vec![novel(span(2, 3)), novel(span(8, 9))];
+        apply_line_fallback(
+            lhs_src,
+            rhs_src,
+            &mut lhs_positions,
+            &mut rhs_positions,
+            &[section],
+        );
+
+        // The other section's position is kept, and the line diff
+        // only adds positions in the degraded section.
+        assert!(lhs_positions.contains(&novel(span(2, 3))));
+        assert!(lhs_positions.iter().any(|mp| mp.pos.start_col >= 6));
+        assert!(lhs_positions
+            .iter()
+            .all(|mp| mp.pos == span(2, 3) || mp.pos.start_col >= 6));
+        assert!(rhs_positions.contains(&novel(span(2, 3))));
+    }
+
+    #[test]
+    fn test_fallback_reason() {
+        let degraded = [DegradedSection {
+            lhs_lines: Some((2.into(), 9.into())),
+            rhs_lines: None,
+            lhs_spans: vec![],
+            rhs_spans: vec![],
+            cause: ExceededGraphLimit::Vertices,
+            fallback: FallbackMode::Line,
+        }];
+
+        assert_eq!(
+            fallback_reason(&degraded, 3),
+            "exceeded DFT_GRAPH_LIMIT for 1/3 sections (lines 3-10)"
+        );
+    }
+
+    #[test]
+    fn test_fallback_reason_myers() {
+        let degraded = [DegradedSection {
+            lhs_lines: Some((2.into(), 9.into())),
+            rhs_lines: None,
+            lhs_spans: vec![],
+            rhs_spans: vec![],
+            cause: ExceededGraphLimit::Vertices,
+            fallback: FallbackMode::Myers,
+        }];
+
+        assert_eq!(
+            fallback_reason(&degraded, 3),
+            "exceeded DFT_GRAPH_LIMIT for 1/3 sections (lines 3-10); used a Myers diff of the syntax nodes"
+        );
+    }
+
+    #[test]
+    fn test_fallback_reason_memory() {
+        let degraded = [
+            DegradedSection {
+                lhs_lines: Some((2.into(), 9.into())),
+                rhs_lines: None,
+                lhs_spans: vec![],
+                rhs_spans: vec![],
+                cause: ExceededGraphLimit::Vertices,
+                fallback: FallbackMode::Line,
+            },
+            DegradedSection {
+                lhs_lines: Some((20.into(), 20.into())),
+                rhs_lines: Some((21.into(), 22.into())),
+                lhs_spans: vec![],
+                rhs_spans: vec![],
+                cause: ExceededGraphLimit::Memory {
+                    peak_bytes: 3 * 1024 * 1024 / 2,
+                },
+                fallback: FallbackMode::Line,
+            },
+        ];
+
+        assert_eq!(
+            fallback_reason(&degraded, 3),
+            "exceeded DFT_GRAPH_LIMIT for 1/3 sections (lines 3-10); \
+             exceeded DFT_MEMORY_BUDGET for 1/3 sections (lines 21, peak 1.5 MiB)"
+        );
+    }
+}
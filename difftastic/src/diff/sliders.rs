@@ -31,54 +31,49 @@
 
 use crate::{
     diff::changes::{insert_deep_novel, insert_deep_unchanged, ChangeKind::*, ChangeMap},
-    parse::guess_language,
+    parse::policy::LanguagePolicy,
     parse::syntax::Syntax,
     positions::SingleLineSpan,
 };
 use Syntax::*;
 
+/// The most steps we take when sliding novel regions. A region moves
+/// one node per step, so this is only reached for pathological inputs
+/// such as long runs of repeated nodes.
+const MAX_SLIDER_STEPS: usize = 1000;
+
+/// Correct sliders in `nodes`, returning the number of fixes applied.
 pub fn fix_all_sliders<'a>(
-    language: guess_language::Language,
+    policy: &LanguagePolicy,
     nodes: &[&'a Syntax<'a>],
     change_map: &mut ChangeMap<'a>,
-) {
-    // TODO: fix sliders that require more than two steps.
-    fix_all_sliders_one_step(nodes, change_map);
-    fix_all_sliders_one_step(nodes, change_map);
-
-    fix_all_nested_sliders(language, nodes, change_map);
-}
-
-/// Should nester slider correction prefer the inner or outer
-/// delimiter?
-fn prefer_outer_delimiter(language: guess_language::Language) -> bool {
-    use crate::parse::guess_language::Language::*;
-    match language {
-        // For Lisp family languages, we get the best result with the
-        // outer delimiter.
-        EmacsLisp | Clojure | CommonLisp | Janet | Racket | Newick => true,
-        // JSON and TOML are like Lisp: the outer delimiter in an array object
-        // is the most relevant.
-        Json | Toml | Hcl => true,
-        // It's probably the case that outer delimiters
-        // (e.g. grouping) are used more frequently than inner
-        // delimiters in SQL. `(foo = 1 OR bar = 2)` is more likely
-        // than `foo(1)`.
-        Sql => true,
-        // For everything else, prefer the inner delimiter. These
-        // languages have syntax like `foo(bar)` or `foo[bar]` where
-        // the inner delimiter is more relevant.
-        _ => false,
+) -> usize {
+    // Each step moves a novel region by at most one node, so repeat
+    // until nothing moves. Sliding to the previous node and sliding
+    // to the next node have opposite conditions on the distances, so
+    // a step never undoes the one before it. Moving a delimiter
+    // between nested lists can create a new slider, and sliding can
+    // create a new nested slider, so both passes are in the loop.
+    let mut num_fixes = 0;
+    for _ in 0..MAX_SLIDER_STEPS {
+        let step_fixes = fix_all_sliders_one_step(nodes, change_map)
+            + fix_all_nested_sliders(policy.prefer_outer_delimiter, nodes, change_map);
+        if step_fixes == 0 {
+            break;
+        }
+        num_fixes += step_fixes;
     }
+    num_fixes
 }
 
-fn fix_all_sliders_one_step<'a>(nodes: &[&'a Syntax<'a>], change_map: &mut ChangeMap<'a>) {
+fn fix_all_sliders_one_step<'a>(nodes: &[&'a Syntax<'a>], change_map: &mut ChangeMap<'a>) -> usize {
+    let mut num_fixes = 0;
     for node in nodes {
         if let List { children, .. } = node {
-            fix_all_sliders_one_step(children, change_map);
+            num_fixes += fix_all_sliders_one_step(children, change_map);
         }
     }
-    fix_sliders(nodes, change_map);
+    num_fixes + fix_sliders(nodes, change_map)
 }
 
 /// Correct sliders in middle insertions.
@@ -98,24 +93,29 @@ fn fix_all_sliders_one_step<'a>(nodes: &[&'a Syntax<'a>], change_map: &mut Chang
 /// For C-like languages, the first case matches human intuition much
 /// better. Fix the slider to make the inner delimiter novel.
 fn fix_all_nested_sliders<'a>(
-    language: guess_language::Language,
+    prefer_outer: bool,
     nodes: &[&'a Syntax<'a>],
     change_map: &mut ChangeMap<'a>,
-) {
-    let prefer_outer = prefer_outer_delimiter(language);
+) -> usize {
+    let mut num_fixes = 0;
     for node in nodes {
-        if prefer_outer {
-            fix_nested_slider_prefer_outer(node, change_map);
+        num_fixes += if prefer_outer {
+            fix_nested_slider_prefer_outer(node, change_map)
         } else {
-            fix_nested_slider_prefer_inner(node, change_map);
-        }
+            fix_nested_slider_prefer_inner(node, change_map)
+        };
     }
+    num_fixes
 }
 
 /// When we see code of the form `(old-1 (novel (old-2)))`, prefer
 /// treating the outer delimiter as novel, so `(novel ...)` in this
 /// example.
-fn fix_nested_slider_prefer_outer<'a>(node: &'a Syntax<'a>, change_map: &mut ChangeMap<'a>) {
+fn fix_nested_slider_prefer_outer<'a>(
+    node: &'a Syntax<'a>,
+    change_map: &mut ChangeMap<'a>,
+) -> usize {
+    let mut num_fixes = 0;
     if let List { children, .. } = node {
         match change_map
             .get(node)
@@ -129,46 +129,55 @@ fn fix_nested_slider_prefer_outer<'a>(node: &'a Syntax<'a>, change_map: &mut Cha
                 if let [unchanged] = found_unchanged[..] {
                     if matches!(unchanged, List { .. })
                         && matches!(change_map.get(unchanged), Some(Novel))
+                        && push_unchanged_to_descendant(node, unchanged, change_map)
                     {
-                        push_unchanged_to_descendant(node, unchanged, change_map);
+                        num_fixes += 1;
                     }
                 }
             }
-            ReplacedComment(_, _) => {}
+            ReplacedComment(_, _) | ReplacedAtom(_, _) => {}
             Novel => {}
         }
 
         for child in children {
-            fix_nested_slider_prefer_outer(child, change_map);
+            num_fixes += fix_nested_slider_prefer_outer(child, change_map);
         }
     }
+    num_fixes
 }
 
 /// When we see code of the form `old1(novel(old2()))`, prefer
 /// treating the inner delimiter as novel, so `novel(...)` in this
 /// example.
-fn fix_nested_slider_prefer_inner<'a>(node: &'a Syntax<'a>, change_map: &mut ChangeMap<'a>) {
+fn fix_nested_slider_prefer_inner<'a>(
+    node: &'a Syntax<'a>,
+    change_map: &mut ChangeMap<'a>,
+) -> usize {
+    let mut num_fixes = 0;
     if let List { children, .. } = node {
         match change_map
             .get(node)
             .expect("Changes should be set before slider correction")
         {
             Unchanged(_) => {}
-            ReplacedComment(_, _) => {}
+            ReplacedComment(_, _) | ReplacedAtom(_, _) => {}
             Novel => {
                 let mut found_unchanged = vec![];
                 unchanged_descendants(children, &mut found_unchanged, change_map);
 
                 if let [List { .. }] = found_unchanged[..] {
-                    push_unchanged_to_ancestor(node, found_unchanged[0], change_map);
+                    if push_unchanged_to_ancestor(node, found_unchanged[0], change_map) {
+                        num_fixes += 1;
+                    }
                 }
             }
         }
 
         for child in children {
-            fix_nested_slider_prefer_inner(child, change_map);
+            num_fixes += fix_nested_slider_prefer_inner(child, change_map);
         }
     }
+    num_fixes
 }
 
 /// Find the unchanged descendants of `nodes`.
@@ -188,7 +197,7 @@ fn unchanged_descendants<'a>(
             Unchanged(_) => {
                 found.push(node);
             }
-            Novel | ReplacedComment(_, _) => {
+            Novel | ReplacedComment(_, _) | ReplacedAtom(_, _) => {
                 if let List { children, .. } = node {
                     unchanged_descendants(children, found, change_map);
                 }
@@ -243,12 +252,12 @@ fn unchanged_descendants_ignore_delim<'a>(
 
 /// Given a nested list where the root delimiters are unchanged but
 /// the inner list's delimiters are novel, mark the inner list as
-/// unchanged instead.
+/// unchanged instead. Returns whether the changes were moved.
 fn push_unchanged_to_descendant<'a>(
     root: &'a Syntax<'a>,
     inner: &'a Syntax<'a>,
     change_map: &mut ChangeMap<'a>,
-) {
+) -> bool {
     let root_change = change_map
         .get(root)
         .expect("Changes should be set before slider correction");
@@ -273,16 +282,17 @@ fn push_unchanged_to_descendant<'a>(
         change_map.insert(root, Novel);
         change_map.insert(inner, root_change);
     }
+    delimiters_match
 }
 
 /// Given a nested list where the root delimiters are novel but
 /// the inner list's delimiters are unchanged, mark the root list as
-/// unchanged instead.
+/// unchanged instead. Returns whether the changes were moved.
 fn push_unchanged_to_ancestor<'a>(
     root: &'a Syntax<'a>,
     inner: &'a Syntax<'a>,
     change_map: &mut ChangeMap<'a>,
-) {
+) -> bool {
     let inner_change = change_map.get(inner).expect("Node changes should be set");
 
     let delimiters_match = match (root, inner) {
@@ -305,18 +315,26 @@ fn push_unchanged_to_ancestor<'a>(
         change_map.insert(root, inner_change);
         change_map.insert(inner, Novel);
     }
+    delimiters_match
 }
 
 /// For every sequence of novel nodes, if it's a potential slider,
 /// change which nodes are marked as novel if it produces a sequence
-/// of nodes that are closer together.
-fn fix_sliders<'a>(nodes: &[&'a Syntax<'a>], change_map: &mut ChangeMap<'a>) {
+/// of nodes that are closer together. Returns the number of regions
+/// that were moved.
+fn fix_sliders<'a>(nodes: &[&'a Syntax<'a>], change_map: &mut ChangeMap<'a>) -> usize {
+    let mut num_fixes = 0;
     for (region_start, region_end) in novel_regions_after_unchanged(nodes, change_map) {
-        slide_to_prev_node(nodes, change_map, region_start, region_end);
+        if slide_to_prev_node(nodes, change_map, region_start, region_end) {
+            num_fixes += 1;
+        }
     }
     for (region_start, region_end) in novel_regions_before_unchanged(nodes, change_map) {
-        slide_to_next_node(nodes, change_map, region_start, region_end);
+        if slide_to_next_node(nodes, change_map, region_start, region_end) {
+            num_fixes += 1;
+        }
     }
+    num_fixes
 }
 
 /// Return the start and end indexes of sequences of novel nodes that
@@ -347,7 +365,7 @@ fn novel_regions_after_unchanged<'a>(
                     region = Some(r);
                 }
             }
-            ReplacedComment(_, _) => {
+            ReplacedComment(_, _) | ReplacedAtom(_, _) => {
                 // Could have just finished a novel region.
                 if let Some(region) = region {
                     regions.push(region);
@@ -395,7 +413,7 @@ fn novel_regions_before_unchanged<'a>(
                 r.push(i);
                 region = Some(r);
             }
-            ReplacedComment(_, _) => {
+            ReplacedComment(_, _) | ReplacedAtom(_, _) => {
                 region = None;
             }
         }
@@ -431,7 +449,8 @@ fn is_novel_deep<'a>(node: &Syntax<'a>, change_map: &ChangeMap<'a>) -> bool {
 }
 
 /// If the previous node is unchanged, matches the end of the region,
-/// and has a smaller text distance, mark it as novel.
+/// and has a smaller text distance, mark it as novel. Returns whether
+/// the region was moved.
 ///
 /// ```text
 /// x UNCHANGED
@@ -453,12 +472,12 @@ fn slide_to_prev_node<'a>(
     change_map: &mut ChangeMap<'a>,
     start_idx: usize,
     end_idx: usize,
-) {
+) -> bool {
     if start_idx == 0 {
-        return;
+        return false;
     }
     if start_idx == end_idx {
-        return;
+        return false;
     }
 
     let start_node = nodes[start_idx];
@@ -467,7 +486,7 @@ fn slide_to_prev_node<'a>(
     let before_last_node = nodes[end_idx - 1];
 
     if before_start_node.content_id() != last_node.content_id() {
-        return;
+        return false;
     }
 
     let distance_to_before_start = distance_between(before_start_node, start_node);
@@ -480,29 +499,32 @@ fn slide_to_prev_node<'a>(
         {
             Unchanged(n) => {
                 if before_start_node.content_id() != n.content_id() {
-                    return;
+                    return false;
                 }
                 n
             }
             _ => {
-                return;
+                return false;
             }
         };
 
         for node in &nodes[start_idx..=end_idx] {
             if !is_novel_deep(node, change_map) {
-                return;
+                return false;
             }
         }
 
         insert_deep_novel(before_start_node, change_map);
         insert_deep_unchanged(last_node, opposite, change_map);
         insert_deep_unchanged(opposite, last_node, change_map);
+        return true;
     }
+    false
 }
 
 /// If the next node is unchanged, matches the beginning of the region,
-/// and has a smaller text distance, mark it as novel.
+/// and has a smaller text distance, mark it as novel. Returns whether
+/// the region was moved.
 ///
 /// ```text
 /// x NOVEL <- start_idx
@@ -524,12 +546,12 @@ fn slide_to_next_node<'a>(
     change_map: &mut ChangeMap<'a>,
     start_idx: usize,
     end_idx: usize,
-) {
+) -> bool {
     if end_idx == nodes.len() - 1 {
-        return;
+        return false;
     }
     if start_idx == end_idx {
-        return;
+        return false;
     }
 
     let start_node = nodes[start_idx];
@@ -538,7 +560,7 @@ fn slide_to_next_node<'a>(
     let after_last_node = nodes[end_idx + 1];
 
     if after_last_node.content_id() != start_node.content_id() {
-        return;
+        return false;
     }
 
     let distance_to_start = distance_between(start_node, after_start_node);
@@ -551,24 +573,26 @@ fn slide_to_next_node<'a>(
         {
             Unchanged(n) => {
                 if after_last_node.content_id() != n.content_id() {
-                    return;
+                    return false;
                 }
                 n
             }
             _ => {
-                return;
+                return false;
             }
         };
         for node in &nodes[start_idx..=end_idx] {
             if !is_novel_deep(node, change_map) {
-                return;
+                return false;
             }
         }
 
         insert_deep_unchanged(start_node, opposite, change_map);
         insert_deep_unchanged(opposite, start_node, change_map);
         insert_deep_novel(after_last_node, change_map);
+        return true;
     }
+    false
 }
 
 /// Return the distance between two syntax nodes, as a tuple of number
@@ -689,7 +713,12 @@ mod tests {
         change_map.insert(lhs[1], Novel);
         change_map.insert(lhs[2], Novel);
 
-        fix_all_sliders(guess_language::Language::EmacsLisp, &lhs, &mut change_map);
+        let num_fixes = fix_all_sliders(
+            &from_language(guess_language::Language::EmacsLisp).policy,
+            &lhs,
+            &mut change_map,
+        );
+        assert_eq!(num_fixes, 1);
         assert_eq!(change_map.get(lhs[0]), Some(Novel));
         assert_eq!(change_map.get(lhs[1]), Some(Novel));
         assert_eq!(change_map.get(lhs[2]), Some(Unchanged(rhs[0])));
@@ -738,7 +767,11 @@ mod tests {
         change_map.insert(lhs[1], Novel);
         change_map.insert(lhs[2], Unchanged(rhs[0]));
 
-        fix_all_sliders(guess_language::Language::EmacsLisp, &lhs, &mut change_map);
+        fix_all_sliders(
+            &from_language(guess_language::Language::EmacsLisp).policy,
+            &lhs,
+            &mut change_map,
+        );
 
         assert_eq!(change_map.get(rhs[0]), Some(Unchanged(lhs[0])));
         assert_eq!(change_map.get(lhs[0]), Some(Unchanged(rhs[0])));
@@ -762,13 +795,147 @@ mod tests {
         change_map.insert(rhs[3], Novel);
         change_map.insert(rhs[4], Novel);
 
-        fix_all_sliders(guess_language::Language::EmacsLisp, &rhs, &mut change_map);
+        fix_all_sliders(&config.policy, &rhs, &mut change_map);
         assert_eq!(change_map.get(rhs[0]), Some(Novel));
         assert_eq!(change_map.get(rhs[1]), Some(Novel));
         assert_eq!(change_map.get(rhs[2]), Some(Novel));
         assert_eq!(change_map.get(rhs[3]), Some(Unchanged(rhs[0])));
     }
 
+    /// Mark the leading nodes of `rhs` as unchanged, matching `lhs`,
+    /// and the rest as novel. This is a valid diff when `rhs` ends
+    /// with a copy of `lhs`, but it's a slider. Returns the number of
+    /// fixes.
+    fn fix_repeated_block<'a>(
+        language: guess_language::Language,
+        lhs: &[&'a Syntax<'a>],
+        rhs: &[&'a Syntax<'a>],
+        change_map: &mut ChangeMap<'a>,
+    ) -> usize {
+        for (lhs_node, rhs_node) in lhs.iter().zip(rhs) {
+            insert_deep_unchanged(lhs_node, rhs_node, change_map);
+            insert_deep_unchanged(rhs_node, lhs_node, change_map);
+        }
+        for rhs_node in &rhs[lhs.len()..] {
+            insert_deep_novel(rhs_node, change_map);
+        }
+
+        fix_all_sliders(&from_language(language).policy, rhs, change_map)
+    }
+
+    /// Assert that the novel nodes in `rhs` have slid all the way to
+    /// the start, so `lhs` matches the copy at the end.
+    fn assert_slid_to_start<'a>(
+        lhs: &[&'a Syntax<'a>],
+        rhs: &[&'a Syntax<'a>],
+        change_map: &ChangeMap<'a>,
+    ) {
+        let num_novel = rhs.len() - lhs.len();
+        for rhs_node in &rhs[..num_novel] {
+            assert_eq!(change_map.get(rhs_node), Some(Novel));
+        }
+        for (lhs_node, rhs_node) in lhs.iter().zip(&rhs[num_novel..]) {
+            assert_eq!(change_map.get(rhs_node), Some(Unchanged(lhs_node)));
+            assert_eq!(change_map.get(lhs_node), Some(Unchanged(rhs_node)));
+        }
+    }
+
+    #[test]
+    fn test_slider_three_steps_rust() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::Rust);
+
+        let lhs = parse(&arena, "use a; use b; use c;", &config, false);
+        let rhs = parse(
+            &arena,
+            "use a; use b; use c; use x;\nuse a; use b; use c;",
+            &config,
+            false,
+        );
+        init_all_info(&lhs, &rhs);
+
+        let mut change_map = ChangeMap::default();
+        let num_fixes =
+            fix_repeated_block(guess_language::Language::Rust, &lhs, &rhs, &mut change_map);
+        assert_eq!(num_fixes, 3);
+        assert_slid_to_start(&lhs, &rhs, &change_map);
+    }
+
+    #[test]
+    fn test_slider_three_steps_go() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::Go);
+
+        let lhs = parse(
+            &arena,
+            "package p\n\nfunc f() {\n\ta()\n\tb()\n\tc()\n}\n",
+            &config,
+            false,
+        );
+        let rhs = parse(
+            &arena,
+            "package p\n\nfunc f() {\n\ta()\n\tb()\n\tc()\n\tx()\n\ta()\n\tb()\n\tc()\n}\n",
+            &config,
+            false,
+        );
+        init_all_info(&lhs, &rhs);
+
+        // The statements in the body of `f`.
+        fn body<'a>(nodes: &[&'a Syntax<'a>]) -> &'a [&'a Syntax<'a>] {
+            let func_children = match nodes[1] {
+                List { children, .. } => children,
+                Atom { .. } => unreachable!(),
+            };
+            match func_children.last().unwrap() {
+                List { children, .. } => children,
+                Atom { .. } => unreachable!(),
+            }
+        }
+        let lhs_body = body(&lhs);
+        let rhs_body = body(&rhs);
+        assert_eq!(rhs_body.len(), 7);
+
+        let mut change_map = ChangeMap::default();
+        let num_fixes = fix_repeated_block(
+            guess_language::Language::Go,
+            lhs_body,
+            rhs_body,
+            &mut change_map,
+        );
+        assert_eq!(num_fixes, 3);
+        assert_slid_to_start(lhs_body, rhs_body, &change_map);
+    }
+
+    #[test]
+    fn test_slider_three_steps_lisp() {
+        let arena = Arena::new();
+        let config = from_language(guess_language::Language::EmacsLisp);
+
+        let lhs = parse(&arena, "(a b c)", &config, false);
+        let rhs = parse(&arena, "(a b c x a b c)", &config, false);
+        init_all_info(&lhs, &rhs);
+
+        fn children<'a>(nodes: &[&'a Syntax<'a>]) -> &'a [&'a Syntax<'a>] {
+            match nodes[0] {
+                List { children, .. } => children,
+                Atom { .. } => unreachable!(),
+            }
+        }
+        let lhs_children = children(&lhs);
+        let rhs_children = children(&rhs);
+        assert_eq!(rhs_children.len(), 7);
+
+        let mut change_map = ChangeMap::default();
+        let num_fixes = fix_repeated_block(
+            guess_language::Language::EmacsLisp,
+            lhs_children,
+            rhs_children,
+            &mut change_map,
+        );
+        assert_eq!(num_fixes, 3);
+        assert_slid_to_start(lhs_children, rhs_children, &change_map);
+    }
+
     /// If a list is partially unchanged but contains some novel
     /// children, we should not slide it.
     #[test]
@@ -803,7 +970,7 @@ mod tests {
             Unchanged(rhs_first_list_children[2]),
         );
 
-        fix_all_sliders(guess_language::Language::EmacsLisp, &lhs, &mut change_map);
+        fix_all_sliders(&config.policy, &lhs, &mut change_map);
         assert_eq!(
             change_map.get(lhs[2]),
             Some(Novel),
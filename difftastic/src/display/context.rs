@@ -142,7 +142,8 @@ fn matched_lines_from_unchanged(
     for mp in mps {
         let opposite_line = match &mp.kind {
             MatchKind::UnchangedToken { opposite_pos, .. }
-            | MatchKind::NovelLinePart { opposite_pos, .. } => {
+            | MatchKind::NovelLinePart { opposite_pos, .. }
+            | MatchKind::Replaced { opposite_pos, .. } => {
                 if let Some(highest_opposite_side) = highest_opposite_line {
                     opposite_pos
                         .iter()
@@ -335,6 +336,11 @@ pub fn opposite_positions(mps: &[MatchedPos]) -> FxHashMap<LineNumber, HashSet<L
                 self_pos,
                 opposite_pos,
                 ..
+            }
+            | MatchKind::Replaced {
+                self_pos,
+                opposite_pos,
+                ..
             } => {
                 for (self_span, opposite_span) in zip_repeat_shorter(self_pos, opposite_pos) {
                     let opposite_lines = res.entry(self_span.line).or_insert_with(HashSet::new);
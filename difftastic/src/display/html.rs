@@ -0,0 +1,352 @@
+//! HTML side-by-side diff display.
+//!
+//! Highlights are written as CSS classes rather than inline styles, so
+//! pages can share the stylesheet in `STYLESHEET`.
+
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    constants::Side,
+    display::{
+        context::all_matched_lines_filled,
+        hunks::{matched_lines_indexes_for_hunk, Hunk},
+        side_by_side::lines_with_novel,
+    },
+    lines::{split_on_newlines, LineNumber},
+    parse::syntax::{AtomKind, MatchKind, MatchedPos, TokenKind},
+    positions::SingleLineSpan,
+};
+
+/// Styles for the classes produced by this module. The colours match
+/// the terminal display on a light background.
+pub const STYLESHEET: &str = "\
+body { font-family: sans-serif; margin: 1em 2em; }
+table.diff { border-collapse: collapse; width: 100%; font-family: monospace; font-size: 0.9em; margin-bottom: 1.5em; }
+table.diff td { vertical-align: top; padding: 0 0.5em; white-space: pre-wrap; word-break: break-all; }
+table.diff td.line-num { color: #888; text-align: right; user-select: none; width: 3em; }
+table.diff tr.hunk-header td { background: #eee; color: #444; padding: 0.2em 0.5em; }
+td.lhs.novel-line { background: #fff0f0; }
+td.rhs.novel-line { background: #f0fff0; }
+td.line-num.lhs.novel-line { color: #c00; font-weight: bold; }
+td.line-num.rhs.novel-line { color: #080; font-weight: bold; }
+.lhs .novel { color: #c00; }
+.rhs .novel { color: #080; }
+.novel-word { font-weight: bold; text-decoration: underline; }
+.string { color: #a0a; }
+.comment { color: #00a; font-style: italic; }
+.novel.comment { font-style: italic; }
+.keyword, .type { font-weight: bold; }
+.error { color: #808; }
+";
+
+fn escape_char(c: char, res: &mut String) {
+    match c {
+        '&' => res.push_str("&amp;"),
+        '<' => res.push_str("&lt;"),
+        '>' => res.push_str("&gt;"),
+        '"' => res.push_str("&quot;"),
+        '\'' => res.push_str("&#39;"),
+        _ => res.push(c),
+    }
+}
+
+/// Escape `s` so it can be used in HTML text or attribute values.
+pub fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        escape_char(c, &mut res);
+    }
+    res
+}
+
+fn token_class(highlight: TokenKind) -> Option<&'static str> {
+    match highlight {
+        TokenKind::Delimiter => None,
+        TokenKind::Atom(AtomKind::Normal) => None,
+        TokenKind::Atom(AtomKind::String) => Some("string"),
+        TokenKind::Atom(AtomKind::Type) => Some("type"),
+        TokenKind::Atom(AtomKind::Comment) => Some("comment"),
+        TokenKind::Atom(AtomKind::Keyword) => Some("keyword"),
+        TokenKind::Atom(AtomKind::TreeSitterError) => Some("error"),
+    }
+}
+
+/// The CSS classes for a position, or `None` if it needs no styling.
+fn css_classes(kind: &MatchKind) -> Option<String> {
+    let (change_class, highlight) = match kind {
+        MatchKind::UnchangedToken { highlight, .. } | MatchKind::Ignored { highlight } => {
+            (None, *highlight)
+        }
+        MatchKind::Novel { highlight }
+        | MatchKind::NovelLinePart { highlight, .. }
+        | MatchKind::Replaced { highlight, .. } => (Some("novel"), *highlight),
+        MatchKind::NovelWord { highlight } => (Some("novel novel-word"), *highlight),
+    };
+
+    match (change_class, token_class(highlight)) {
+        (Some(change_class), Some(token_class)) => {
+            Some(format!("{} {}", change_class, token_class))
+        }
+        (Some(class), None) | (None, Some(class)) => Some(class.to_owned()),
+        (None, None) => None,
+    }
+}
+
+/// Group the styled spans of `mps` by line, sorted by column.
+fn spans_by_line(mps: &[MatchedPos]) -> FxHashMap<LineNumber, Vec<(SingleLineSpan, String)>> {
+    let mut res: FxHashMap<LineNumber, Vec<(SingleLineSpan, String)>> = FxHashMap::default();
+    for mp in mps {
+        if let Some(classes) = css_classes(&mp.kind) {
+            res.entry(mp.pos.line).or_default().push((mp.pos, classes));
+        }
+    }
+
+    for spans in res.values_mut() {
+        spans.sort_by_key(|(span, _)| span.start_col);
+    }
+    res
+}
+
+/// Render `line` as escaped HTML, wrapping each span in a `<span>`
+/// with its classes.
+///
+/// Tolerant against spans that overlap or extend beyond the line.
+fn render_line(line: &str, spans: &[(SingleLineSpan, String)]) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut i = 0;
+    for (span, classes) in spans {
+        let start_col = span.start_col as usize;
+        let end_col = std::cmp::min(span.end_col as usize, line.len());
+        if start_col < i || start_col >= end_col {
+            continue;
+        }
+        let (before, span_s) = match (line.get(i..start_col), line.get(start_col..end_col)) {
+            (Some(before), Some(span_s)) => (before, span_s),
+            _ => continue,
+        };
+
+        res.push_str(&escape(before));
+        res.push_str(&format!(
+            "<span class=\"{}\">{}</span>",
+            classes,
+            escape(span_s)
+        ));
+        i = end_col;
+    }
+
+    if let Some(rest) = line.get(i..) {
+        res.push_str(&escape(rest));
+    }
+    res
+}
+
+fn side_class(side: Side) -> &'static str {
+    match side {
+        Side::Left => "lhs",
+        Side::Right => "rhs",
+    }
+}
+
+fn write_cells(
+    out: &mut impl Write,
+    side: Side,
+    line_num: Option<LineNumber>,
+    lines: &[&str],
+    spans: &FxHashMap<LineNumber, Vec<(SingleLineSpan, String)>>,
+    lines_with_novel: &HashSet<LineNumber>,
+) -> io::Result<()> {
+    let side_class = side_class(side);
+    match line_num {
+        Some(line_num) => {
+            let novel_class = if lines_with_novel.contains(&line_num) {
+                " novel-line"
+            } else {
+                ""
+            };
+            let line = lines.get(line_num.as_usize()).copied().unwrap_or("");
+            let line_spans = spans.get(&line_num).map(|s| &s[..]).unwrap_or(&[]);
+            write!(
+                out,
+                "<td class=\"line-num {}{}\">{}</td><td class=\"{}{}\">{}</td>",
+                side_class,
+                novel_class,
+                line_num.as_usize() + 1,
+                side_class,
+                novel_class,
+                render_line(line, line_spans)
+            )
+        }
+        None => write!(
+            out,
+            "<td class=\"line-num {}\"></td><td class=\"{}\"></td>",
+            side_class, side_class
+        ),
+    }
+}
+
+/// Write a side-by-side diff of the hunks as an HTML table.
+///
+/// The table references the classes in `STYLESHEET`, so the caller
+/// is responsible for including it in the page.
+pub fn write_diff(
+    out: &mut impl Write,
+    hunks: &[Hunk],
+    lhs_display_path: &str,
+    rhs_display_path: &str,
+    lhs_src: &str,
+    rhs_src: &str,
+    lhs_mps: &[MatchedPos],
+    rhs_mps: &[MatchedPos],
+    num_context_lines: usize,
+) -> io::Result<()> {
+    let lhs_lines = split_on_newlines(lhs_src);
+    let rhs_lines = split_on_newlines(rhs_src);
+    let lhs_spans = spans_by_line(lhs_mps);
+    let rhs_spans = spans_by_line(rhs_mps);
+    let (lhs_lines_with_novel, rhs_lines_with_novel) = lines_with_novel(lhs_mps, rhs_mps);
+
+    writeln!(out, "<table class=\"diff\">")?;
+    writeln!(
+        out,
+        "<tr class=\"hunk-header\"><td colspan=\"2\">{}</td><td colspan=\"2\">{}</td></tr>",
+        escape(lhs_display_path),
+        escape(rhs_display_path)
+    )?;
+
+    // An added or removed file has no alignment to speak of, so show
+    // every line of the non-empty side.
+    if lhs_src.is_empty() || rhs_src.is_empty() {
+        let (side, lines) = if lhs_src.is_empty() {
+            (Side::Right, &rhs_lines)
+        } else {
+            (Side::Left, &lhs_lines)
+        };
+        for i in 0..lines.len() {
+            let line_num = Some(LineNumber::from(i as u32));
+            let (lhs_line_num, rhs_line_num) = match side {
+                Side::Left => (line_num, None),
+                Side::Right => (None, line_num),
+            };
+            write!(out, "<tr>")?;
+            write_cells(
+                out,
+                Side::Left,
+                lhs_line_num,
+                &lhs_lines,
+                &lhs_spans,
+                &lhs_lines_with_novel,
+            )?;
+            write_cells(
+                out,
+                Side::Right,
+                rhs_line_num,
+                &rhs_lines,
+                &rhs_spans,
+                &rhs_lines_with_novel,
+            )?;
+            writeln!(out, "</tr>")?;
+        }
+        return writeln!(out, "</table>");
+    }
+
+    let matched_lines = all_matched_lines_filled(lhs_mps, rhs_mps, &lhs_lines, &rhs_lines);
+    let mut matched_lines_to_print = &matched_lines[..];
+
+    for (i, hunk) in hunks.iter().enumerate() {
+        writeln!(
+            out,
+            "<tr class=\"hunk-header\"><td colspan=\"4\">Hunk {}/{}</td></tr>",
+            i + 1,
+            hunks.len()
+        )?;
+
+        let (start_i, end_i) =
+            matched_lines_indexes_for_hunk(matched_lines_to_print, hunk, num_context_lines);
+        let aligned_lines = &matched_lines_to_print[start_i..end_i];
+        matched_lines_to_print = &matched_lines_to_print[start_i..];
+
+        for (lhs_line_num, rhs_line_num) in aligned_lines {
+            write!(out, "<tr>")?;
+            write_cells(
+                out,
+                Side::Left,
+                *lhs_line_num,
+                &lhs_lines,
+                &lhs_spans,
+                &lhs_lines_with_novel,
+            )?;
+            write_cells(
+                out,
+                Side::Right,
+                *rhs_line_num,
+                &rhs_lines,
+                &rhs_spans,
+                &rhs_lines_with_novel,
+            )?;
+            writeln!(out, "</tr>")?;
+        }
+    }
+
+    writeln!(out, "</table>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("a < b && \"c\""),
+            "a &lt; b &amp;&amp; &quot;c&quot;"
+        );
+    }
+
+    #[test]
+    fn test_render_line_spans() {
+        let spans = vec![
+            (
+                SingleLineSpan {
+                    line: 0.into(),
+                    start_col: 0,
+                    end_col: 2,
+                },
+                "keyword".to_owned(),
+            ),
+            (
+                SingleLineSpan {
+                    line: 0.into(),
+                    start_col: 3,
+                    end_col: 4,
+                },
+                "novel".to_owned(),
+            ),
+        ];
+
+        assert_eq!(
+            render_line("fn f<T>()", &spans),
+            "<span class=\"keyword\">fn</span> <span class=\"novel\">f</span>&lt;T&gt;()"
+        );
+    }
+
+    #[test]
+    fn test_css_classes() {
+        assert_eq!(
+            css_classes(&MatchKind::Novel {
+                highlight: TokenKind::Atom(AtomKind::String)
+            }),
+            Some("novel string".to_owned())
+        );
+        assert_eq!(
+            css_classes(&MatchKind::Ignored {
+                highlight: TokenKind::Delimiter
+            }),
+            None
+        );
+    }
+}
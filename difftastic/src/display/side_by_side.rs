@@ -5,6 +5,7 @@ use rustc_hash::FxHashMap;
 use std::{
     cmp::{max, min},
     collections::HashSet,
+    io::{self, Write},
 };
 
 use crate::{
@@ -324,6 +325,36 @@ pub fn print(
     lhs_mps: &[MatchedPos],
     rhs_mps: &[MatchedPos],
 ) {
+    let stdout = io::stdout();
+    write_to(
+        &mut stdout.lock(),
+        hunks,
+        display_options,
+        lhs_display_path,
+        rhs_display_path,
+        file_format,
+        lhs_src,
+        rhs_src,
+        lhs_mps,
+        rhs_mps,
+    )
+    .expect("failed printing to stdout");
+}
+
+/// Write a side-by-side diff to `out`, formatted exactly as `print`
+/// would display it.
+pub fn write_to(
+    out: &mut impl Write,
+    hunks: &[Hunk],
+    display_options: &DisplayOptions,
+    lhs_display_path: &str,
+    rhs_display_path: &str,
+    file_format: &FileFormat,
+    lhs_src: &str,
+    rhs_src: &str,
+    lhs_mps: &[MatchedPos],
+    rhs_mps: &[MatchedPos],
+) -> io::Result<()> {
     let (lhs_colored_lines, rhs_colored_lines) = if display_options.use_color {
         (
             apply_colors(
@@ -365,10 +396,10 @@ pub fn print(
             Side::Right,
             display_options,
         ) {
-            print!("{}", line);
+            write!(out, "{}", line)?;
         }
-        println!();
-        return;
+        writeln!(out)?;
+        return Ok(());
     }
     if rhs_src.is_empty() {
         for line in display_single_column(
@@ -379,10 +410,10 @@ pub fn print(
             Side::Left,
             display_options,
         ) {
-            print!("{}", line);
+            write!(out, "{}", line)?;
         }
-        println!();
-        return;
+        writeln!(out)?;
+        return Ok(());
     }
 
     // TODO: this is largely duplicating the `apply_colors` logic.
@@ -409,7 +440,8 @@ pub fn print(
     let mut matched_lines_to_print = &matched_lines[..];
 
     for (i, hunk) in hunks.iter().enumerate() {
-        println!(
+        writeln!(
+            out,
             "{}",
             style::header(
                 lhs_display_path,
@@ -419,7 +451,7 @@ pub fn print(
                 file_format,
                 display_options
             )
-        );
+        )?;
 
         let (start_i, end_i) = matched_lines_indexes_for_hunk(
             matched_lines_to_print,
@@ -478,19 +510,20 @@ pub fn print(
                     Some(rhs_line_num) => {
                         let rhs_line = &rhs_colored_lines[rhs_line_num.as_usize()];
                         if same_lines {
-                            print!("{}{}", display_rhs_line_num, rhs_line);
+                            write!(out, "{}{}", display_rhs_line_num, rhs_line)?;
                         } else {
-                            print!(
+                            write!(
+                                out,
                                 "{}{}{}",
                                 display_lhs_line_num, display_rhs_line_num, rhs_line
-                            );
+                            )?;
                         }
                     }
                     None => {
                         // We didn't have any changed RHS lines in the
                         // hunk, but we had some contextual lines that
                         // only occurred on the LHS (e.g. extra newlines).
-                        println!("{}{}", display_lhs_line_num, display_rhs_line_num);
+                        writeln!(out, "{}{}", display_lhs_line_num, display_rhs_line_num)?;
                     }
                 }
             } else if no_rhs_changes && !show_both {
@@ -498,16 +531,17 @@ pub fn print(
                     Some(lhs_line_num) => {
                         let lhs_line = &lhs_colored_lines[lhs_line_num.as_usize()];
                         if same_lines {
-                            print!("{}{}", display_lhs_line_num, lhs_line);
+                            write!(out, "{}{}", display_lhs_line_num, lhs_line)?;
                         } else {
-                            print!(
+                            write!(
+                                out,
                                 "{}{}{}",
                                 display_lhs_line_num, display_rhs_line_num, lhs_line
-                            );
+                            )?;
                         }
                     }
                     None => {
-                        println!("{}{}", display_lhs_line_num, display_rhs_line_num);
+                        writeln!(out, "{}{}", display_lhs_line_num, display_rhs_line_num)?;
                     }
                 }
             } else {
@@ -580,7 +614,11 @@ pub fn print(
                         s
                     };
 
-                    println!("{}{}{}{}{}", lhs_num, lhs_line, SPACER, rhs_num, rhs_line);
+                    writeln!(
+                        out,
+                        "{}{}{}{}{}",
+                        lhs_num, lhs_line, SPACER, rhs_num, rhs_line
+                    )?;
                 }
             }
 
@@ -591,8 +629,10 @@ pub fn print(
                 prev_rhs_line_num = *rhs_line_num;
             }
         }
-        println!();
+        writeln!(out)?;
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -346,7 +346,7 @@ pub fn color_positions(
                     }
                 }
             }
-            MatchKind::Novel { highlight, .. } => {
+            MatchKind::Novel { highlight, .. } | MatchKind::Replaced { highlight, .. } => {
                 style = novel_style(style, side, background);
                 if syntax_highlight
                     && matches!(
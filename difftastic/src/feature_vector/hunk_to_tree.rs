@@ -2,6 +2,7 @@ use crate::{
     parse::syntax::{Syntax, MatchedPos, MatchKind, get_novel_nodes},
     display::hunks::{Hunk},
     lines::LineNumber, positions::SingleLineSpan,
+    parse::tree_sitter_parser::SubTrees,
 };
 use rustc_hash::FxHashMap;
 use tree_sitter as ts;
@@ -113,6 +114,23 @@ pub fn matched_pos_to_tree_node<'a>(matched_pos: &MatchedPos, cursor: &mut ts::T
     }
 }
 
+// 同 matched_pos_to_tree_node，但如果节点在子语言（如 Rust 宏的 token_tree）中被重新解析过，
+// 则返回（最内层）子语言树中的节点，这样向量里的节点类型是真正的表达式类型
+pub fn matched_pos_to_sub_tree_node<'a>(matched_pos: &MatchedPos, tree: &'a ts::Tree, subtrees: &'a SubTrees) -> Option<Node<'a>>{
+    let node = matched_pos_to_tree_node(matched_pos, &mut tree.walk())?.node();
+    let mut ancestor = Some(node);
+    while let Some(n) = ancestor {
+        if let Some(subtree) = subtrees.get(&n.id()) {
+            if let Some(sub_node) = matched_pos_to_sub_tree_node(matched_pos, &subtree.tree, &subtree.subtrees) {
+                return Some(sub_node);
+            }
+            break;
+        }
+        ancestor = n.parent();
+    }
+    Some(node)
+}
+
 // 判断一个matchedpos的line span是否包含在一个syntax node对应的line span中（一个syntax node可能跨行，而matched pos不会）
 fn is_inside_span(single_span: SingleLineSpan, spans: &Vec<SingleLineSpan>) -> bool{
     if spans.len() == 1{ // syntax node 没有跨行，那么只要判断两个line span是否相同
@@ -1,2 +1,4 @@
 pub mod hunk_to_tree;
+pub mod path_contexts;
+pub mod token_ngrams;
 pub mod tree_to_vector;
\ No newline at end of file
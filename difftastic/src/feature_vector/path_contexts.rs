@@ -0,0 +1,358 @@
+//! Path-context export for changed subtrees, in the style of
+//! code2vec.
+//!
+//! A path context is a triple (leaf, AST path, leaf) between two
+//! novel leaves of the same hunk. We write the contexts in the
+//! code2vec text format, and a hashed bag-of-paths vector for each
+//! hunk in svmlight format, which most ML libraries can load
+//! directly.
+
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+};
+
+use crate::parse::{normalize::Normalizer, syntax::AtomKind};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use tree_sitter as ts;
+
+/// code2vec only considers paths with at most this many edges.
+pub const DEFAULT_MAX_PATH_LENGTH: usize = 8;
+/// Don't emit more than this many contexts for one side of a hunk,
+/// so large hunks don't dominate the output.
+pub const DEFAULT_MAX_CONTEXTS: usize = 200;
+pub const DEFAULT_EMBEDDING_DIM: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathContext {
+    pub start: String,
+    pub path: String,
+    pub end: String,
+}
+
+impl fmt::Display for PathContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.start, self.path, self.end)
+    }
+}
+
+/// Replace characters that have a meaning in the code2vec and
+/// svmlight formats.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_whitespace() || c == ',' || c == '#' {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn leaf_atom_kind(node: &ts::Node) -> AtomKind {
+    let kind = node.kind();
+    if !node.is_named() {
+        AtomKind::Keyword
+    } else if kind.contains("string") || kind.contains("char") {
+        AtomKind::String
+    } else if kind.contains("type") {
+        AtomKind::Type
+    } else {
+        AtomKind::Normal
+    }
+}
+
+fn leaf_token(node: &ts::Node, src: &str, normalizer: &mut Normalizer) -> String {
+    let text = src.get(node.start_byte()..node.end_byte()).unwrap_or("");
+    let start = node.start_position();
+    let token = normalizer
+        .normalize_atom(
+            text,
+            leaf_atom_kind(node),
+            ((start.row as u32).into(), start.column as u32),
+        )
+        .unwrap_or_else(|| text.to_owned());
+    sanitize(&token)
+}
+
+/// The ancestors of `node`, starting with `node` itself, up to
+/// `max_depth` levels above it.
+fn ancestors<'a>(node: &ts::Node<'a>, max_depth: usize) -> Vec<ts::Node<'a>> {
+    let mut res = vec![*node];
+    let mut current = *node;
+    while res.len() <= max_depth {
+        match current.parent() {
+            Some(parent) => {
+                res.push(parent);
+                current = parent;
+            }
+            None => break,
+        }
+    }
+    res
+}
+
+/// The AST path up through `up` and down through `down`, such as
+/// `identifier↑arguments↑call_expression↓identifier`. Both slices
+/// start at a leaf and end at the leaves' lowest common ancestor.
+/// Kinds can contain underscores and ASCII punctuation, so the path
+/// uses `↑` and `↓` for steps up and down the tree.
+fn path_through(up: &[ts::Node], down: &[ts::Node]) -> String {
+    let mut path = String::new();
+    for node in &up[..up.len() - 1] {
+        path.push_str(node.kind());
+        path.push('↑');
+    }
+    path.push_str(up[up.len() - 1].kind());
+    for node in down[..down.len() - 1].iter().rev() {
+        path.push('↓');
+        path.push_str(node.kind());
+    }
+    sanitize(&path)
+}
+
+/// Path contexts between pairs of distinct leaves in `leaves` whose
+/// path has at most `max_path_length` edges.
+///
+/// Each leaf is grouped under its ancestors up to `max_path_length`
+/// levels above it, and a pair is only formed under its lowest common
+/// ancestor, where the two leaves come from different children. This
+/// avoids considering pairs that are too far apart, so
+/// `max_contexts` bounds the work for large hunks.
+pub fn path_contexts(
+    leaves: &[ts::Node],
+    src: &str,
+    normalizer: &mut Normalizer,
+    max_path_length: usize,
+    max_contexts: usize,
+) -> Vec<PathContext> {
+    // Several MatchedPos values can map to the same tree-sitter node,
+    // e.g. a multiline string.
+    let mut seen = FxHashSet::default();
+    let mut leaves: Vec<ts::Node> = leaves
+        .iter()
+        .filter(|node| seen.insert(node.id()))
+        .copied()
+        .collect();
+    leaves.sort_by_key(|node| node.start_byte());
+
+    // Normalize in source order, so renaming is stable.
+    let tokens: Vec<String> = leaves
+        .iter()
+        .map(|node| leaf_token(node, src, normalizer))
+        .collect();
+
+    let leaf_ancestors: Vec<Vec<ts::Node>> = leaves
+        .iter()
+        .map(|node| ancestors(node, max_path_length))
+        .collect();
+
+    // For each ancestor, the leaves below it in source order, with
+    // their depth and the child of the ancestor they're under. A leaf
+    // is its own ancestor at depth 0, with no child.
+    let mut groups: FxHashMap<usize, Vec<(usize, usize, Option<usize>)>> = FxHashMap::default();
+    for (i, ancestors) in leaf_ancestors.iter().enumerate() {
+        for (depth, ancestor) in ancestors.iter().enumerate() {
+            let child = depth.checked_sub(1).map(|d| ancestors[d].id());
+            groups
+                .entry(ancestor.id())
+                .or_default()
+                .push((i, depth, child));
+        }
+    }
+
+    let mut res = vec![];
+    for (i, ancestors) in leaf_ancestors.iter().enumerate() {
+        for (depth, ancestor) in ancestors.iter().enumerate() {
+            let child = depth.checked_sub(1).map(|d| ancestors[d].id());
+            let group = &groups[&ancestor.id()];
+            let after_i = group.partition_point(|(j, _, _)| *j <= i);
+
+            for (j, other_depth, other_child) in &group[after_i..] {
+                if *other_child == child || depth + other_depth > max_path_length {
+                    continue;
+                }
+                if res.len() >= max_contexts {
+                    return res;
+                }
+
+                res.push(PathContext {
+                    start: tokens[i].clone(),
+                    path: path_through(&ancestors[..=depth], &leaf_ancestors[*j][..=*other_depth]),
+                    end: tokens[*j].clone(),
+                });
+            }
+        }
+    }
+
+    res
+}
+
+/// Hash every path into one of `dim` buckets, ignoring the leaf
+/// tokens. Returns (index, count) pairs sorted by index.
+///
+/// This uses `FxHasher`, which is not randomly seeded, so indexes are
+/// stable between runs.
+pub fn hashed_bag_of_paths(contexts: &[PathContext], dim: usize) -> Vec<(usize, u32)> {
+    let mut counts: FxHashMap<usize, u32> = FxHashMap::default();
+    if dim == 0 {
+        return vec![];
+    }
+
+    for context in contexts {
+        let mut hasher = FxHasher::default();
+        context.path.hash(&mut hasher);
+        let index = (hasher.finish() % dim as u64) as usize;
+        *counts.entry(index).or_insert(0) += 1;
+    }
+
+    let mut res: Vec<_> = counts.into_iter().collect();
+    res.sort_unstable();
+    res
+}
+
+/// Write a line in the code2vec format: `label ctx ctx ...`.
+pub fn write_code2vec_line(
+    out: &mut impl Write,
+    label: &str,
+    contexts: &[PathContext],
+) -> io::Result<()> {
+    write!(out, "{}", sanitize(label))?;
+    for context in contexts {
+        write!(out, " {}", context)?;
+    }
+    writeln!(out)
+}
+
+/// Write a line in the svmlight format: `0 index:value ... # comment`.
+///
+/// svmlight indexes are one-based, and the target value is always 0
+/// because we don't have labels.
+pub fn write_svmlight_line(
+    out: &mut impl Write,
+    features: &[(usize, u32)],
+    comment: &str,
+) -> io::Result<()> {
+    write!(out, "0")?;
+    for (index, count) in features {
+        write!(out, " {}:{}", index + 1, count)?;
+    }
+    writeln!(out, " # {}", comment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        guess_language::Language, normalize::NormalizeOptions, tree_sitter_parser as tsp,
+    };
+
+    fn leaves<'a>(node: ts::Node<'a>, res: &mut Vec<ts::Node<'a>>) {
+        if node.child_count() == 0 {
+            res.push(node);
+        }
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            leaves(child, res);
+        }
+    }
+
+    #[test]
+    fn test_path_contexts() {
+        let src = "fn f(x: T) { x.unwrap(); }";
+        let config = tsp::from_language(Language::Rust);
+        let tree = tsp::to_tree(src, &config);
+
+        let mut nodes = vec![];
+        leaves(tree.root_node(), &mut nodes);
+        let nodes: Vec<_> = nodes
+            .into_iter()
+            .filter(|node| node.kind() == "identifier" || node.kind() == "field_identifier")
+            .collect();
+
+        let options = NormalizeOptions::default();
+        let locals = tsp::local_positions(&tree, src, &config);
+        let mut normalizer = Normalizer::new(&options, &locals);
+        let contexts = path_contexts(
+            &nodes,
+            src,
+            &mut normalizer,
+            DEFAULT_MAX_PATH_LENGTH,
+            DEFAULT_MAX_CONTEXTS,
+        );
+
+        assert!(contexts.contains(&PathContext {
+            start: "$id0".into(),
+            path: "identifier↑field_expression↓field_identifier".into(),
+            end: "unwrap".into(),
+        }));
+    }
+
+    #[test]
+    fn test_path_contexts_max_length() {
+        let src = "fn f() { a(b); c(d); }";
+        let config = tsp::from_language(Language::Rust);
+        let tree = tsp::to_tree(src, &config);
+
+        let mut nodes = vec![];
+        leaves(tree.root_node(), &mut nodes);
+        let nodes: Vec<_> = nodes
+            .into_iter()
+            .filter(|node| node.kind() == "identifier")
+            .collect();
+
+        let options = NormalizeOptions::default();
+        let locals = tsp::local_positions(&tree, src, &config);
+        let mut normalizer = Normalizer::new(&options, &locals);
+        let contexts = path_contexts(&nodes, src, &mut normalizer, 4, DEFAULT_MAX_CONTEXTS);
+
+        // Only the calls are short enough. Paths between statements
+        // have at least six edges.
+        let path = "identifier↑call_expression↓arguments↓identifier";
+        assert_eq!(
+            contexts,
+            vec![
+                PathContext {
+                    start: "a".into(),
+                    path: path.into(),
+                    end: "b".into(),
+                },
+                PathContext {
+                    start: "c".into(),
+                    path: path.into(),
+                    end: "d".into(),
+                },
+            ]
+        );
+
+        // Every context counts towards the limit.
+        let contexts = path_contexts(&nodes, src, &mut normalizer, 4, 1);
+        assert_eq!(contexts.len(), 1);
+    }
+
+    #[test]
+    fn test_hashed_bag_of_paths_counts() {
+        let context = PathContext {
+            start: "a".into(),
+            path: "identifier↑call_expression↓identifier".into(),
+            end: "b".into(),
+        };
+
+        let features = hashed_bag_of_paths(&[context.clone(), context], 16);
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].1, 2);
+        assert!(features[0].0 < 16);
+    }
+
+    #[test]
+    fn test_write_svmlight_line() {
+        let mut out = vec![];
+        write_svmlight_line(&mut out, &[(0, 2), (5, 1)], "repo commit Added").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0 1:2 6:1 # repo commit Added\n"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Token n-gram features over the novel tokens of a hunk.
+//!
+//! Node kinds can't distinguish `.unwrap()` from `.expect()`, so this
+//! feature family looks at the (normalized) token text instead.
+
+use crate::{
+    lines::LineNumber,
+    parse::{
+        normalize::Normalizer,
+        syntax::{AtomKind, MatchKind, MatchedPos, TokenKind},
+    },
+};
+use rustc_hash::FxHashMap;
+
+/// The value written in the change type column of the vector file for
+/// n-gram rows, so they can be told apart from node kind rows.
+pub const TOKEN_NGRAM_NAMESPACE: &str = "TokenNgram";
+
+fn token_kind_name(kind: TokenKind) -> &'static str {
+    match kind {
+        TokenKind::Delimiter => "delimiter",
+        TokenKind::Atom(AtomKind::Normal) => "normal",
+        TokenKind::Atom(AtomKind::String) => "string",
+        TokenKind::Atom(AtomKind::Type) => "type",
+        TokenKind::Atom(AtomKind::Comment) => "comment",
+        TokenKind::Atom(AtomKind::Keyword) => "keyword",
+        TokenKind::Atom(AtomKind::TreeSitterError) => "error",
+    }
+}
+
+fn highlight(matched_pos: &MatchedPos) -> TokenKind {
+    match matched_pos.kind {
+        MatchKind::UnchangedToken { highlight, .. }
+        | MatchKind::Novel { highlight }
+        | MatchKind::NovelLinePart { highlight, .. }
+        | MatchKind::NovelWord { highlight }
+        | MatchKind::Replaced { highlight, .. }
+        | MatchKind::Ignored { highlight } => highlight,
+    }
+}
+
+/// The source text covered by `matched_pos`.
+fn token_text<'s>(src_lines: &[&'s str], matched_pos: &MatchedPos) -> &'s str {
+    let span = matched_pos.pos;
+    src_lines
+        .get(span.line.0 as usize)
+        .and_then(|line| line.get(span.start_col as usize..span.end_col as usize))
+        .unwrap_or("")
+}
+
+/// Describe every novel token in `novels` as `kind:text`, in source
+/// order, with the text normalized by `normalizer`.
+pub fn novel_tokens(
+    novels: &FxHashMap<LineNumber, Vec<&MatchedPos>>,
+    src: &str,
+    normalizer: &mut Normalizer,
+) -> Vec<String> {
+    let src_lines: Vec<&str> = src.split('\n').collect();
+
+    let mut positions: Vec<&MatchedPos> = novels.values().flatten().copied().collect();
+    positions.sort_by_key(|matched_pos| matched_pos.pos);
+
+    positions
+        .into_iter()
+        .filter_map(|matched_pos| {
+            let text = token_text(&src_lines, matched_pos);
+            if text.is_empty() {
+                return None;
+            }
+
+            let kind = highlight(matched_pos);
+            Some(format!(
+                "{}:{}",
+                token_kind_name(kind),
+                normalizer.normalize_token(
+                    text,
+                    kind,
+                    (matched_pos.pos.line, matched_pos.pos.start_col)
+                )
+            ))
+        })
+        .collect()
+}
+
+/// All the contiguous runs of `n` tokens. If there are fewer than `n`
+/// tokens, return them as a single shorter n-gram so small changes
+/// still produce a feature.
+pub fn ngrams(tokens: &[String], n: usize) -> Vec<String> {
+    if n == 0 || tokens.is_empty() {
+        return vec![];
+    }
+    if tokens.len() < n {
+        return vec![tokens.join(" ")];
+    }
+
+    tokens.windows(n).map(|window| window.join(" ")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strs(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ngrams() {
+        let tokens = strs(&["normal:$id0", "delimiter:.", "normal:unwrap"]);
+        assert_eq!(
+            ngrams(&tokens, 2),
+            strs(&["normal:$id0 delimiter:.", "delimiter:. normal:unwrap"])
+        );
+    }
+
+    #[test]
+    fn test_ngrams_fewer_tokens_than_n() {
+        let tokens = strs(&["normal:expect"]);
+        assert_eq!(ngrams(&tokens, 3), strs(&["normal:expect"]));
+    }
+
+    #[test]
+    fn test_ngrams_disabled() {
+        let tokens = strs(&["normal:expect"]);
+        assert!(ngrams(&tokens, 0).is_empty());
+    }
+}
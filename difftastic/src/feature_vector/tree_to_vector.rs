@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt::{Display, write}};
+use std::{collections::{HashMap, HashSet}, fmt::{Display, write}};
 
 use crate::{
     parse::syntax::{Syntax, MatchedPos, MatchKind, get_novel_nodes},
@@ -16,7 +16,9 @@ pub enum ChangeType{
     Added,
     Deleted,
     MaybeUpdated,
-    DeletedThenAdded
+    DeletedThenAdded,
+    /// An atom replaced by a similar atom, e.g. a renamed identifier.
+    Replaced,
 }
 impl Display for ChangeType{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -24,7 +26,8 @@ impl Display for ChangeType{
             ChangeType::Added => write!(f, "Added"),
             ChangeType::Deleted => write!(f, "Deleted"),
             ChangeType::DeletedThenAdded => write!(f, "DeltedThenAdded"),
-            ChangeType::MaybeUpdated => write!(f, "MaybeUpdated")
+            ChangeType::MaybeUpdated => write!(f, "MaybeUpdated"),
+            ChangeType::Replaced => write!(f, "Replaced"),
         }
     }
 }
@@ -119,6 +122,7 @@ pub fn get_node_change_type<'a> (nodes_1: &Vec<TreeCursor<'a>>, nodes_2: &Vec<Tr
                 j -= 1;
                 maybe_updated.push((nodes_1[i].clone(), nodes_2[j].clone()));
             }
+            ChangeType::Replaced => unreachable!("calculate_edit_action doesn't produce replacements"),
         }
     }
 
@@ -126,8 +130,15 @@ pub fn get_node_change_type<'a> (nodes_1: &Vec<TreeCursor<'a>>, nodes_2: &Vec<Tr
 }
 
 
-pub fn tag_change_type<'a>(lhs_nodes: &'a Vec<Node<'a>>, rhs_nodes: &'a Vec<Node<'a>>) -> HashMap<&'a Node<'a>, ChangeType>{
+/// Tag each novel node as added or deleted, or as replaced if it's in
+/// `replaced_nodes`.
+pub fn tag_change_type<'a>(lhs_nodes: &'a Vec<Node<'a>>, rhs_nodes: &'a Vec<Node<'a>>, replaced_nodes: &[Node<'a>]) -> HashMap<&'a Node<'a>, ChangeType>{
     let mut change_type_map = HashMap::new();
+    for node in lhs_nodes.iter().chain(rhs_nodes.iter()) {
+        if replaced_nodes.contains(node) {
+            change_type_map.insert(node, ChangeType::Replaced);
+        }
+    }
     for (_, lhs_node) in lhs_nodes.iter().enumerate(){
         change_type_map.entry(lhs_node).or_insert(ChangeType::Deleted);
     }
@@ -171,9 +182,7 @@ pub fn calculate_edit_action<'a>(nodes_1: &Vec<TreeCursor>, nodes_2: &Vec<TreeCu
             }
         }
     }
-    if (nodes_1.len() == 6 && nodes_2.len() == 7){
-        println!("{:#?}", path);
-    }
+    debug!("Edit actions: {:#?}", path);
     path
 }
 
@@ -182,8 +191,19 @@ pub fn get_parent_kind(node: &Node) -> &'static str{
     node.parent().unwrap().kind()
 }
 
-pub fn get_grandparent_kind(node: &Node) -> &'static str{
-    node.parent().unwrap().parent().unwrap().kind()
+/// The kind recorded as the context of `node`: the nearest ancestor
+/// above its parent whose kind is in `container_kinds`, or its
+/// grandparent if there is none.
+pub fn get_context_kind(node: &Node, container_kinds: &HashSet<String>) -> &'static str{
+    let grandparent = node.parent().unwrap().parent().unwrap();
+    let mut ancestor = Some(grandparent);
+    while let Some(container) = ancestor {
+        if container_kinds.contains(container.kind()) {
+            return container.kind();
+        }
+        ancestor = container.parent();
+    }
+    grandparent.kind()
 }
 
 pub fn is_same_tree(cursor_1: &TreeCursor, cursor_2: &TreeCursor) -> bool {
@@ -242,4 +262,28 @@ fn is_same_label(cursor_1: &TreeCursor, cursor_2: &TreeCursor) -> bool {
     }
 
     false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        guess_language::Language,
+        tree_sitter_parser::{from_language, to_tree},
+    };
+
+    #[test]
+    fn test_context_kind() {
+        let config = from_language(Language::Rust);
+        let tree = to_tree("fn f() {\n    let x = 1;\n}\n", &config);
+        let node = tree.root_node().descendant_for_byte_range(21, 22).unwrap();
+        assert_eq!(node.kind(), "integer_literal");
+
+        // The grandparent, when the language has no containers.
+        assert_eq!(get_context_kind(&node, &HashSet::new()), "block");
+
+        let container_kinds: HashSet<String> =
+            vec!["function_item".to_owned()].into_iter().collect();
+        assert_eq!(get_context_kind(&node, &container_kinds), "function_item");
+    }
+}
@@ -23,6 +23,8 @@
 // correct.
 #![allow(clippy::mutable_key_type)]
 
+mod cluster_report;
+mod cluster_view;
 mod constants;
 mod diff;
 mod display;
@@ -33,27 +35,33 @@ mod lines;
 mod options;
 mod parse;
 mod positions;
+mod progress;
+mod stats;
 mod summary;
 mod feature_vector;
 
 #[macro_use]
 extern crate log;
 
-use crate::diff::{dijkstra, unchanged};
+use crate::diff::{sections, unchanged};
 use crate::display::hunks::{matched_pos_to_hunks, merge_adjacent};
-use crate::feature_vector::hunk_to_tree;
+use crate::feature_vector::{hunk_to_tree, path_contexts, token_ngrams};
+use crate::parse::normalize::{
+    shift_positions, LocalPositions, NormalizeOptions, Normalizer, Substitutions,
+};
 use crate::parse::guess_language::{LANG_EXTENSIONS, LANG_FILE_NAMES};
-use crate::parse::syntax::{self, Syntax};
+use crate::parse::incremental::ParseSession;
+use crate::parse::loaded_grammars;
+use crate::parse::syntax::{self, MatchKind, MatchedPos, Syntax};
 use diff::changes::ChangeMap;
-use diff::dijkstra::ExceededGraphLimit;
 use display::context::opposite_positions;
-use exit_codes::{EXIT_FOUND_CHANGES, EXIT_SUCCESS};
+use exit_codes::{EXIT_BAD_ARGUMENTS, EXIT_FOUND_CHANGES, EXIT_SUCCESS};
 use files::{
     guess_content, read_files_or_die, read_or_die, relative_paths_in_either, ProbableFileKind,
 };
 use log::info;
 use mimalloc::MiMalloc;
-use parse::guess_language::{guess, language_name};
+use parse::guess_language::{guess, guess_unnamed, language_name};
 
 /// The global allocator used by difftastic.
 ///
@@ -70,16 +78,14 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use std::{env, path::Path};
 use summary::{DiffResult, FileContent, FileFormat};
-use syntax::init_next_prev;
+use syntax::init_all_info_with;
 use typed_arena::Arena;
 //use tree_edit_distance::diff;
 
-use crate::{
-    dijkstra::mark_syntax, lines::MaxLine, parse::syntax::init_all_info,
-    parse::tree_sitter_parser as tsp,
-};
+use crate::{lines::MaxLine, parse::syntax::init_all_info, parse::tree_sitter_parser as tsp};
 
 extern crate pretty_env_logger;
 
@@ -98,7 +104,14 @@ fn reset_sigpipe() {
 
 /// The entrypoint.
 fn main() {
-    pretty_env_logger::init_timed();
+    // Show warnings, such as sections that fell back to a line diff,
+    // unless RUST_LOG says otherwise.
+    let mut logger = pretty_env_logger::formatted_timed_builder();
+    logger.filter_level(log::LevelFilter::Warn);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    logger.init();
     reset_sigpipe();
 
     match options::parse_args() {
@@ -145,6 +158,131 @@ fn main() {
                 }
             }
         }
+        Mode::ViewClusters {
+            diff_options,
+            display_options,
+            language_override,
+            hac_path,
+            code_dir,
+        } => {
+            let hac_src = String::from_utf8_lossy(&read_or_die(&hac_path)).to_string();
+            let clusters = cluster_view::parse_hac_results(&hac_src);
+
+            let render = |files: &cluster_view::MemberFiles, width: usize| {
+                let mut display_options = display_options.clone();
+                display_options.display_width = width;
+
+                let lhs_display_path = files.before.display().to_string();
+                let rhs_display_path = files.after.display().to_string();
+                let diff_result = diff_file(
+                    &lhs_display_path,
+                    &rhs_display_path,
+                    &FileArgument::NamedPath(files.before.clone()),
+                    &FileArgument::NamedPath(files.after.clone()),
+                    &display_options,
+                    &diff_options,
+                    language_override,
+                );
+
+                let mut buf = vec![];
+                if let (FileContent::Text(lhs_src), FileContent::Text(rhs_src)) =
+                    (&diff_result.lhs_src, &diff_result.rhs_src)
+                {
+                    if !diff_result.hunks.is_empty() {
+                        display::side_by_side::write_to(
+                            &mut buf,
+                            &diff_result.hunks,
+                            &display_options,
+                            &diff_result.lhs_display_path,
+                            &diff_result.rhs_display_path,
+                            &diff_result.file_format,
+                            lhs_src,
+                            rhs_src,
+                            &diff_result.lhs_positions,
+                            &diff_result.rhs_positions,
+                        )
+                        .expect("writing to a Vec cannot fail");
+                    }
+                }
+                if buf.is_empty() {
+                    return vec!["No syntactic changes.".to_owned()];
+                }
+
+                String::from_utf8_lossy(&buf)
+                    .lines()
+                    .map(|line| line.to_owned())
+                    .collect()
+            };
+
+            if let Err(e) = cluster_view::run(&clusters, &code_dir, render) {
+                eprintln!("Cluster viewer failed: {}", e);
+            }
+        }
+        Mode::ClusterReport {
+            diff_options,
+            display_options,
+            language_override,
+            hac_path,
+            code_dir,
+            out_dir,
+            vector_path,
+        } => {
+            let hac_src = String::from_utf8_lossy(&read_or_die(&hac_path)).to_string();
+            let clusters = cluster_view::parse_hac_results(&hac_src);
+            let histograms = vector_path.map(|vector_path| {
+                cluster_report::parse_vector_file(&String::from_utf8_lossy(&read_or_die(
+                    &vector_path,
+                )))
+            });
+
+            let render = |files: &cluster_view::MemberFiles, out: &mut Vec<u8>| {
+                let lhs_display_path = files.before.display().to_string();
+                let rhs_display_path = files.after.display().to_string();
+                let diff_result = diff_file(
+                    &lhs_display_path,
+                    &rhs_display_path,
+                    &FileArgument::NamedPath(files.before.clone()),
+                    &FileArgument::NamedPath(files.after.clone()),
+                    &display_options,
+                    &diff_options,
+                    language_override,
+                );
+
+                match (&diff_result.lhs_src, &diff_result.rhs_src) {
+                    (FileContent::Text(lhs_src), FileContent::Text(rhs_src))
+                        if !diff_result.hunks.is_empty() =>
+                    {
+                        display::html::write_diff(
+                            out,
+                            &diff_result.hunks,
+                            &diff_result.lhs_display_path,
+                            &diff_result.rhs_display_path,
+                            lhs_src,
+                            rhs_src,
+                            &diff_result.lhs_positions,
+                            &diff_result.rhs_positions,
+                            display_options.num_context_lines as usize,
+                        )
+                    }
+                    _ => writeln!(out, "<p>No syntactic changes.</p>"),
+                }
+            };
+
+            match cluster_report::write_report(
+                &out_dir,
+                &clusters,
+                &code_dir,
+                histograms.as_ref(),
+                render,
+            ) {
+                Ok(()) => println!(
+                    "Wrote {} clusters to {}",
+                    clusters.len(),
+                    out_dir.join("index.html").display()
+                ),
+                Err(e) => eprintln!("Writing HTML report failed: {}", e),
+            }
+        }
         Mode::ListLanguages { use_color } => {
             for (language, extensions) in LANG_EXTENSIONS {
                 let mut name = language_name(*language).to_string();
@@ -172,6 +310,22 @@ fn main() {
                 }
                 println!();
             }
+
+            for (_, grammar) in loaded_grammars::all() {
+                let mut name = grammar.name.to_string();
+                if use_color {
+                    name = name.bold().to_string();
+                }
+                println!("{}", name);
+
+                for extension in &grammar.extensions {
+                    print!(" *.{}", extension);
+                }
+                for file_name in &grammar.file_names {
+                    print!(" {}", file_name);
+                }
+                println!();
+            }
         }
         Mode::Diff {
             diff_options,
@@ -185,7 +339,18 @@ fn main() {
             repo_name,
             commit_hash,
             vector_file,
+            feature_options,
+            stats_file,
+            progress_file,
         } => {
+            let start_time = Instant::now();
+            let mut progress = progress_file.map(|path| {
+                progress::open(&path, &repo_name, &commit_hash, &rhs_display_path).unwrap_or_else(|e| {
+                    eprintln!("Could not open progress stream {}: {}", path, e);
+                    std::process::exit(EXIT_BAD_ARGUMENTS);
+                })
+            });
+
             // get tree-sitter::Tree
             // if diff_options.ignore_comments {
             //     let lhs_comments =
@@ -203,23 +368,52 @@ fn main() {
             let lhs_src = String::from_utf8_lossy(&read_or_die(lhs_path)).to_string();
 
             let language = language_override.or_else(|| guess(lhs_path, &lhs_src)).unwrap();
-            let ts_lang = tsp::from_language(language);
-            let lhs_arena = Arena::new();
-            let lhs_ast = tsp::parse(&lhs_arena, &lhs_src, &ts_lang, true) ;
+            // With --incremental-parse, the RHS is parsed from the LHS,
+            // reusing the top-level nodes outside the edited lines.
+            let arena = Arena::new();
+            let mut parse_session = ParseSession::new(
+                &arena,
+                tsp::from_language_with_options(language, &diff_options),
+                true,
+            );
+            let parse_start = Instant::now();
+            let lhs_parsed = parse_session.parse(&lhs_src);
+            let lhs_parse = parse_start.elapsed();
+            let lhs_ast = lhs_parsed.nodes;
+            if !diff_options.incremental_parse {
+                parse_session.forget_previous();
+            }
             // get rhs_ast
             let rhs_path = Path::new(&rhs_display_path);
             let rhs_src = String::from_utf8_lossy(&read_or_die(rhs_path)).to_string();
 
-            let rhs_arena = Arena::new();
-            let rhs_ast = tsp::parse(&rhs_arena, &rhs_src, &ts_lang, true) ;
+            let parse_start = Instant::now();
+            let rhs_parsed = parse_session.parse(&rhs_src);
+            let rhs_parse = parse_start.elapsed();
+            let rhs_ast = rhs_parsed.nodes;
+            info!("Reused {} top-level RHS nodes from the LHS", rhs_parsed.num_reused);
 
 
-            init_all_info(&lhs_ast, &rhs_ast);
-            // println!("{}", rhs_ast.len());
-
             // tree-sitter::Tree
-            let lhs_tree = tsp::to_tree(&lhs_src[..], &ts_lang);
-            let rhs_tree = tsp::to_tree(&rhs_src[..], &ts_lang);
+            let lhs_tree = lhs_parsed.tree;
+            let rhs_tree = rhs_parsed.tree;
+
+            // The token n-gram and path context features normalise
+            // locals even without --normalize.
+            let lhs_locals = tsp::local_positions(&lhs_tree, &lhs_src, parse_session.config());
+            let rhs_locals = tsp::local_positions(&rhs_tree, &rhs_src, parse_session.config());
+            init_all_info_with(
+                &lhs_ast,
+                &rhs_ast,
+                diff_options
+                    .normalize
+                    .as_ref()
+                    .map(|options| (options, &lhs_locals, &rhs_locals)),
+            );
+            // println!("{}", rhs_ast.len());
+            // trees of macro bodies etc. parsed as sub-languages
+            let lhs_subtrees = lhs_parsed.subtrees;
+            let rhs_subtrees = rhs_parsed.subtrees;
 
             let mut change_map = ChangeMap::default();
             let possibly_changed = if env::var("DFT_DBG_KEEP_UNCHANGED").is_ok() {
@@ -228,30 +422,46 @@ fn main() {
                 unchanged::mark_unchanged(&lhs_ast, &rhs_ast, &mut change_map)
             };
 
-            let mut exceeded_graph_limit = false;
-
-            for (lhs_section_nodes, rhs_section_nodes) in possibly_changed {
-                init_next_prev(&lhs_section_nodes);
-                init_next_prev(&rhs_section_nodes);
-
-                match mark_syntax(
-                    lhs_section_nodes.get(0).copied(),
-                    rhs_section_nodes.get(0).copied(),
-                    &mut change_map,
-                    diff_options.graph_limit,
-                ) {
-                    Ok(()) => {}
-                    Err(ExceededGraphLimit {}) => {
-                        exceeded_graph_limit = true;
-                        break;
-                    }
+            let num_sections = possibly_changed.len();
+            let (degraded, search_stats) = sections::mark_sections(
+                possibly_changed,
+                &mut change_map,
+                diff_options.graph_limit,
+                diff_options.memory_budget,
+                diff_options.search_algorithm,
+                &diff_options.cost_model,
+                diff_options.fallback,
+            );
+            if !degraded.is_empty() {
+                let reason = sections::fallback_reason(&degraded, num_sections);
+                warn!("{}: {}", lhs_display_path, reason);
+                if let Some(progress) = &mut progress {
+                    progress.fallback(&reason);
                 }
             }
-            fix_all_sliders(language, &lhs_ast, &mut change_map);
-            fix_all_sliders(language, &rhs_ast, &mut change_map);
+            let policy = &parse_session.config().policy;
+            let slider_fixes = fix_all_sliders(policy, &lhs_ast, &mut change_map)
+                + fix_all_sliders(policy, &rhs_ast, &mut change_map);
 
             let mut lhs_positions = syntax::change_positions(&lhs_ast, &change_map);
             let mut rhs_positions = syntax::change_positions(&rhs_ast, &change_map);
+            lhs_positions.extend(tsp::ignored_positions(
+                &lhs_tree,
+                &lhs_src,
+                parse_session.config(),
+            ));
+            rhs_positions.extend(tsp::ignored_positions(
+                &rhs_tree,
+                &rhs_src,
+                parse_session.config(),
+            ));
+            sections::apply_line_fallback(
+                &lhs_src,
+                &rhs_src,
+                &mut lhs_positions,
+                &mut rhs_positions,
+                &degraded,
+            );
 
             // println!("lhs_pos = {:#?}", lhs_positions);
             // println!("rhs_pos = {:#?}", rhs_positions);
@@ -302,11 +512,13 @@ fn main() {
 
 
             // 获取每个hunk中Novel的MatchedPos对应的Syntax节点
-            for (_, hunk) in hunks.iter().enumerate(){
+            let mut num_vectors = 0;
+            for (hunk_index, hunk) in hunks.iter().enumerate(){
                 let mut lhs_novel_syntax:Vec<&Syntax> = vec![];
                 let mut lhs_novel_tree_node = vec![];
                 let mut rhs_novel_syntax:Vec<&Syntax> = vec![];
                 let mut rhs_novel_tree_node = vec![];
+                let mut replaced_tree_node = vec![];
                 let (lhs_novels, rhs_novels) = hunk_to_tree::get_novels_from_hunk(&lhs_positions, &rhs_positions, hunk);
                 // println!("{:#?}", lhs_novels);
                 // for (_, line_and_pos) in lhs_novels.iter().enumerate(){
@@ -375,15 +587,33 @@ fn main() {
                 // matched pos 匹配到tree node
                 for (_, matched_pos_map) in lhs_novels.iter().enumerate(){
                     for (_, matched_pos) in matched_pos_map.1.iter().enumerate(){
-                        let mut cursor = lhs_tree.walk();
-                        lhs_novel_tree_node.push(hunk_to_tree::matched_pos_to_tree_node(*matched_pos, &mut cursor).unwrap().node());
+                        let node = match hunk_to_tree::matched_pos_to_sub_tree_node(*matched_pos, &lhs_tree, &lhs_subtrees) {
+                            Some(node) => node,
+                            // The line diff of a degraded section can mark
+                            // the whitespace between nodes.
+                            None if matches!(matched_pos.kind, MatchKind::NovelWord { .. }) => continue,
+                            None => panic!("No tree-sitter node at {:?}", matched_pos.pos),
+                        };
+                        if matches!(matched_pos.kind, MatchKind::Replaced { .. }) {
+                            replaced_tree_node.push(node);
+                        }
+                        lhs_novel_tree_node.push(node);
                     }
                 }
 
                 for (_, matched_pos_map) in rhs_novels.iter().enumerate(){
                     for (_, matched_pos) in matched_pos_map.1.iter().enumerate(){
-                        let mut cursor = rhs_tree.walk();
-                        rhs_novel_tree_node.push(hunk_to_tree::matched_pos_to_tree_node(*matched_pos, &mut cursor).unwrap().node());
+                        let node = match hunk_to_tree::matched_pos_to_sub_tree_node(*matched_pos, &rhs_tree, &rhs_subtrees) {
+                            Some(node) => node,
+                            // The line diff of a degraded section can mark
+                            // the whitespace between nodes.
+                            None if matches!(matched_pos.kind, MatchKind::NovelWord { .. }) => continue,
+                            None => panic!("No tree-sitter node at {:?}", matched_pos.pos),
+                        };
+                        if matches!(matched_pos.kind, MatchKind::Replaced { .. }) {
+                            replaced_tree_node.push(node);
+                        }
+                        rhs_novel_tree_node.push(node);
                     }
                 }
 
@@ -396,15 +626,89 @@ fn main() {
                 //     // let node = cursor.node();
                 //     println!("{:?}, kind: {}, str:{}", node, node.kind(), &rhs_src[node.start_byte()..node.end_byte()]);
                 // }
-                let change_type_map = feature_vector::tree_to_vector::tag_change_type(&lhs_novel_tree_node, &rhs_novel_tree_node);
+                let change_type_map = feature_vector::tree_to_vector::tag_change_type(&lhs_novel_tree_node, &rhs_novel_tree_node, &replaced_tree_node);
+                num_vectors += change_type_map.len();
                 for (_, map) in change_type_map.iter().enumerate(){
                     //println!("node = {:?},\n  change_type = {:?},\n  type = {:?},\n  context = {:?}", map.0, map.1, feature_vector::tree_to_vector::get_parent_kind(*map.0), feature_vector::tree_to_vector::get_grandparent_kind(*map.0));
                     let mut vector_fp = OpenOptions::new().append(true).open(&vector_file[..]).expect("cannot open file");
                     let mut wtr = csv::Writer::from_writer(vector_fp);
-                    wtr.write_record(&[&repo_name, &commit_hash, &map.1.to_string(), &feature_vector::tree_to_vector::get_parent_kind(*map.0).to_string(), &feature_vector::tree_to_vector::get_grandparent_kind(*map.0).to_string()]).expect("wrtie vector into file failed");
+                    wtr.write_record(&[&repo_name, &commit_hash, &map.1.to_string(), &feature_vector::tree_to_vector::get_parent_kind(*map.0).to_string(), &feature_vector::tree_to_vector::get_context_kind(*map.0, &policy.container_kinds).to_string()]).expect("wrtie vector into file failed");
                     wtr.flush().expect("flush failed");
                     //vector_fp.write_all()
                 }
+
+                if feature_options.token_ngrams > 0 {
+                    let normalize_options = diff_options.normalize.clone().unwrap_or_default();
+                    let vector_fp = OpenOptions::new().append(true).open(&vector_file[..]).expect("cannot open file");
+                    let mut wtr = csv::Writer::from_writer(vector_fp);
+                    for (novels, src, locals, change_type) in [
+                        (
+                            &lhs_novels,
+                            &lhs_src,
+                            &lhs_locals,
+                            feature_vector::tree_to_vector::ChangeType::Deleted,
+                        ),
+                        (
+                            &rhs_novels,
+                            &rhs_src,
+                            &rhs_locals,
+                            feature_vector::tree_to_vector::ChangeType::Added,
+                        ),
+                    ] {
+                        let mut normalizer = Normalizer::new(&normalize_options, locals);
+                        let tokens = token_ngrams::novel_tokens(novels, src, &mut normalizer);
+                        for ngram in token_ngrams::ngrams(&tokens, feature_options.token_ngrams) {
+                            wtr.write_record([&repo_name, &commit_hash, token_ngrams::TOKEN_NGRAM_NAMESPACE, &change_type.to_string(), &ngram]).expect("write vector into file failed");
+                        }
+                    }
+                    wtr.flush().expect("flush failed");
+                }
+
+                if let Some(prefix) = &feature_options.embedding_export {
+                    let normalize_options = diff_options.normalize.clone().unwrap_or_default();
+                    let open_export = |extension: &str| {
+                        OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(format!("{}.{}", prefix, extension))
+                            .expect("cannot open embedding export file")
+                    };
+                    let mut c2v_fp = open_export("c2v");
+                    let mut svm_fp = open_export("svm");
+                    for (nodes, src, locals, change_type) in [
+                        (
+                            &lhs_novel_tree_node,
+                            &lhs_src,
+                            &lhs_locals,
+                            feature_vector::tree_to_vector::ChangeType::Deleted,
+                        ),
+                        (
+                            &rhs_novel_tree_node,
+                            &rhs_src,
+                            &rhs_locals,
+                            feature_vector::tree_to_vector::ChangeType::Added,
+                        ),
+                    ] {
+                        let mut normalizer = Normalizer::new(&normalize_options, locals);
+                        let contexts = path_contexts::path_contexts(
+                            nodes,
+                            src,
+                            &mut normalizer,
+                            path_contexts::DEFAULT_MAX_PATH_LENGTH,
+                            path_contexts::DEFAULT_MAX_CONTEXTS,
+                        );
+                        if contexts.is_empty() {
+                            continue;
+                        }
+
+                        let label = format!("{}/{}/{}", repo_name, commit_hash, change_type);
+                        path_contexts::write_code2vec_line(&mut c2v_fp, &label, &contexts)
+                            .expect("write path contexts failed");
+                        let features = path_contexts::hashed_bag_of_paths(&contexts, feature_options.embedding_dim);
+                        path_contexts::write_svmlight_line(&mut svm_fp, &features, &format!("{} {} {}", repo_name, commit_hash, change_type))
+                            .expect("write path vectors failed");
+                    }
+                }
                 
                 // let (added_nodes, deleted_nodes, updated_nodes ) = feature_vector::tree_to_vector::get_node_change_type(&lhs_novel_tree_node, &rhs_novel_tree_node, &feature_vector::tree_to_vector::calculate_edit_action(&lhs_novel_tree_node, &rhs_novel_tree_node));
                 // println!("--------------------------\n");
@@ -424,7 +728,9 @@ fn main() {
                 // for (_, updated_cursor) in updated_nodes.iter().enumerate(){
                 //     println!("{:?}, {:?}", updated_cursor.0.node(), updated_cursor.1.node());
                 // }
-                println!("--------------------------\n");
+                if let Some(progress) = &mut progress {
+                    progress.hunk(hunk_index, change_type_map.len());
+                }
             }
             // println!("--------------------------\n");
             // let lhs_root = lhs_tree.walk();
@@ -445,7 +751,40 @@ fn main() {
             //     println!("{:?}, {:?}", updated_cursor.0.node(), updated_cursor.1.node());
             // }
             // println!("--------------------------\n");
+            if let Some(progress) = &mut progress {
+                progress.done(hunks.len(), num_vectors);
+            }
+
+            if let Some(stats_file) = &stats_file {
+                let file_stats = stats::FileStats {
+                    repo_name: repo_name.clone(),
+                    commit_hash: commit_hash.clone(),
+                    path: rhs_display_path.clone(),
+                    language: language_name(language).into(),
+                    lhs_parse,
+                    rhs_parse,
+                    lhs_nodes: stats::node_count(&lhs_ast),
+                    rhs_nodes: stats::node_count(&rhs_ast),
+                    sections: num_sections,
+                    degraded_sections: degraded.len(),
+                    search: search_stats,
+                    slider_fixes,
+                    total: start_time.elapsed(),
+                };
+                if let Err(e) = stats::append_stats(stats_file, &file_stats) {
+                    eprintln!("Could not write stats to {}: {}", stats_file, e);
+                }
+            }
+
             // get diff result
+            let (lhs_src, rhs_src) = match &diff_options.normalize {
+                Some(options) if options.show_in_display => show_normalized(
+                    options,
+                    (&lhs_ast, &lhs_locals, &lhs_src, &mut lhs_positions),
+                    (&rhs_ast, &rhs_locals, &rhs_src, &mut rhs_positions),
+                ),
+                _ => (lhs_src, rhs_src),
+            };
             let has_syntactic_changes = !hunks.is_empty();
             let file_format = FileFormat::SupportedLanguage(language);
             let diff_result = DiffResult {
@@ -461,7 +800,6 @@ fn main() {
                 has_syntactic_changes,
             };
             print_diff_result(&display_options, &diff_result);
-            println!("repo name = {}, commit hash = {}", repo_name, commit_hash);
             //let (edits, cost) = diff(&lhs_tree, &rhs_tree);
             // println!("{}", feature_vector::tree_to_vector::is_same_tree(&lhs_tree.walk(), &rhs_tree.walk()));
         }
@@ -469,6 +807,27 @@ fn main() {
 }
 
 
+/// The source on each side with its normalised tokens, for
+/// `--show-normalized`. The positions are moved to match.
+fn show_normalized(
+    options: &NormalizeOptions,
+    lhs: (&[&Syntax], &LocalPositions, &str, &mut Vec<MatchedPos>),
+    rhs: (&[&Syntax], &LocalPositions, &str, &mut Vec<MatchedPos>),
+) -> (String, String) {
+    let (lhs_roots, lhs_locals, lhs_src, lhs_positions) = lhs;
+    let (rhs_roots, rhs_locals, rhs_src, rhs_positions) = rhs;
+
+    let lhs_substitutions = Substitutions::new(lhs_roots, options, lhs_locals);
+    let rhs_substitutions = Substitutions::new(rhs_roots, options, rhs_locals);
+    shift_positions(lhs_positions, &lhs_substitutions, &rhs_substitutions);
+    shift_positions(rhs_positions, &rhs_substitutions, &lhs_substitutions);
+
+    (
+        lhs_substitutions.apply(lhs_src),
+        rhs_substitutions.apply(rhs_src),
+    )
+}
+
 fn format_num_bytes(num_bytes: usize) -> String {
     if num_bytes >= 1024 * 1024 * 1024 {
         let g = num_bytes as f64 / (1024.0 * 1024.0 * 1024.0);
@@ -534,7 +893,7 @@ fn check_only_text(
 fn diff_file_content(
     lhs_display_path: &str,
     rhs_display_path: &str,
-    _lhs_path: &FileArgument,
+    lhs_path: &FileArgument,
     rhs_path: &FileArgument,
     lhs_bytes: &[u8],
     rhs_bytes: &[u8],
@@ -570,14 +929,18 @@ fn diff_file_content(
         rhs_src.pop();
     }
 
-    let (guess_src, guess_path) = match rhs_path {
-        FileArgument::NamedPath(_) => (&rhs_src, Path::new(&rhs_display_path)),
-        FileArgument::Stdin => (&rhs_src, Path::new(&lhs_display_path)),
-        FileArgument::DevNull => (&lhs_src, Path::new(&lhs_display_path)),
+    let (guess_src, guess_path, guess_file) = match rhs_path {
+        FileArgument::NamedPath(_) => (&rhs_src, Path::new(&rhs_display_path), rhs_path),
+        FileArgument::Stdin => (&rhs_src, Path::new(&lhs_display_path), lhs_path),
+        FileArgument::DevNull => (&lhs_src, Path::new(&lhs_display_path), lhs_path),
     };
 
-    let language = language_override.or_else(|| guess(guess_path, guess_src));
-    let lang_config = language.map(tsp::from_language);
+    let language = language_override.or_else(|| match guess_file {
+        FileArgument::NamedPath(_) => guess(guess_path, guess_src),
+        FileArgument::Stdin | FileArgument::DevNull => guess_unnamed(guess_path, guess_src),
+    });
+    let lang_config =
+        language.map(|language| tsp::from_language_with_options(language, diff_options));
 
     if lhs_bytes == rhs_bytes {
         let file_format = match language {
@@ -661,65 +1024,75 @@ fn diff_file_content(
                                 unchanged::mark_unchanged(&lhs, &rhs, &mut change_map)
                             };
 
-                            let mut exceeded_graph_limit = false;
-
-                            for (lhs_section_nodes, rhs_section_nodes) in possibly_changed {
-                                init_next_prev(&lhs_section_nodes);
-                                init_next_prev(&rhs_section_nodes);
-
-                                match mark_syntax(
-                                    lhs_section_nodes.get(0).copied(),
-                                    rhs_section_nodes.get(0).copied(),
-                                    &mut change_map,
-                                    diff_options.graph_limit,
-                                ) {
-                                    Ok(()) => {}
-                                    Err(ExceededGraphLimit {}) => {
-                                        exceeded_graph_limit = true;
-                                        break;
-                                    }
+                            let num_sections = possibly_changed.len();
+                            let (degraded, _) = sections::mark_sections(
+                                possibly_changed,
+                                &mut change_map,
+                                diff_options.graph_limit,
+                                diff_options.memory_budget,
+                                diff_options.search_algorithm,
+                                &diff_options.cost_model,
+                                diff_options.fallback,
+                            );
+
+                            // TODO: Make this .expect() unnecessary.
+                            let language = language
+                                .expect("If we had a ts_lang, we must have guessed the language");
+                            fix_all_sliders(&ts_lang.policy, &lhs, &mut change_map);
+                            fix_all_sliders(&ts_lang.policy, &rhs, &mut change_map);
+
+                            let mut lhs_positions = syntax::change_positions(&lhs, &change_map);
+                            let mut rhs_positions = syntax::change_positions(&rhs, &change_map);
+
+                            if diff_options.ignore_comments {
+                                let lhs_comments =
+                                    tsp::comment_positions(&lhs_tree, &lhs_src, &ts_lang);
+                                lhs_positions.extend(lhs_comments);
+
+                                let rhs_comments =
+                                    tsp::comment_positions(&rhs_tree, &rhs_src, &ts_lang);
+                                rhs_positions.extend(rhs_comments);
+                            }
+                            lhs_positions
+                                .extend(tsp::ignored_positions(&lhs_tree, &lhs_src, &ts_lang));
+                            rhs_positions
+                                .extend(tsp::ignored_positions(&rhs_tree, &rhs_src, &ts_lang));
+
+                            // Sections that were too big for the graph
+                            // search get a line diff, and the rest of
+                            // the file keeps its structural diff.
+                            sections::apply_line_fallback(
+                                &lhs_src,
+                                &rhs_src,
+                                &mut lhs_positions,
+                                &mut rhs_positions,
+                                &degraded,
+                            );
+
+                            if let Some(options) = &diff_options.normalize {
+                                if options.show_in_display {
+                                    let lhs_locals =
+                                        tsp::local_positions(&lhs_tree, &lhs_src, &ts_lang);
+                                    let rhs_locals =
+                                        tsp::local_positions(&rhs_tree, &rhs_src, &ts_lang);
+                                    let (lhs_normalized, rhs_normalized) = show_normalized(
+                                        options,
+                                        (&lhs, &lhs_locals, &lhs_src, &mut lhs_positions),
+                                        (&rhs, &rhs_locals, &rhs_src, &mut rhs_positions),
+                                    );
+                                    lhs_src = lhs_normalized;
+                                    rhs_src = rhs_normalized;
                                 }
                             }
 
-                            if exceeded_graph_limit {
-                                let lhs_positions =
-                                    line_parser::change_positions(&lhs_src, &rhs_src);
-                                let rhs_positions =
-                                    line_parser::change_positions(&rhs_src, &lhs_src);
-                                (
-                                    FileFormat::TextFallback {
-                                        reason: "exceeded DFT_GRAPH_LIMIT".into(),
-                                    },
-                                    lhs_positions,
-                                    rhs_positions,
-                                )
+                            let file_format = if degraded.is_empty() {
+                                FileFormat::SupportedLanguage(language)
                             } else {
-                                // TODO: Make this .expect() unnecessary.
-                                let language = language.expect(
-                                    "If we had a ts_lang, we must have guessed the language",
-                                );
-                                fix_all_sliders(language, &lhs, &mut change_map);
-                                fix_all_sliders(language, &rhs, &mut change_map);
-
-                                let mut lhs_positions = syntax::change_positions(&lhs, &change_map);
-                                let mut rhs_positions = syntax::change_positions(&rhs, &change_map);
-
-                                if diff_options.ignore_comments {
-                                    let lhs_comments =
-                                        tsp::comment_positions(&lhs_tree, &lhs_src, &ts_lang);
-                                    lhs_positions.extend(lhs_comments);
-
-                                    let rhs_comments =
-                                        tsp::comment_positions(&rhs_tree, &rhs_src, &ts_lang);
-                                    rhs_positions.extend(rhs_comments);
+                                FileFormat::TextFallback {
+                                    reason: sections::fallback_reason(&degraded, num_sections),
                                 }
-
-                                (
-                                    FileFormat::SupportedLanguage(language),
-                                    lhs_positions,
-                                    rhs_positions,
-                                )
-                            }
+                            };
+                            (file_format, lhs_positions, rhs_positions)
                         }
                         Err(tsp::ExceededParseErrorLimit(error_count)) => {
                             let file_format = FileFormat::TextFallback {
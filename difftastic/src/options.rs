@@ -7,7 +7,12 @@ use const_format::formatcp;
 use crossterm::tty::IsTty;
 
 use crate::{
-    display::style::BackgroundColor, exit_codes::EXIT_BAD_ARGUMENTS, parse::guess_language,
+    diff::{cost_model::CostModel, dijkstra::SearchAlgorithm, sections::FallbackMode},
+    display::style::BackgroundColor,
+    exit_codes::EXIT_BAD_ARGUMENTS,
+    files::read_or_die,
+    feature_vector::path_contexts::DEFAULT_EMBEDDING_DIM,
+    parse::{guess_language, language_map, loaded_grammars, normalize::{NormalizeOptions, SyntaxNormalizations}, policy::PolicyOverrides, tree_sitter_parser as tsp},
 };
 
 pub const DEFAULT_BYTE_LIMIT: usize = 1_000_000;
@@ -60,24 +65,68 @@ impl Default for DisplayOptions {
 #[derive(Debug, Clone)]
 pub struct DiffOptions {
     pub graph_limit: usize,
+    /// If set, use a text diff for sections whose graph search would
+    /// take the searches running in parallel over this many bytes.
+    pub memory_budget: Option<usize>,
     pub byte_limit: usize,
     pub parse_error_limit: usize,
     pub check_only: bool,
     pub ignore_comments: bool,
+    /// If set, compare atoms after renaming locals and bucketing
+    /// literals.
+    pub normalize: Option<NormalizeOptions>,
+    pub search_algorithm: SearchAlgorithm,
+    pub cost_model: CostModel,
+    /// How to diff sections that exceed `graph_limit` or
+    /// `memory_budget`.
+    pub fallback: FallbackMode,
+    /// Changes to the default per-language policies.
+    pub policy_overrides: PolicyOverrides,
+    /// Syntactic differences to ignore, such as trailing commas.
+    pub syntax_normalizations: SyntaxNormalizations,
+    /// Tree-sitter queries for regions to leave out of the diff, and
+    /// the languages they apply to.
+    pub ignore_queries: Vec<(guess_language::Language, String)>,
+    /// Parse the RHS incrementally from the LHS, reusing the
+    /// top-level syntax nodes outside the edited lines.
+    pub incremental_parse: bool,
 }
 
 impl Default for DiffOptions {
     fn default() -> Self {
         Self {
             graph_limit: DEFAULT_GRAPH_LIMIT,
+            memory_budget: None,
             byte_limit: DEFAULT_BYTE_LIMIT,
             parse_error_limit: DEFAULT_PARSE_ERROR_LIMIT,
             check_only: false,
             ignore_comments: false,
+            normalize: None,
+            search_algorithm: SearchAlgorithm::default(),
+            cost_model: CostModel::default(),
+            fallback: FallbackMode::default(),
+            policy_overrides: PolicyOverrides::default(),
+            syntax_normalizations: SyntaxNormalizations::default(),
+            ignore_queries: vec![],
+            incremental_parse: false,
         }
     }
 }
 
+/// Which feature families to extract, in addition to the node kind
+/// vectors.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureOptions {
+    /// Length of the token n-grams written to the vector file, or 0
+    /// if n-gram features are disabled.
+    pub token_ngrams: usize,
+    /// If set, write code2vec path contexts to `PREFIX.c2v` and
+    /// hashed bag-of-paths vectors to `PREFIX.svm`.
+    pub embedding_export: Option<String>,
+    /// The number of buckets in the hashed bag-of-paths vectors.
+    pub embedding_dim: usize,
+}
+
 fn app() -> clap::Command<'static> {
     Command::new("Difftastic")
         .override_usage(USAGE)
@@ -199,6 +248,95 @@ inline: A single column display, closer to traditional diff display.")
                 .env("DFT_IGNORE_COMMENTS")
                 .help("Don't consider comments when diffing.")
         )
+        .arg(
+            Arg::new("incremental-parse").long("incremental-parse")
+                .env("DFT_INCREMENTAL_PARSE")
+                .help("Parse the new file incrementally from the old one, reusing the syntax of the top-level items outside the edited lines. This is faster for large files, but the RHS syntax isn't built by a fresh parse.")
+        )
+        .arg(
+            Arg::new("normalize").long("normalize")
+                .env("DFT_NORMALIZE")
+                .help("Rename local identifiers and replace literals with their type before diffing, so changes that only differ in naming or values are treated as unchanged. Locals are names declared by a pattern or parameter, numbered per top-level item. The display shows the original code unless --show-normalized is given, and the node kinds in the vector file always do.")
+        )
+        .arg(
+            Arg::new("show-normalized").long("show-normalized")
+                .requires("normalize")
+                .env("DFT_SHOW_NORMALIZED")
+                .help("Show the renamed locals and literal placeholders from --normalize in the diff display, instead of the original code.")
+        )
+        .arg(
+            Arg::new("keep-names").long("keep-names")
+                .takes_value(true)
+                .value_name("NAMES")
+                .env("DFT_KEEP_NAMES")
+                .help("Comma-separated identifiers that --normalize should never rename, in addition to well-known API names such as unwrap, clone and lock.")
+        )
+        .arg(
+            Arg::new("normalize-syntax").long("normalize-syntax")
+                .takes_value(true)
+                .value_name("KINDS")
+                .use_value_delimiter(true)
+                .possible_values(["trailing-separators", "import-order", "redundant-parens", "all"])
+                .env("DFT_NORMALIZE_SYNTAX")
+                .help("Comma-separated syntactic differences to ignore: trailing commas in lists, the order of imports, and parentheses that don't change the meaning of an expression. Ignored tokens are neither highlighted nor written to the vector file.")
+        )
+        .arg(
+            Arg::new("ignore-query").long("ignore-query")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["LANGUAGE", "QUERY"])
+                .multiple_occurrences(true)
+                .help("Leave the nodes that the tree-sitter QUERY captures with @ignore out of the diff for LANGUAGE files, and for LANGUAGE code embedded in other files such as CSS in HTML, e.g. --ignore-query Rust '(attribute_item) @ignore'. Ignored regions are shown like ignored comments and don't produce vector rows. Can be given several times.")
+        )
+        .arg(
+            Arg::new("ignore-query-file").long("ignore-query-file")
+                .takes_value(true)
+                .number_of_values(2)
+                .value_names(&["LANGUAGE", "PATH"])
+                .multiple_occurrences(true)
+                .help("Like --ignore-query, but read the query from PATH.")
+        )
+        .arg(
+            Arg::new("token-ngrams").long("token-ngrams")
+                .takes_value(true)
+                .value_name("N")
+                .help("Also write n-grams of N novel tokens per hunk to the vector file, as TokenNgram rows. 0 disables this feature family.")
+                .default_value("0")
+                .env("DFT_TOKEN_NGRAMS")
+                .validator(|s| s.parse::<usize>())
+                .required(false),
+        )
+        .arg(
+            Arg::new("embedding-export").long("embedding-export")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .env("DFT_EMBEDDING_EXPORT")
+                .help("Append code2vec path contexts for each hunk to PREFIX.c2v, and hashed bag-of-paths vectors in svmlight format to PREFIX.svm.")
+        )
+        .arg(
+            Arg::new("embedding-dim").long("embedding-dim")
+                .takes_value(true)
+                .value_name("DIM")
+                .help("The number of dimensions of the hashed bag-of-paths vectors written by --embedding-export.")
+                .default_value(formatcp!("{}", DEFAULT_EMBEDDING_DIM))
+                .env("DFT_EMBEDDING_DIM")
+                .validator(|s| s.parse::<usize>())
+                .required(false),
+        )
+        .arg(
+            Arg::new("stats").long("stats")
+                .takes_value(true)
+                .value_name("PATH")
+                .env("DFT_STATS")
+                .help("Append a CSV row of diff statistics for each file to PATH: parse times, node counts, sections, vertices explored, route cost, edges of each kind, slider fixes and total time. Use - to write to stderr.")
+        )
+        .arg(
+            Arg::new("progress").long("progress")
+                .takes_value(true)
+                .value_name("PATH")
+                .env("DFT_PROGRESS")
+                .help("Append mining progress events to PATH as CSV rows (repo, commit_hash, path, event, hunk, vectors, detail): one per hunk, one if sections fell back to a line diff, and one when the file is done. Use - to write to stderr.")
+        )
         .arg(
             Arg::new("skip-unchanged").long("skip-unchanged")
                 .help("Don't display anything if a file is unchanged.")
@@ -214,6 +352,50 @@ inline: A single column display, closer to traditional diff display.")
                 .help("Override language detection. Inputs are assumed to have this file extension. When diffing directories, applies to all files.")
                 // TODO: support DFT_LANGUAGE for consistency
         )
+        .arg(
+            Arg::new("language-map").long("language-map")
+                .takes_value(true)
+                .value_name("GLOB:LANGUAGE")
+                .multiple_occurrences(true)
+                .use_value_delimiter(true)
+                .env("DFT_LANGUAGE_MAP")
+                .help("Treat files matching GLOB as LANGUAGE, e.g. --language-map 'scripts/*:Python'. A glob without a / matches file names in any directory. Can be given several times, or as a comma-separated list, and later mappings take precedence. Otherwise the language is guessed from .gitattributes (linguist-language), Emacs and Vim modelines, shebangs, file names and extensions, in that order.")
+        )
+        .arg(
+            Arg::new("grammar-dir").long("grammar-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .env("DFT_GRAMMAR_DIR")
+                .allow_invalid_utf8(true)
+                .help("Load additional tree-sitter grammars from shared libraries in this directory, as described by DIR/grammars.conf. Each [name] section loads tree_sitter_<name> from <name>.so and highlight queries from <name>.scm, and binds the grammar to its extensions and file_names.")
+        )
+        .arg(
+            Arg::new("view-clusters").long("view-clusters")
+                .takes_value(true)
+                .value_name("HAC_FILE")
+                .help("Browse the clusters in a HAC results file (as written by cluster_vectors.py) in a terminal UI, showing the diff of each member.")
+        )
+        .arg(
+            Arg::new("cluster-code-dir").long("cluster-code-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .requires("view-clusters")
+                .help("The directory containing REPO/COMMIT/NAME/NAME_before.rs files for --view-clusters. Defaults to file-code-merged next to HAC_FILE.")
+        )
+        .arg(
+            Arg::new("html-report").long("html-report")
+                .takes_value(true)
+                .value_name("OUT_DIR")
+                .requires("view-clusters")
+                .help("Instead of opening the terminal UI, write a static HTML report of the clusters in --view-clusters to OUT_DIR.")
+        )
+        .arg(
+            Arg::new("cluster-vectors").long("cluster-vectors")
+                .takes_value(true)
+                .value_name("VECTOR_FILE")
+                .requires("html-report")
+                .help("The vector file written when mining, used to show a feature histogram for each cluster in --html-report.")
+        )
         .arg(
             Arg::new("list-languages").long("list-languages")
                 .help("Print the all the languages supported by difftastic, along with their extensions.")
@@ -238,6 +420,68 @@ inline: A single column display, closer to traditional diff display.")
                 .validator(|s| s.parse::<usize>())
                 .required(false),
         )
+        .arg(
+            Arg::new("memory-budget").long("memory-budget")
+                .takes_value(true)
+                .value_name("SIZE")
+                .help("Use a text diff for a section if the structural graph would use more than this much memory. Accepts bytes, or a K, M or G suffix, e.g. 512M. Unlimited by default.
+
+Sections and files are diffed in parallel, and the budget applies to all of them together.")
+                .env("DFT_MEMORY_BUDGET")
+                .validator(parse_byte_size)
+                .required(false),
+        )
+        .arg(
+            Arg::new("search").long("search")
+                .possible_values(["dijkstra", "astar"])
+                .default_value("dijkstra")
+                .value_name("ALGORITHM")
+                .env("DFT_SEARCH")
+                .help("How to search for the best structural diff.
+
+dijkstra: Explore the diff graph in order of cost so far.
+
+astar: Also estimate the remaining cost from the number of nodes left on each side. When lots of code is added or removed, this explores less of the graph, so larger files fit in DFT_GRAPH_LIMIT. The resulting diff may be less minimal.")
+        )
+        .arg(
+            Arg::new("fallback").long("fallback")
+                .possible_values(["line", "myers"])
+                .default_value("line")
+                .value_name("MODE")
+                .env("DFT_FALLBACK")
+                .help("How to diff a section that exceeds DFT_GRAPH_LIMIT or DFT_MEMORY_BUDGET.
+
+line: Use a line diff for the lines of the section.
+
+myers: Match top-level syntax nodes with identical content using Myers' diff, then recurse into changed lists with the same delimiters. This is coarser than the structural diff, but still syntax aware.")
+        )
+        .arg(
+            Arg::new("cost-model").long("cost-model")
+                .takes_value(true)
+                .value_name("PATH")
+                .env("DFT_COST_MODEL")
+                .allow_invalid_utf8(true)
+                .help("Read the costs of structural diff edges from this file. Each line is NAME = VALUE, and costs not listed keep their defaults.
+
+Costs: unchanged_max_depth_penalty (40), enter_unchanged_delimiter (100), replaced_comment (150), replaced_atom (250), replaced_atom_min_similarity (50, a percentage), novel_atom (300), novel_delimiter (300), not_contiguous_penalty (50), punctuation_discount (10).")
+        )
+        .arg(
+            Arg::new("language-policy").long("language-policy")
+                .takes_value(true)
+                .value_name("PATH")
+                .env("DFT_LANGUAGE_POLICY")
+                .allow_invalid_utf8(true)
+                .help("Read per-language settings from this file. Each section starts with [Language], followed by NAME = VALUES lines. Use += to add to the default values rather than replace them.
+
+Settings: atom_nodes, delimiter_tokens (pairs of open and close tokens), comment_kinds, container_kinds (the ancestors recorded as the context of a change in the vector file), prefer_outer_delimiter (true or false), doc_comment_prefixes (doc comments whose code blocks are parsed as the language), import_kinds, import_list_kinds and parenthesized_kinds (used by --normalize-syntax), and binding_kinds (where identifiers declare the locals that --normalize renames, as KIND or KIND.FIELD).")
+        )
+        .arg(
+            Arg::new("cost").long("cost")
+                .takes_value(true)
+                .value_name("NAME=VALUE")
+                .multiple_occurrences(true)
+                .help("Override a single cost, after reading --cost-model. For example, --cost novel_delimiter=500 makes difftastic less willing to split a call expression.")
+        )
         .arg(
             Arg::new("parse-error-limit").long("parse-error-limit")
                 .takes_value(true)
@@ -343,6 +587,32 @@ pub enum Mode {
         repo_name: String,
         commit_hash: String,
         vector_file: String,
+        feature_options: FeatureOptions,
+        /// If set, append per-file statistics to this CSV file.
+        stats_file: Option<String>,
+        /// If set, append mining progress events to this CSV file.
+        progress_file: Option<String>,
+    },
+    ViewClusters {
+        diff_options: DiffOptions,
+        display_options: DisplayOptions,
+        language_override: Option<guess_language::Language>,
+        /// The HAC results file listing the members of each cluster.
+        hac_path: PathBuf,
+        /// The directory containing the before/after files of each
+        /// member.
+        code_dir: PathBuf,
+    },
+    ClusterReport {
+        diff_options: DiffOptions,
+        display_options: DisplayOptions,
+        language_override: Option<guess_language::Language>,
+        hac_path: PathBuf,
+        code_dir: PathBuf,
+        /// The directory to write the HTML pages to.
+        out_dir: PathBuf,
+        /// The vector file to compute feature histograms from.
+        vector_path: Option<PathBuf>,
     },
     ListLanguages {
         use_color: bool,
@@ -362,6 +632,22 @@ pub enum Mode {
 pub fn parse_args() -> Mode {
     let matches = app().get_matches();
 
+    // Load grammars first, so --language can refer to their
+    // extensions.
+    if let Some(dir) = matches.value_of_os("grammar-dir") {
+        if let Err(e) = loaded_grammars::load_dir(Path::new(dir)) {
+            eprintln!("error: {}", e);
+            std::process::exit(EXIT_BAD_ARGUMENTS);
+        }
+    }
+
+    for mapping in matches.values_of("language-map").into_iter().flatten() {
+        if let Err(e) = language_map::add(mapping) {
+            eprintln!("error: --language-map: {}", e);
+            std::process::exit(EXIT_BAD_ARGUMENTS);
+        }
+    }
+
     let language_override = match matches.value_of_os("language") {
         Some(lang_str) => {
             if let Some(lang) = guess_language::from_extension(lang_str) {
@@ -411,11 +697,20 @@ pub fn parse_args() -> Mode {
     let args: Vec<_> = matches.values_of_os("paths").unwrap_or_default().collect();
     info!("CLI arguments: {:?}", args);
 
+    let view_clusters = matches.value_of("view-clusters").map(PathBuf::from);
+
     let mut repo_name= String::new();
     let mut commit_hash = String::new();
     let mut vector_file = String::new();
     // TODO: document these different ways of calling difftastic.
     let (lhs_display_path, rhs_display_path, lhs_path, rhs_path, in_vcs) = match &args[..] {
+        [] if view_clusters.is_some() => (
+            String::new(),
+            String::new(),
+            FileArgument::DevNull,
+            FileArgument::DevNull,
+            false,
+        ),
         [lhs_path, rhs_path] => {
             let lhs_arg = FileArgument::from_cli_argument(lhs_path);
             let rhs_arg = FileArgument::from_cli_argument(rhs_path);
@@ -524,6 +819,98 @@ pub fn parse_args() -> Mode {
         .parse::<usize>()
         .expect("Value already validated by clap");
 
+    let memory_budget = matches
+        .value_of("memory-budget")
+        .map(|s| parse_byte_size(s).expect("Value already validated by clap"));
+
+    let search_algorithm = match matches
+        .value_of("search")
+        .expect("Always present as we've given clap a default")
+    {
+        "dijkstra" => SearchAlgorithm::Dijkstra,
+        "astar" => SearchAlgorithm::AStar,
+        _ => unreachable!("clap has already validated the values"),
+    };
+
+    let fallback = match matches
+        .value_of("fallback")
+        .expect("Always present as we've given clap a default")
+    {
+        "line" => FallbackMode::Line,
+        "myers" => FallbackMode::Myers,
+        _ => unreachable!("clap has already validated the values"),
+    };
+
+    let mut cost_model = match matches.value_of_os("cost-model") {
+        Some(path) => {
+            let bytes = read_or_die(Path::new(path));
+            let src = String::from_utf8_lossy(&bytes);
+            match CostModel::from_file_content(&src) {
+                Ok(cost_model) => cost_model,
+                Err(e) => {
+                    eprintln!("error: {}: {}", Path::new(path).display(), e);
+                    std::process::exit(EXIT_BAD_ARGUMENTS);
+                }
+            }
+        }
+        None => CostModel::default(),
+    };
+    for assignment in matches.values_of("cost").into_iter().flatten() {
+        if let Err(e) = cost_model.set_from_str(assignment) {
+            eprintln!("error: --cost: {}", e);
+            std::process::exit(EXIT_BAD_ARGUMENTS);
+        }
+    }
+
+    let policy_overrides = match matches.value_of_os("language-policy") {
+        Some(path) => {
+            let bytes = read_or_die(Path::new(path));
+            let src = String::from_utf8_lossy(&bytes);
+            match PolicyOverrides::from_file_content(&src) {
+                Ok(policy_overrides) => policy_overrides,
+                Err(e) => {
+                    eprintln!("error: {}: {}", Path::new(path).display(), e);
+                    std::process::exit(EXIT_BAD_ARGUMENTS);
+                }
+            }
+        }
+        None => PolicyOverrides::default(),
+    };
+
+    let mut ignore_queries = vec![];
+    let query_args = matches
+        .values_of("ignore-query")
+        .into_iter()
+        .flatten()
+        .map(|value| ("--ignore-query", value));
+    let query_file_args = matches
+        .values_of("ignore-query-file")
+        .into_iter()
+        .flatten()
+        .map(|value| ("--ignore-query-file", value));
+    let query_args: Vec<_> = query_args.chain(query_file_args).collect();
+    for pair in query_args.chunks(2) {
+        let (flag, name) = pair[0];
+        let (_, value) = pair[1];
+        let language = match guess_language::from_language_name(name) {
+            Some(language) => language,
+            None => {
+                eprintln!("error: {}: Unknown language: {}", flag, name);
+                std::process::exit(EXIT_BAD_ARGUMENTS);
+            }
+        };
+        let query = if flag == "--ignore-query-file" {
+            String::from_utf8_lossy(&read_or_die(Path::new(value))).to_string()
+        } else {
+            value.to_owned()
+        };
+        if let Err(e) = tsp::check_ignore_query(language, &query) {
+            eprintln!("error: {} {}: {}", flag, name, e);
+            std::process::exit(EXIT_BAD_ARGUMENTS);
+        }
+        ignore_queries.push((language, query));
+    }
+
     let byte_limit = matches
         .value_of("byte-limit")
         .expect("Always present as we've given clap a default")
@@ -548,12 +935,58 @@ pub fn parse_args() -> Mode {
         .parse::<u32>()
         .expect("Value already validated by clap");
 
+    let token_ngrams = matches
+        .value_of("token-ngrams")
+        .expect("Always present as we've given clap a default")
+        .parse::<usize>()
+        .expect("Value already validated by clap");
+
+    let embedding_dim = matches
+        .value_of("embedding-dim")
+        .expect("Always present as we've given clap a default")
+        .parse::<usize>()
+        .expect("Value already validated by clap");
+
+    let feature_options = FeatureOptions {
+        token_ngrams,
+        embedding_export: matches.value_of("embedding-export").map(|s| s.to_owned()),
+        embedding_dim,
+    };
+
     let print_unchanged = !matches.is_present("skip-unchanged");
 
     let set_exit_code = matches.is_present("exit-code");
 
     let check_only = matches.is_present("check-only");
 
+    let normalize = if matches.is_present("normalize") {
+        let mut normalize_options = NormalizeOptions::default();
+        if let Some(keep_names) = matches.value_of("keep-names") {
+            normalize_options.keep_names.extend(
+                keep_names
+                    .split(',')
+                    .map(|name| name.trim())
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_owned()),
+            );
+        }
+        normalize_options.show_in_display = matches.is_present("show-normalized");
+        Some(normalize_options)
+    } else {
+        None
+    };
+
+    let mut syntax_normalizations = SyntaxNormalizations::default();
+    for kind in matches.values_of("normalize-syntax").into_iter().flatten() {
+        match kind {
+            "trailing-separators" => syntax_normalizations.trailing_separators = true,
+            "import-order" => syntax_normalizations.import_order = true,
+            "redundant-parens" => syntax_normalizations.redundant_parens = true,
+            "all" => syntax_normalizations = SyntaxNormalizations::all(),
+            _ => unreachable!("clap has already validated the values"),
+        }
+    }
+
     let display_options = DisplayOptions {
         background_color,
         use_color,
@@ -568,12 +1001,51 @@ pub fn parse_args() -> Mode {
 
     let diff_options = DiffOptions {
         graph_limit,
+        memory_budget,
         byte_limit,
         parse_error_limit,
         check_only,
         ignore_comments,
+        normalize,
+        search_algorithm,
+        cost_model,
+        fallback,
+        policy_overrides,
+        syntax_normalizations,
+        ignore_queries,
+        incremental_parse: matches.is_present("incremental-parse"),
     };
 
+    if let Some(hac_path) = view_clusters {
+        let code_dir = match matches.value_of("cluster-code-dir") {
+            Some(code_dir) => PathBuf::from(code_dir),
+            None => hac_path
+                .parent()
+                .unwrap_or_else(|| Path::new("."))
+                .join("file-code-merged"),
+        };
+
+        if let Some(out_dir) = matches.value_of("html-report") {
+            return Mode::ClusterReport {
+                diff_options,
+                display_options,
+                language_override,
+                hac_path,
+                code_dir,
+                out_dir: PathBuf::from(out_dir),
+                vector_path: matches.value_of("cluster-vectors").map(PathBuf::from),
+            };
+        }
+
+        return Mode::ViewClusters {
+            diff_options,
+            display_options,
+            language_override,
+            hac_path,
+            code_dir,
+        };
+    }
+
     Mode::Diff {
         diff_options,
         display_options,
@@ -586,9 +1058,31 @@ pub fn parse_args() -> Mode {
         repo_name,
         commit_hash,
         vector_file,
+        feature_options,
+        stats_file: matches.value_of("stats").map(|s| s.to_owned()),
+        progress_file: matches.value_of("progress").map(|s| s.to_owned()),
     }
 }
 
+/// Parse a size in bytes, with an optional K, M or G suffix (powers
+/// of 1024).
+fn parse_byte_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    digits
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Expected a size such as 4096, 512M or 2G, got: {}", s))
+}
+
 /// Choose the display width: try to autodetect, or fall back to a
 /// sensible default.
 fn detect_display_width() -> usize {
@@ -621,6 +1115,14 @@ mod tests {
         app().debug_assert();
     }
 
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!(parse_byte_size("4096"), Ok(4096));
+        assert_eq!(parse_byte_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_byte_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_byte_size("lots").is_err());
+    }
+
     #[test]
     fn test_detect_display_width() {
         // Basic smoke test.
@@ -14,9 +14,16 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use std::{borrow::Borrow, ffi::OsStr, path::Path};
 
+use crate::parse::{
+    language_map::{self, LanguageMap},
+    loaded_grammars,
+};
+
 /// Languages supported by difftastic. Each language here has a
-/// corresponding tree-sitter parser.
+/// corresponding tree-sitter parser, although it may have been left
+/// out of this build (see `is_compiled_in`).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "all-languages"), allow(dead_code))]
 pub enum Language {
     Ada,
     Bash,
@@ -70,11 +77,14 @@ pub enum Language {
     TypeScript,
     Yaml,
     Zig,
+    /// A grammar loaded at runtime, by its ID in `loaded_grammars`.
+    Loaded(usize),
 }
 
 /// The language name shown to the user.
 pub fn language_name(language: Language) -> &'static str {
     match language {
+        Loaded(id) => loaded_grammars::get(id).name,
         Ada => "Ada",
         Bash => "Bash",
         C => "C",
@@ -134,7 +144,9 @@ use Language::*;
 
 /// Which file extensions are associated with which languages.
 pub const LANG_EXTENSIONS: &[(Language, &[&str])] = &[
+    #[cfg(feature = "lang-ada")]
     (Ada, &["ada", "adb", "ads"]),
+    #[cfg(feature = "lang-bash")]
     (
         Bash,
         &[
@@ -142,42 +154,66 @@ pub const LANG_EXTENSIONS: &[(Language, &[&str])] = &[
             "zsh",
         ],
     ),
+    #[cfg(feature = "lang-c")]
     (C, &["c"]),
+    #[cfg(feature = "lang-clojure")]
     (
         Clojure,
         &[
             "bb", "boot", "clj", "cljc", "clje", "cljs", "cljx", "edn", "joke", "joker",
         ],
     ),
+    #[cfg(feature = "lang-cmake")]
     (CMake, &["cmake", "cmake.in"]),
+    #[cfg(feature = "lang-commonlisp")]
     (CommonLisp, &["lisp", "lsp", "asd"]),
     // Treat .h as C++ rather than C. This is an arbitrary choice, but
     // C++ is more widely used than C according to
     // https://madnight.github.io/githut/
+    #[cfg(feature = "lang-cpp")]
     (CPlusPlus, &["cc", "cpp", "h", "hh", "hpp", "ino", "cxx"]),
+    #[cfg(feature = "lang-c-sharp")]
     (CSharp, &["cs"]),
+    #[cfg(feature = "lang-css")]
     (Css, &["css"]),
+    #[cfg(feature = "lang-dart")]
     (Dart, &["dart"]),
+    #[cfg(feature = "lang-elm")]
     (Elm, &["elm"]),
+    #[cfg(feature = "lang-elisp")]
     (EmacsLisp, &["el"]),
+    #[cfg(feature = "lang-elixir")]
     (Elixir, &["ex", "exs"]),
+    #[cfg(feature = "lang-elvish")]
     (Elvish, &["elv"]),
     // TODO: confirm that we support multiple extensions for files
     // like foo.app.src.
+    #[cfg(feature = "lang-erlang")]
     (
         Erlang,
         &["erl", "app.src", "es", "escript", "hrl", "xrl", "yrl"],
     ),
+    #[cfg(feature = "lang-gleam")]
     (Gleam, &["gleam"]),
+    #[cfg(feature = "lang-go")]
     (Go, &["go"]),
+    #[cfg(feature = "lang-hack")]
     (Hack, &["hack", "hck", "hhi"]),
+    #[cfg(feature = "lang-hare")]
     (Hare, &["ha"]),
+    #[cfg(feature = "lang-haskell")]
     (Haskell, &["hs"]),
+    #[cfg(feature = "lang-hcl")]
     (Hcl, &["hcl", "nomad", "tf", "tfvars", "workflow"]),
+    #[cfg(feature = "lang-html")]
     (Html, &["html", "htm", "xhtml"]),
+    #[cfg(feature = "lang-janet")]
     (Janet, &["janet", "jdn"]),
+    #[cfg(feature = "lang-java")]
     (Java, &["java"]),
+    #[cfg(feature = "lang-javascript")]
     (JavaScript, &["cjs", "js", "mjs"]),
+    #[cfg(feature = "lang-json")]
     (
         Json,
         &[
@@ -197,36 +233,63 @@ pub const LANG_EXTENSIONS: &[(Language, &[&str])] = &[
             "webmanifest",
         ],
     ),
+    #[cfg(feature = "lang-javascript")]
     (Jsx, &["jsx"]),
+    #[cfg(feature = "lang-julia")]
     (Julia, &["jl"]),
+    #[cfg(feature = "lang-kotlin")]
     (Kotlin, &["kt", "ktm", "kts"]),
+    #[cfg(feature = "lang-lua")]
     (Lua, &["lua"]),
+    #[cfg(feature = "lang-make")]
     (Make, &["mak", "d", "make", "makefile", "mk", "mkfile"]),
+    #[cfg(feature = "lang-newick")]
     (Newick, &["nhx", "nwk", "nh"]),
+    #[cfg(feature = "lang-nix")]
     (Nix, &["nix"]),
+    #[cfg(feature = "lang-ocaml")]
     (OCaml, &["ml"]),
+    #[cfg(feature = "lang-ocaml")]
     (OCamlInterface, &["mli"]),
+    #[cfg(feature = "lang-pascal")]
     (Pascal, &["pas", "dfm", "dpr", "lpr", "pascal"]),
+    #[cfg(feature = "lang-perl")]
     (Perl, &["pm", "pl"]),
+    #[cfg(feature = "lang-php")]
     (Php, &["php"]),
+    #[cfg(feature = "lang-python")]
     (Python, &["py", "py3", "pyi", "bzl"]),
+    #[cfg(feature = "lang-qml")]
     (Qml, &["qml"]),
+    #[cfg(feature = "lang-r")]
     (R, &["R", "r", "rd", "rsx"]),
+    #[cfg(feature = "lang-racket")]
     (Racket, &["rkt"]),
+    #[cfg(feature = "lang-ruby")]
     (Ruby, &["rb", "builder", "spec", "rake"]),
+    #[cfg(feature = "lang-rust")]
     (Rust, &["rs"]),
+    #[cfg(feature = "lang-scala")]
     (Scala, &["scala", "sbt", "sc"]),
+    #[cfg(feature = "lang-sql")]
     (Sql, &["sql", "pgsql"]),
+    #[cfg(feature = "lang-swift")]
     (Swift, &["swift"]),
+    #[cfg(feature = "lang-toml")]
     (Toml, &["toml"]),
+    #[cfg(feature = "lang-typescript")]
     (TypeScript, &["ts"]),
+    #[cfg(feature = "lang-typescript")]
     (Tsx, &["tsx"]),
+    #[cfg(feature = "lang-yaml")]
     (Yaml, &["yaml", "yml"]),
+    #[cfg(feature = "lang-zig")]
     (Zig, &["zig"]),
 ];
 
 /// Which file names are associated with which languages.
 pub const LANG_FILE_NAMES: &[(Language, &[&str])] = &[
+    #[cfg(feature = "lang-bash")]
     (
         Bash,
         &[
@@ -266,9 +329,13 @@ pub const LANG_FILE_NAMES: &[(Language, &[&str])] = &[
             "zshrc",
         ],
     ),
+    #[cfg(feature = "lang-cmake")]
     (CMake, &["CMakeLists.txt"]),
+    #[cfg(feature = "lang-elisp")]
     (EmacsLisp, &[".emacs", "_emacs", "Cask"]),
+    #[cfg(feature = "lang-erlang")]
     (Erlang, &["Emakefile"]),
+    #[cfg(feature = "lang-json")]
     (
         Json,
         &[
@@ -286,6 +353,7 @@ pub const LANG_FILE_NAMES: &[(Language, &[&str])] = &[
             "mcmod.info",
         ],
     ),
+    #[cfg(feature = "lang-make")]
     (
         Make,
         &[
@@ -304,20 +372,70 @@ pub const LANG_FILE_NAMES: &[(Language, &[&str])] = &[
             "mkfile",
         ],
     ),
+    #[cfg(feature = "lang-python")]
     (Python, &["TARGETS", "BUCK", "DEPS"]),
+    #[cfg(feature = "lang-r")]
     (R, &[".Rprofile", "expr-dist"]),
+    #[cfg(feature = "lang-ruby")]
     (Ruby, &["Gemfile", "Rakefile"]),
+    #[cfg(feature = "lang-toml")]
     (
         Toml,
         &["Cargo.lock", "Gopkg.lock", "Pipfile", "poetry.lock"],
     ),
 ];
 
+/// Is there a parser for `language` in this build? Vendored parsers
+/// can be left out with cargo features, which also removes their
+/// entries from `LANG_EXTENSIONS`.
+pub fn is_compiled_in(language: Language) -> bool {
+    match language {
+        Loaded(_) => true,
+        _ => LANG_EXTENSIONS.iter().any(|(lang, _)| *lang == language),
+    }
+}
+
 pub fn guess(path: &Path, src: &str) -> Option<Language> {
-    if let Some(lang) = from_emacs_mode_header(src) {
+    guess_with(path, src, &language_map::LANGUAGE_MAP.read().unwrap(), true)
+}
+
+/// Like `guess`, for input that isn't a file on disk, such as stdin.
+/// `path` is only a name, so `.gitattributes` files aren't read.
+pub fn guess_unnamed(path: &Path, src: &str) -> Option<Language> {
+    guess_with(
+        path,
+        src,
+        &language_map::LANGUAGE_MAP.read().unwrap(),
+        false,
+    )
+}
+
+fn guess_with(
+    path: &Path,
+    src: &str,
+    language_map: &LanguageMap,
+    read_gitattributes: bool,
+) -> Option<Language> {
+    if let Some(lang) = language_map
+        .language(path)
+        .filter(|lang| is_compiled_in(*lang))
+    {
+        return Some(lang);
+    }
+    if read_gitattributes {
+        if let Some(lang) =
+            language_map::from_gitattributes(path).filter(|lang| is_compiled_in(*lang))
+        {
+            return Some(lang);
+        }
+    }
+    if let Some(lang) = from_emacs_mode_header(src).filter(|lang| is_compiled_in(*lang)) {
+        return Some(lang);
+    }
+    if let Some(lang) = from_vim_modeline(src).filter(|lang| is_compiled_in(*lang)) {
         return Some(lang);
     }
-    if let Some(lang) = from_shebang(src) {
+    if let Some(lang) = from_shebang(src).filter(|lang| is_compiled_in(*lang)) {
         return Some(lang);
     }
     if let Some(lang) = from_name(path) {
@@ -397,6 +515,38 @@ fn from_emacs_mode_header(src: &str) -> Option<Language> {
     None
 }
 
+/// Try to guess the language based on a Vim modeline, such as
+/// `# vim: set ft=python:`, in the first or last five lines.
+///
+/// <https://vimhelp.org/options.txt.html#modeline>
+fn from_vim_modeline(src: &str) -> Option<Language> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?:^|\s)(?:vi|vim|Vim|ex)(?:[<=>]?\d+)?:(.*)").unwrap();
+    }
+
+    // Vim checks five lines at each end of the file by default.
+    let lines: Vec<&str> = src.lines().collect();
+    let last_lines = lines.len().saturating_sub(5).max(5);
+    for line in lines.iter().take(5).chain(lines.iter().skip(last_lines)) {
+        let options = match RE.captures(line) {
+            Some(cap) => cap[1].to_owned(),
+            None => continue,
+        };
+        for option in options.split(|c: char| c == ':' || c.is_whitespace()) {
+            let filetype = match option.split_once('=') {
+                Some(("ft" | "filetype" | "syn" | "syntax", filetype)) => filetype,
+                _ => continue,
+            };
+            // A filetype can be compound, such as `htmldjango.html`.
+            if let Some(lang) = filetype.split('.').find_map(from_language_alias) {
+                return Some(lang);
+            }
+        }
+    }
+
+    None
+}
+
 /// Try to guess the language based on a shebang present in the source.
 fn from_shebang(src: &str) -> Option<Language> {
     lazy_static! {
@@ -444,6 +594,9 @@ fn from_name(path: &Path) -> Option<Language> {
     match path.file_name() {
         Some(name) => {
             let name = name.to_string_lossy().into_owned();
+            if let Some(id) = loaded_grammars::find(|grammar| grammar.file_names.contains(&name)) {
+                return Some(Loaded(id));
+            }
             for (language, known_file_names) in LANG_FILE_NAMES {
                 for known_file_name in *known_file_names {
                     if &name == known_file_name {
@@ -458,8 +611,54 @@ fn from_name(path: &Path) -> Option<Language> {
     }
 }
 
+/// The language with this name, as shown by `language_name`. Case is
+/// ignored.
+pub fn from_language_name(name: &str) -> Option<Language> {
+    if let Some(id) = loaded_grammars::find(|grammar| grammar.name.eq_ignore_ascii_case(name)) {
+        return Some(Loaded(id));
+    }
+    LANG_EXTENSIONS
+        .iter()
+        .chain(LANG_FILE_NAMES)
+        .map(|(language, _)| *language)
+        .find(|language| language_name(*language).eq_ignore_ascii_case(name))
+}
+
+/// The language with this name, or a common alternative name such as
+/// Vim's `sh` or `cpp`, or GitHub Linguist's `Shell`. Case is ignored.
+pub fn from_language_alias(name: &str) -> Option<Language> {
+    if let Some(lang) = from_language_name(name) {
+        return Some(lang);
+    }
+    match name.to_ascii_lowercase().borrow() {
+        "sh" | "zsh" | "shell" => Some(Bash),
+        "cpp" => Some(CPlusPlus),
+        "cs" | "csharp" => Some(CSharp),
+        "elisp" | "emacs-lisp" => Some(EmacsLisp),
+        "golang" => Some(Go),
+        "javascriptreact" | "jsx" => Some(Jsx),
+        "js" => Some(JavaScript),
+        "lisp" | "common-lisp" => Some(CommonLisp),
+        "makefile" => Some(Make),
+        "py" => Some(Python),
+        "terraform" => Some(Hcl),
+        "ts" => Some(TypeScript),
+        "tsx" | "typescriptreact" => Some(Tsx),
+        "yml" => Some(Yaml),
+        _ => None,
+    }
+}
+
 pub fn from_extension(current_extension: &OsStr) -> Option<Language> {
     let current_extension = current_extension.to_string_lossy();
+    if let Some(id) = loaded_grammars::find(|grammar| {
+        grammar
+            .extensions
+            .iter()
+            .any(|extension| *extension == current_extension)
+    }) {
+        return Some(Loaded(id));
+    }
 
     for (language, extensions) in LANG_EXTENSIONS {
         for extension in *extensions {
@@ -530,9 +729,48 @@ mod tests {
         assert_eq!(guess(path, "# -*-python-*-"), Some(Python));
     }
 
+    #[test]
+    fn test_guess_by_vim_modeline() {
+        let path = Path::new("foo");
+        assert_eq!(
+            guess(path, "#!/bin/sh\n# vim: set ft=python:"),
+            Some(Python)
+        );
+        assert_eq!(
+            guess(path, "x\n\n\n\n\n\n/* vim:ft=cpp */"),
+            Some(CPlusPlus)
+        );
+        assert_eq!(guess(path, "# vim: set ts=4 sw=4 :"), None);
+    }
+
+    #[test]
+    fn test_guess_by_language_map() {
+        let mut language_map = LanguageMap::default();
+        language_map.add("*.dfttest:Rust").unwrap();
+        assert_eq!(
+            guess_with(Path::new("src/foo.dfttest"), "", &language_map, false),
+            Some(Rust)
+        );
+    }
+
+    #[test]
+    fn test_from_language_alias() {
+        assert_eq!(from_language_alias("Shell"), Some(Bash));
+        assert_eq!(from_language_alias("emacs lisp"), Some(EmacsLisp));
+        assert_eq!(from_language_alias("typescriptreact"), Some(Tsx));
+        assert_eq!(from_language_alias("klingon"), None);
+    }
+
     #[test]
     fn test_guess_unknown() {
         let path = Path::new("jfkdlsjfkdsljfkdsljf");
         assert_eq!(guess(path, ""), None);
     }
+
+    #[test]
+    fn test_compiled_in() {
+        // Every language is compiled in with the default features.
+        assert!(is_compiled_in(Rust));
+        assert!(is_compiled_in(OCamlInterface));
+    }
 }
@@ -0,0 +1,474 @@
+//! Incremental parsing of successive versions of a file.
+//!
+//! When walking a commit chain, each version of a file is the RHS of
+//! one diff and the LHS of the next, and consecutive versions are
+//! mostly identical. A `ParseSession` keeps the previous version's
+//! tree-sitter tree and syntax nodes, so the next version is parsed
+//! incrementally: the previous tree is edited with `InputEdit`s from
+//! a line diff, and top-level nodes outside the edited and changed
+//! ranges are copied from the previous syntax nodes rather than
+//! converted again.
+
+use rustc_hash::FxHashMap;
+use tree_sitter as ts;
+use typed_arena::Arena;
+
+use crate::{
+    diff::myers_diff,
+    parse::{
+        syntax::Syntax,
+        tree_sitter_parser::{
+            ignored_nodes, parse_subtrees, to_syntax_with_reuse, toplevel_nodes, SubTrees,
+            TreeSitterConfig,
+        },
+    },
+    positions::SingleLineSpan,
+};
+
+/// The position after `text`, if it starts at `start`.
+fn advance(start: ts::Point, text: &str) -> ts::Point {
+    match text.rfind('\n') {
+        Some(last_newline) => ts::Point {
+            row: start.row + text.matches('\n').count(),
+            column: text.len() - last_newline - 1,
+        },
+        None => ts::Point {
+            row: start.row,
+            column: start.column + text.len(),
+        },
+    }
+}
+
+/// The edits that turn `old_src` into `new_src`, from a line diff.
+///
+/// Each edit is relative to the text after the previous edits have
+/// been applied, which is the order `Tree::edit` expects.
+pub fn input_edits(old_src: &str, new_src: &str) -> Vec<ts::InputEdit> {
+    let old_lines: Vec<&str> = old_src.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new_src.split_inclusive('\n').collect();
+
+    // Group the diff into runs of changed lines, as the index of the
+    // first line of the run on each side and the number of lines. The
+    // results are in order, so walk both sides with indexes rather
+    // than relying on the references returned.
+    let mut runs: Vec<((usize, usize), (usize, usize))> = vec![];
+    let mut old_i = 0;
+    let mut new_i = 0;
+    let mut in_run = false;
+    for diff_res in myers_diff::slice_by_hash(&old_lines, &new_lines) {
+        if matches!(diff_res, myers_diff::DiffResult::Both(_, _)) {
+            old_i += 1;
+            new_i += 1;
+            in_run = false;
+            continue;
+        }
+
+        if !in_run {
+            runs.push(((old_i, 0), (new_i, 0)));
+            in_run = true;
+        }
+        let run = runs.last_mut().unwrap();
+        if let myers_diff::DiffResult::Left(_) = diff_res {
+            run.0 .1 += 1;
+            old_i += 1;
+        } else {
+            run.1 .1 += 1;
+            new_i += 1;
+        }
+    }
+
+    let mut edits = vec![];
+    let mut start_byte = 0;
+    let mut line = 0;
+    for ((old_start, old_len), (new_start, new_len)) in runs {
+        // Every run starts at the beginning of a line.
+        start_byte += new_lines[line..new_start]
+            .iter()
+            .map(|l| l.len())
+            .sum::<usize>();
+        line = new_start;
+        let start_position = ts::Point {
+            row: new_start,
+            column: 0,
+        };
+
+        let old_text = old_lines[old_start..old_start + old_len].concat();
+        let new_text = new_lines[new_start..new_start + new_len].concat();
+        edits.push(ts::InputEdit {
+            start_byte,
+            old_end_byte: start_byte + old_text.len(),
+            new_end_byte: start_byte + new_text.len(),
+            start_position,
+            old_end_position: advance(start_position, &old_text),
+            new_end_position: advance(start_position, &new_text),
+        });
+    }
+
+    edits
+}
+
+/// A copy of `node` and its descendants, moved by `line_offset`
+/// lines. Positions on the same line are unchanged.
+fn copy_shifted<'a>(
+    arena: &'a Arena<Syntax<'a>>,
+    node: &Syntax<'a>,
+    line_offset: i64,
+) -> &'a Syntax<'a> {
+    let shift = |spans: &[SingleLineSpan]| -> Vec<SingleLineSpan> {
+        spans
+            .iter()
+            .map(|span| SingleLineSpan {
+                line: ((i64::from(span.line.0) + line_offset) as u32).into(),
+                ..*span
+            })
+            .collect()
+    };
+
+    match node {
+        Syntax::List {
+            open_position,
+            open_content,
+            children,
+            close_position,
+            close_content,
+            num_descendants,
+            ..
+        } => arena.alloc(Syntax::List {
+            info: Default::default(),
+            open_position: shift(open_position),
+            open_content: open_content.clone(),
+            children: children
+                .iter()
+                .map(|child| copy_shifted(arena, child, line_offset))
+                .collect(),
+            close_position: shift(close_position),
+            close_content: close_content.clone(),
+            num_descendants: *num_descendants,
+        }),
+        Syntax::Atom {
+            position,
+            content,
+            kind,
+            ..
+        } => arena.alloc(Syntax::Atom {
+            info: Default::default(),
+            position: shift(position),
+            content: content.clone(),
+            kind: *kind,
+        }),
+    }
+}
+
+/// A child of the root in the previous version, keyed by its start
+/// byte.
+struct TopLevel<'a> {
+    end_byte: usize,
+    kind_id: u16,
+    start_position: ts::Point,
+    syntax: Option<&'a Syntax<'a>>,
+}
+
+struct Version<'a> {
+    src: String,
+    tree: ts::Tree,
+    toplevel: FxHashMap<usize, TopLevel<'a>>,
+}
+
+/// A version of the file, parsed by `ParseSession::parse`.
+pub struct Parsed<'a> {
+    pub tree: ts::Tree,
+    /// Nodes of `tree` parsed as other languages.
+    pub subtrees: SubTrees,
+    pub nodes: Vec<&'a Syntax<'a>>,
+    /// The number of top-level syntax nodes copied from the previous
+    /// version.
+    pub num_reused: usize,
+}
+
+/// Parses successive versions of the same file, reusing the work from
+/// the previous version.
+///
+/// Syntax nodes are allocated in `arena`, which grows with each
+/// version. Nodes are never shared between versions, so the nodes
+/// from two calls to `parse` can be diffed against each other.
+pub struct ParseSession<'a> {
+    arena: &'a Arena<Syntax<'a>>,
+    config: TreeSitterConfig,
+    parser: ts::Parser,
+    ignore_comments: bool,
+    previous: Option<Version<'a>>,
+}
+
+impl<'a> ParseSession<'a> {
+    pub fn new(
+        arena: &'a Arena<Syntax<'a>>,
+        config: TreeSitterConfig,
+        ignore_comments: bool,
+    ) -> Self {
+        let mut parser = ts::Parser::new();
+        parser
+            .set_language(config.language)
+            .expect("Incompatible tree-sitter version");
+        Self {
+            arena,
+            config,
+            parser,
+            ignore_comments,
+            previous: None,
+        }
+    }
+
+    pub fn config(&self) -> &TreeSitterConfig {
+        &self.config
+    }
+
+    /// Forget the previous version, so the next call to `parse` is a
+    /// fresh parse.
+    pub fn forget_previous(&mut self) {
+        self.previous = None;
+    }
+
+    /// Parse `src`, incrementally if we've parsed a previous version.
+    pub fn parse(&mut self, src: &str) -> Parsed<'a> {
+        let (tree, edits, changed_ranges) = match &self.previous {
+            Some(previous) => {
+                let edits = input_edits(&previous.src, src);
+                let mut old_tree = previous.tree.clone();
+                for edit in &edits {
+                    old_tree.edit(edit);
+                }
+                let tree = self.parser.parse(src, Some(&old_tree)).unwrap();
+                let changed_ranges: Vec<ts::Range> = old_tree.changed_ranges(&tree).collect();
+                (tree, edits, changed_ranges)
+            }
+            None => (self.parser.parse(src, None).unwrap(), vec![], vec![]),
+        };
+
+        // Sub-language queries match nodes inside a top-level node,
+        // so an unchanged node is converted the same way.
+        let can_reuse = self.previous.is_some();
+        let subtrees = parse_subtrees(src, &self.config, &tree);
+        // Ignore queries can match siblings, so whether a node is
+        // ignored can depend on the nodes around it. Never reuse
+        // nodes that overlap an ignored region, in this version or
+        // the previous one.
+        let ignored: Vec<(usize, usize)> = ignored_nodes(&tree, src, &self.config)
+            .iter()
+            .map(|node| (node.start_byte(), node.end_byte()))
+            .collect();
+        let is_ignored = |start_byte: usize, end_byte: usize| {
+            ignored.iter().any(|(ignored_start, ignored_end)| {
+                *ignored_start < end_byte && start_byte < *ignored_end
+            })
+        };
+
+        let arena = self.arena;
+        let previous = &self.previous;
+        let doc_comment_prefixes = &self.config.policy.doc_comment_prefixes;
+        let mut toplevel = FxHashMap::default();
+        let mut num_reused = 0;
+        let mut reuse = |node: ts::Node| -> Option<Option<&'a Syntax<'a>>> {
+            if !can_reuse || node.has_error() {
+                return None;
+            }
+            let (start_byte, end_byte) = (node.start_byte(), node.end_byte());
+            if is_ignored(start_byte, end_byte) {
+                return None;
+            }
+            // Whether a doc comment line is code depends on the fences
+            // around it, so always convert them. They're cheap.
+            if doc_comment_prefixes
+                .iter()
+                .any(|prefix| src[start_byte..end_byte].starts_with(prefix.as_str()))
+            {
+                return None;
+            }
+            let overlaps = |range_start: usize, range_end: usize| {
+                range_start < end_byte && start_byte < range_end
+            };
+            if edits
+                .iter()
+                .any(|edit| overlaps(edit.start_byte, edit.new_end_byte.max(edit.start_byte + 1)))
+                || changed_ranges
+                    .iter()
+                    .any(|range| overlaps(range.start_byte, range.end_byte))
+            {
+                return None;
+            }
+
+            // The node is outside every edit, so its text is
+            // unchanged. Find where it was in the previous version.
+            let offset: i64 = edits
+                .iter()
+                .filter(|edit| edit.new_end_byte <= start_byte)
+                .map(|edit| edit.new_end_byte as i64 - edit.old_end_byte as i64)
+                .sum();
+            let old_start_byte = (start_byte as i64 - offset) as usize;
+            let old = previous.as_ref()?.toplevel.get(&old_start_byte)?;
+
+            let start_position = node.start_position();
+            if old.kind_id != node.kind_id()
+                || old.end_byte as i64 != end_byte as i64 - offset
+                || old.start_position.column != start_position.column
+            {
+                return None;
+            }
+
+            num_reused += 1;
+            let line_offset = start_position.row as i64 - old.start_position.row as i64;
+            Some(
+                old.syntax
+                    .map(|syntax| copy_shifted(arena, syntax, line_offset)),
+            )
+        };
+
+        let (syntaxes, _) = to_syntax_with_reuse(
+            &tree,
+            src,
+            arena,
+            &self.config,
+            &subtrees,
+            self.ignore_comments,
+            &mut reuse,
+        );
+
+        // Remember each child of the root, to reuse in the next
+        // version.
+        if !syntaxes.is_empty() {
+            let root = tree.root_node();
+            let mut cursor = root.walk();
+            for (child, syntax) in root.children(&mut cursor).zip(syntaxes.iter()) {
+                if is_ignored(child.start_byte(), child.end_byte()) {
+                    continue;
+                }
+                toplevel.insert(
+                    child.start_byte(),
+                    TopLevel {
+                        end_byte: child.end_byte(),
+                        kind_id: child.kind_id(),
+                        start_position: child.start_position(),
+                        syntax: *syntax,
+                    },
+                );
+            }
+        }
+
+        self.previous = Some(Version {
+            src: src.into(),
+            tree: tree.clone(),
+            toplevel,
+        });
+
+        let nodes = toplevel_nodes(&tree, &self.config, syntaxes);
+        Parsed {
+            tree,
+            subtrees,
+            nodes,
+            num_reused,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::DiffOptions;
+    use crate::parse::{
+        guess_language::Language,
+        syntax::init_all_info,
+        tree_sitter_parser::{from_language, from_language_with_options, parse, to_tree},
+    };
+
+    #[test]
+    fn test_input_edits() {
+        let old_src = "a\nb\nc\n";
+        let new_src = "a\nxx\nyy\nc\n";
+        let edits = input_edits(old_src, new_src);
+
+        assert_eq!(
+            edits,
+            vec![ts::InputEdit {
+                start_byte: 2,
+                old_end_byte: 4,
+                new_end_byte: 8,
+                start_position: ts::Point { row: 1, column: 0 },
+                old_end_position: ts::Point { row: 2, column: 0 },
+                new_end_position: ts::Point { row: 3, column: 0 },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_input_edits_apply() {
+        let config = from_language(Language::JavaScript);
+        let old_src = "foo(1);\nbar(2);\nbaz(3);\nqux(4);";
+        let new_src = "foo(1);\nbar(2, 3);\nbaz(3);\nquux(4);";
+
+        let mut tree = to_tree(old_src, &config);
+        for edit in input_edits(old_src, new_src) {
+            tree.edit(&edit);
+        }
+        let mut parser = ts::Parser::new();
+        parser.set_language(config.language).unwrap();
+        let incremental = parser.parse(new_src, Some(&tree)).unwrap();
+
+        assert_eq!(
+            incremental.root_node().to_sexp(),
+            to_tree(new_src, &config).root_node().to_sexp()
+        );
+    }
+
+    #[test]
+    fn test_session_matches_parse() {
+        let old_src = "function foo() {\n  return 1;\n}\n\nfunction bar() {\n  return 2;\n}\n\nfunction baz() {\n  return 3;\n}\n";
+        let new_src = "// A new comment.\nfunction foo() {\n  return 1;\n}\n\nfunction bar() {\n  return 22;\n}\n\nfunction baz() {\n  return 3;\n}\n";
+
+        let arena = Arena::new();
+        let mut session = ParseSession::new(&arena, from_language(Language::JavaScript), false);
+        session.parse(old_src);
+        let parsed = session.parse(new_src);
+        // `foo` and `baz` are copied, shifted down a line.
+        assert_eq!(parsed.num_reused, 2);
+
+        let fresh = parse(&arena, new_src, &from_language(Language::JavaScript), false);
+        init_all_info(&parsed.nodes, &[]);
+        init_all_info(&fresh, &[]);
+        assert_eq!(format!("{:?}", parsed.nodes), format!("{:?}", fresh));
+    }
+
+    #[test]
+    fn test_session_same_source() {
+        let src = "a;\nb;\n";
+        let arena = Arena::new();
+        let mut session = ParseSession::new(&arena, from_language(Language::JavaScript), false);
+        let first = session.parse(src);
+        let second = session.parse(src);
+
+        assert_eq!(second.num_reused, 2);
+        // The nodes are copies, so both versions can be diffed.
+        assert!(!std::ptr::eq(first.nodes[0], second.nodes[0]));
+
+        session.forget_previous();
+        assert_eq!(session.parse(src).num_reused, 0);
+    }
+
+    #[test]
+    fn test_session_ignore_query_siblings() {
+        let diff_options = DiffOptions {
+            ignore_queries: vec![(
+                Language::Rust,
+                "((attribute_item) @ignore . (mod_item) @ignore)".into(),
+            )],
+            ..DiffOptions::default()
+        };
+        let config = from_language_with_options(Language::Rust, &diff_options);
+        let arena = Arena::new();
+        let mut session = ParseSession::new(&arena, config, false);
+        session.parse("#[cfg(test)]\nmod tests {}\n");
+        // The module is unchanged, but it's no longer ignored.
+        let parsed = session.parse("const X: u8 = 1;\nmod tests {}\n");
+
+        assert_eq!(parsed.num_reused, 0);
+        assert_eq!(parsed.nodes.len(), 2);
+    }
+}
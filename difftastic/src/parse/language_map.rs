@@ -0,0 +1,366 @@
+//! Languages chosen by the user for particular paths, rather than
+//! guessed from the file: `--language-map` globs, and
+//! `linguist-language` attributes in `.gitattributes` files.
+//!
+//! Both use gitignore-style globs. A pattern without a `/` matches
+//! the file name in any directory, and `*.h` matches `src/foo.h`.
+//! Otherwise the pattern matches the whole path, where `*` doesn't
+//! match a `/` but `**` does, so `scripts/**` matches every file
+//! under `scripts`.
+
+use std::{
+    env, fmt, fs,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use lazy_static::lazy_static;
+use rustc_hash::FxHashMap;
+
+use crate::parse::guess_language::{self as guess, Language};
+
+lazy_static! {
+    pub static ref LANGUAGE_MAP: RwLock<LanguageMap> = RwLock::new(LanguageMap::default());
+    static ref DIRECTORIES: Mutex<FxHashMap<PathBuf, Directory>> = Mutex::new(FxHashMap::default());
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LanguageMapError(pub String);
+
+impl fmt::Display for LanguageMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// `GLOB:LANGUAGE` mappings, in the order they were given. The
+/// mappings from `--language-map` are in `LANGUAGE_MAP`.
+#[derive(Debug, Default)]
+pub struct LanguageMap(Vec<(String, Language)>);
+
+impl LanguageMap {
+    /// Add a `GLOB:LANGUAGE` mapping. Mappings added later take
+    /// precedence.
+    pub fn add(&mut self, mapping: &str) -> Result<(), LanguageMapError> {
+        let (pattern, name) = mapping
+            .rsplit_once(':')
+            .ok_or_else(|| LanguageMapError(format!("Expected GLOB:LANGUAGE, got: {}", mapping)))?;
+        let language = guess::from_language_alias(name.trim())
+            .ok_or_else(|| LanguageMapError(format!("Unknown language: {}", name.trim())))?;
+        if pattern.is_empty() {
+            return Err(LanguageMapError(format!(
+                "Expected a glob before the language: {}",
+                mapping
+            )));
+        }
+
+        self.0.push((pattern.to_owned(), language));
+        Ok(())
+    }
+
+    /// The language that this map sets for `path`, if any. Relative
+    /// globs are matched against the path relative to the current
+    /// directory.
+    pub fn language(&self, path: &Path) -> Option<Language> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let path = match env::current_dir() {
+            Ok(current_dir) => path.strip_prefix(&current_dir).unwrap_or(path),
+            Err(_) => path,
+        };
+        let path = slash_path(path);
+        self.0
+            .iter()
+            .rev()
+            .find(|(pattern, _)| glob_matches_path(pattern, &path))
+            .map(|(_, language)| *language)
+    }
+}
+
+/// Add a `GLOB:LANGUAGE` mapping from `--language-map`.
+pub fn add(mapping: &str) -> Result<(), LanguageMapError> {
+    LANGUAGE_MAP.write().unwrap().add(mapping)
+}
+
+/// What `from_gitattributes` needs to know about a directory. Many
+/// files share a directory in a directory diff, so this is cached.
+#[derive(Debug, Clone)]
+struct Directory {
+    /// The contents of its `.gitattributes`, if it has one.
+    gitattributes: Option<Arc<str>>,
+    /// Is this the root of a repository?
+    is_repo_root: bool,
+}
+
+fn directory(dir: &Path) -> Directory {
+    if let Some(directory) = DIRECTORIES.lock().unwrap().get(dir) {
+        return directory.clone();
+    }
+
+    let directory = Directory {
+        gitattributes: fs::read_to_string(dir.join(".gitattributes"))
+            .ok()
+            .map(Arc::from),
+        is_repo_root: dir.join(".git").exists(),
+    };
+    DIRECTORIES
+        .lock()
+        .unwrap()
+        .insert(dir.to_path_buf(), directory.clone());
+    directory
+}
+
+/// The `linguist-language` of `path` in the nearest `.gitattributes`
+/// that sets one, looking in each directory up to the root of the
+/// repository. Paths outside a repository have no attributes.
+pub fn from_gitattributes(path: &Path) -> Option<Language> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().ok()?.join(path)
+    };
+
+    let mut gitattributes = vec![];
+    let mut dir = path.parent();
+    loop {
+        let current_dir = dir?;
+        let directory = directory(current_dir);
+        if let Some(src) = directory.gitattributes {
+            gitattributes.push((current_dir, src));
+        }
+        if directory.is_repo_root {
+            break;
+        }
+        dir = current_dir.parent();
+    }
+
+    gitattributes.into_iter().find_map(|(dir, src)| {
+        let relative_path = path.strip_prefix(dir).ok()?;
+        gitattributes_language(&src, &slash_path(relative_path))
+    })
+}
+
+/// `path` with `/` separators, and without a leading `/`.
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            Component::ParentDir => Some("..".into()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The language set by the last line of a `.gitattributes` file that
+/// matches `path` and sets `linguist-language`. `path` is relative to
+/// the directory of the file.
+fn gitattributes_language(src: &str, path: &str) -> Option<Language> {
+    let mut language = None;
+    for line in src.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let pattern = match parts.next() {
+            Some(pattern) => pattern,
+            None => continue,
+        };
+        if !glob_matches_path(pattern, path) {
+            continue;
+        }
+        for attribute in parts {
+            if let Some(name) = attribute.strip_prefix("linguist-language=") {
+                if let Some(attribute_language) = guess::from_language_alias(name) {
+                    language = Some(attribute_language);
+                }
+            }
+        }
+    }
+    language
+}
+
+/// Does the gitignore-style `pattern` match `path`?
+fn glob_matches_path(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        glob_match(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+    } else {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        glob_match(pattern.as_bytes(), file_name.as_bytes())
+    }
+}
+
+/// Match `text` against a glob, where `*` and `?` don't match `/`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` can also match no directories at all.
+            if let Some(after_slash) = rest.strip_prefix(b"/") {
+                if glob_match(after_slash, text) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text_rest @ ..] if *c != b'/' => glob_match(rest, text_rest),
+            _ => false,
+        },
+        [b'[', class @ ..] => match (text, match_class(class, text.first().copied())) {
+            ([_, text_rest @ ..], Some((true, rest))) => glob_match(rest, text_rest),
+            (_, Some((false, _))) | ([], Some(_)) => false,
+            // An unclosed `[` is an ordinary character.
+            (_, None) => text.first() == Some(&b'[') && glob_match(class, &text[1..]),
+        },
+        [c, rest @ ..] => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Match `c` against a character class such as `[a-z]` or `[!0-9]`,
+/// where `class` starts after the `[`. Returns whether it matched and
+/// the pattern after the `]`, or `None` if there's no `]`.
+fn match_class(class: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, class) = match class {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    // A `]` straight after the `[` is part of the class.
+    let end = class.iter().skip(1).position(|b| *b == b']')? + 1;
+    let (items, rest) = (&class[..end], &class[end + 1..]);
+
+    let c = match c {
+        Some(c) if c != b'/' => c,
+        _ => return Some((false, rest)),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < items.len() {
+        if i + 2 < items.len() && items[i + 1] == b'-' {
+            matched |= items[i] <= c && c <= items[i + 2];
+            i += 3;
+        } else {
+            matched |= items[i] == c;
+            i += 1;
+        }
+    }
+    Some((matched != negated, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches_path() {
+        assert!(glob_matches_path("*.h", "src/foo.h"));
+        assert!(!glob_matches_path("*.h", "src/foo.hh"));
+        assert!(glob_matches_path("scripts/*", "scripts/build"));
+        assert!(!glob_matches_path("scripts/*", "scripts/ci/build"));
+        assert!(glob_matches_path("scripts/**", "scripts/ci/build"));
+        assert!(glob_matches_path("**/Jenkinsfile", "Jenkinsfile"));
+        assert!(glob_matches_path("**/Jenkinsfile", "ci/Jenkinsfile"));
+        assert!(glob_matches_path("/bin/*", "bin/tool"));
+        assert!(glob_matches_path("tool-[0-9]", "tool-3"));
+        assert!(!glob_matches_path("tool-[!0-9]", "tool-3"));
+        assert!(glob_matches_path("a[b", "a[b"));
+    }
+
+    #[test]
+    fn test_gitattributes_language() {
+        let src = "# Generated code is Python.
+*.gen linguist-generated
+*.gen linguist-language=Python
+bin/* text linguist-language=Shell
+bin/special linguist-language=Ruby
+";
+        assert_eq!(
+            gitattributes_language(src, "a/b.gen"),
+            Some(Language::Python)
+        );
+        assert_eq!(
+            gitattributes_language(src, "bin/build"),
+            Some(Language::Bash)
+        );
+        assert_eq!(
+            gitattributes_language(src, "bin/special"),
+            Some(Language::Ruby)
+        );
+        assert_eq!(gitattributes_language(src, "lib/build"), None);
+    }
+
+    #[test]
+    fn test_from_gitattributes() {
+        let dir = env::temp_dir().join(format!("difft-gitattributes-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(dir.join("tools")).unwrap();
+        fs::write(
+            dir.join(".gitattributes"),
+            "tools/* linguist-language=Python\n",
+        )
+        .unwrap();
+
+        let language = from_gitattributes(&dir.join("tools").join("deploy"));
+
+        // Without a repository, the .gitattributes isn't used.
+        let outside = env::temp_dir().join(format!("difft-no-repo-{}", std::process::id()));
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(
+            outside.join(".gitattributes"),
+            "* linguist-language=Python\n",
+        )
+        .unwrap();
+        let outside_language = from_gitattributes(&outside.join("deploy"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+        assert_eq!(language, Some(Language::Python));
+        assert_eq!(outside_language, None);
+    }
+
+    #[test]
+    fn test_language_map() {
+        let mut language_map = LanguageMap::default();
+        language_map.add("*.h:C").unwrap();
+        language_map.add("src/*.h:C++").unwrap();
+        assert_eq!(
+            language_map.language(Path::new("include/foo.h")),
+            Some(Language::C)
+        );
+        assert_eq!(
+            language_map.language(Path::new("src/foo.h")),
+            Some(Language::CPlusPlus)
+        );
+        assert_eq!(language_map.language(Path::new("src/foo.c")), None);
+    }
+
+    #[test]
+    fn test_add_errors() {
+        let mut language_map = LanguageMap::default();
+        assert_eq!(
+            language_map.add("*.foo"),
+            Err(LanguageMapError(
+                "Expected GLOB:LANGUAGE, got: *.foo".into()
+            ))
+        );
+        assert_eq!(
+            language_map.add("*.foo:Klingon"),
+            Err(LanguageMapError("Unknown language: Klingon".into()))
+        );
+    }
+}
@@ -0,0 +1,294 @@
+//! Tree-sitter grammars loaded from shared libraries at runtime.
+//!
+//! The directory passed with `--grammar-dir` contains a
+//! `grammars.conf` file describing each grammar:
+//!
+//! ```text
+//! # An internal build DSL.
+//! [mydsl]
+//! extensions = dsl mydsl
+//! file_names = Dslfile
+//! atom_nodes = string
+//! delimiter_tokens = ( ) { }
+//! ```
+//!
+//! Each section loads the `tree_sitter_<name>` function from
+//! `<name>.so` (`.dylib` on macOS, `.dll` on Windows) in the same
+//! directory, and highlight queries from `<name>.scm` if it
+//! exists. `library = PATH` and `highlights = PATH` override these
+//! paths, relative to the directory.
+//!
+//! Loaded grammars are registered for the rest of the process, and
+//! their extensions and file names take precedence over the built-in
+//! languages.
+
+use std::{
+    env::consts::DLL_SUFFIX,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+use tree_sitter as ts;
+
+/// The name of the file describing the grammars in a grammar
+/// directory.
+pub const CONFIG_FILE_NAME: &str = "grammars.conf";
+
+pub struct LoadedGrammar {
+    /// The name of the grammar, as shown to the user.
+    pub name: &'static str,
+    pub language: ts::Language,
+    pub extensions: Vec<String>,
+    pub file_names: Vec<String>,
+    /// The source of the highlight query, which has already been
+    /// checked against `language`.
+    pub highlights: String,
+    pub atom_nodes: Vec<String>,
+    pub delimiter_tokens: Vec<(String, String)>,
+}
+
+lazy_static! {
+    static ref GRAMMARS: RwLock<Vec<&'static LoadedGrammar>> = RwLock::new(vec![]);
+}
+
+/// The grammar registered at `id`.
+pub fn get(id: usize) -> &'static LoadedGrammar {
+    GRAMMARS.read().unwrap()[id]
+}
+
+/// All the registered grammars, with their IDs.
+pub fn all() -> Vec<(usize, &'static LoadedGrammar)> {
+    GRAMMARS
+        .read()
+        .unwrap()
+        .iter()
+        .copied()
+        .enumerate()
+        .collect()
+}
+
+/// The ID of the first grammar matching `pred`.
+pub fn find(pred: impl Fn(&LoadedGrammar) -> bool) -> Option<usize> {
+    GRAMMARS
+        .read()
+        .unwrap()
+        .iter()
+        .position(|grammar| pred(grammar))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct GrammarError(pub String);
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A section of `grammars.conf`.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct GrammarSpec {
+    name: String,
+    library: Option<String>,
+    highlights: Option<String>,
+    extensions: Vec<String>,
+    file_names: Vec<String>,
+    atom_nodes: Vec<String>,
+    delimiter_tokens: Vec<(String, String)>,
+}
+
+/// Parse `grammars.conf`. Blank lines and lines starting with `#`
+/// are ignored.
+fn parse_config(src: &str) -> Result<Vec<GrammarSpec>, GrammarError> {
+    let mut specs: Vec<GrammarSpec> = vec![];
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let err = |msg: String| GrammarError(format!("line {}: {}", i + 1, msg));
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(err(format!(
+                    "Grammar names may only contain letters, digits and underscores, got: {}",
+                    name
+                )));
+            }
+            specs.push(GrammarSpec {
+                name: name.into(),
+                ..GrammarSpec::default()
+            });
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| err(format!("Expected NAME = VALUE or [grammar], got: {}", line)))?;
+        let key = key.trim();
+        let value = value.trim();
+        let spec = specs
+            .last_mut()
+            .ok_or_else(|| err(format!("{} must be inside a [grammar] section", key)))?;
+
+        let values = || value.split_whitespace().map(String::from).collect();
+        match key {
+            "library" => spec.library = Some(value.into()),
+            "highlights" => spec.highlights = Some(value.into()),
+            "extensions" => spec.extensions = values(),
+            "file_names" => spec.file_names = values(),
+            "atom_nodes" => spec.atom_nodes = values(),
+            "delimiter_tokens" => {
+                let tokens: Vec<String> = values();
+                if tokens.len() % 2 != 0 {
+                    return Err(err(format!(
+                        "Expected pairs of open and close tokens for {}",
+                        key
+                    )));
+                }
+                spec.delimiter_tokens = tokens
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+            }
+            _ => return Err(err(format!("Unknown setting: {}", key))),
+        }
+    }
+    Ok(specs)
+}
+
+/// Load the `tree_sitter_<name>` function from the shared library at
+/// `path`.
+fn load_language(path: &Path, name: &str) -> Result<ts::Language, GrammarError> {
+    // The errors from the dynamic loader already include the path.
+    let err = |e: libloading::Error| GrammarError(e.to_string());
+
+    let language = unsafe {
+        let library = libloading::Library::new(path).map_err(err)?;
+        let language_fn: libloading::Symbol<unsafe extern "C" fn() -> ts::Language> = library
+            .get(format!("tree_sitter_{}", name).as_bytes())
+            .map_err(err)?;
+        let language = language_fn();
+        // The language points into the library, so it must stay
+        // loaded for the rest of the process.
+        std::mem::forget(library);
+        language
+    };
+
+    ts::Parser::new()
+        .set_language(language)
+        .map_err(|e| GrammarError(format!("{}: {}", path.display(), e)))?;
+    Ok(language)
+}
+
+fn load(dir: &Path, spec: GrammarSpec) -> Result<LoadedGrammar, GrammarError> {
+    let library_path = match &spec.library {
+        Some(library) => dir.join(library),
+        None => dir.join(format!("{}{}", spec.name, DLL_SUFFIX)),
+    };
+    let language = load_language(&library_path, &spec.name)?;
+
+    let highlights_path: Option<PathBuf> = match &spec.highlights {
+        Some(highlights) => Some(dir.join(highlights)),
+        None => Some(dir.join(format!("{}.scm", spec.name))).filter(|path| path.exists()),
+    };
+    let highlights = match highlights_path {
+        Some(path) => {
+            let src = fs::read_to_string(&path)
+                .map_err(|e| GrammarError(format!("{}: {}", path.display(), e)))?;
+            ts::Query::new(language, &src)
+                .map_err(|e| GrammarError(format!("{}: {:?}", path.display(), e)))?;
+            src
+        }
+        None => String::new(),
+    };
+
+    Ok(LoadedGrammar {
+        name: Box::leak(spec.name.into_boxed_str()),
+        language,
+        extensions: spec.extensions,
+        file_names: spec.file_names,
+        highlights,
+        atom_nodes: spec.atom_nodes,
+        delimiter_tokens: spec.delimiter_tokens,
+    })
+}
+
+/// Load the grammars described by `grammars.conf` in `dir`, and
+/// register them. Returns the number of grammars loaded.
+pub fn load_dir(dir: &Path) -> Result<usize, GrammarError> {
+    let config_path = dir.join(CONFIG_FILE_NAME);
+    let src = fs::read_to_string(&config_path)
+        .map_err(|e| GrammarError(format!("{}: {}", config_path.display(), e)))?;
+    let specs = parse_config(&src)
+        .map_err(|GrammarError(e)| GrammarError(format!("{}: {}", config_path.display(), e)))?;
+
+    let num_specs = specs.len();
+    for spec in specs {
+        let grammar = load(dir, spec)?;
+        GRAMMARS.write().unwrap().push(Box::leak(Box::new(grammar)));
+    }
+    Ok(num_specs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let specs = parse_config(
+            "# An internal DSL.
+[mydsl]
+extensions = dsl mydsl
+delimiter_tokens = ( ) { }
+library = build/libtree-sitter-mydsl.so
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            specs,
+            vec![GrammarSpec {
+                name: "mydsl".into(),
+                library: Some("build/libtree-sitter-mydsl.so".into()),
+                extensions: vec!["dsl".into(), "mydsl".into()],
+                delimiter_tokens: vec![("(".into(), ")".into()), ("{".into(), "}".into())],
+                ..GrammarSpec::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        assert_eq!(
+            parse_config("extensions = dsl"),
+            Err(GrammarError(
+                "line 1: extensions must be inside a [grammar] section".into()
+            ))
+        );
+        assert_eq!(
+            parse_config("[my-dsl]"),
+            Err(GrammarError(
+                "line 1: Grammar names may only contain letters, digits and underscores, got: my-dsl".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_load_missing_library() {
+        let dir = std::env::temp_dir().join("difft-test-missing-grammar");
+        let res = load(
+            &dir,
+            GrammarSpec {
+                name: "missing".into(),
+                ..GrammarSpec::default()
+            },
+        );
+        assert!(matches!(res, Err(GrammarError(e)) if e.contains("missing")));
+    }
+}
@@ -1,3 +1,8 @@
 pub mod guess_language;
+pub mod incremental;
+pub mod language_map;
+pub mod loaded_grammars;
+pub mod normalize;
+pub mod policy;
 pub mod syntax;
 pub mod tree_sitter_parser;
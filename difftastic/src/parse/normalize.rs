@@ -0,0 +1,555 @@
+//! Normalising identifiers and literals, so that the same change
+//! made with different variable names or values looks the same.
+//!
+//! Normalisation is per top-level item: each item on each side of a
+//! diff gets its own renaming table, so `let x = 1; x + 2` and
+//! `let y = 3; y + 4` both become `let $id0 = $num; $id0 + $num`.
+//! Locals are numbered in order of first occurrence, so adding a new
+//! local renumbers the later locals in the same item, and they're no
+//! longer unchanged. Other items aren't affected.
+//!
+//! A local is a name that the item declares in a binding position,
+//! such as a `let` pattern or a parameter, according to the tree-sitter
+//! node kinds in `LanguagePolicy::binding_kinds`. Every `identifier`
+//! with that name in the item is renamed, but fields, methods, types
+//! and keywords aren't.
+//!
+//! Normalised content decides which nodes are unchanged, and is used
+//! for the token n-gram and path context features. With
+//! `--show-normalized`, the display shows it too. The node kinds in
+//! the vector file are unaffected.
+//!
+//! Normalisations of the tree structure, such as ignoring trailing
+//! commas, are applied earlier, when tree-sitter nodes are converted
+//! to syntax nodes. See `SyntaxNormalizations`.
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    lines::LineNumber,
+    parse::syntax::{AtomKind, MatchKind, MatchedPos, Syntax, SyntaxId, TokenKind},
+    positions::SingleLineSpan,
+};
+
+/// Names that we never rename, because they're usually calls to
+/// well-known APIs rather than local variables. Replacing `unwrap`
+/// with `expect` is interesting, replacing `foo` with `bar` is not.
+pub const DEFAULT_KEEP_NAMES: &[&str] = &[
+    "as_mut",
+    "as_ref",
+    "assert",
+    "assert_eq",
+    "assert_ne",
+    "borrow",
+    "borrow_mut",
+    "clone",
+    "collect",
+    "default",
+    "drop",
+    "err",
+    "expect",
+    "format",
+    "get",
+    "get_mut",
+    "insert",
+    "into",
+    "into_iter",
+    "is_empty",
+    "is_none",
+    "is_some",
+    "iter",
+    "iter_mut",
+    "len",
+    "lock",
+    "map",
+    "map_err",
+    "new",
+    "ok",
+    "ok_or",
+    "ok_or_else",
+    "panic",
+    "println",
+    "push",
+    "read",
+    "remove",
+    "self",
+    "take",
+    "to_owned",
+    "to_string",
+    "todo",
+    "unreachable",
+    "unwrap",
+    "unwrap_or",
+    "unwrap_or_default",
+    "unwrap_or_else",
+    "vec",
+    "write",
+];
+
+#[derive(Debug, Clone)]
+pub struct NormalizeOptions {
+    /// Replace local identifiers with `$id0`, `$id1` etc, numbered in
+    /// order of first occurrence.
+    pub rename_identifiers: bool,
+    /// Replace literals with a placeholder for their type, such as
+    /// `$str` or `$num`.
+    pub bucket_literals: bool,
+    /// Identifiers that are kept as-is, even when renaming.
+    pub keep_names: FxHashSet<String>,
+    /// Show the normalised tokens in the diff display, instead of the
+    /// original code.
+    pub show_in_display: bool,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        Self {
+            rename_identifiers: true,
+            bucket_literals: true,
+            keep_names: DEFAULT_KEEP_NAMES.iter().map(|s| s.to_string()).collect(),
+            show_in_display: false,
+        }
+    }
+}
+
+/// Syntactic differences that don't change the meaning of the code,
+/// and so are left out when converting tree-sitter nodes to syntax
+/// nodes. All of these are off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyntaxNormalizations {
+    /// Ignore a comma after the last item of a list with at least two
+    /// items, so `[a, b,]` and `[a, b]` are the same. A single item
+    /// is left alone, as `(x,)` is often a tuple and `(x)` isn't.
+    pub trailing_separators: bool,
+    /// Compare adjacent imports, and the items of import lists such
+    /// as Rust's `use foo::{a, b}`, in any order.
+    pub import_order: bool,
+    /// Ignore parentheses around expressions that don't need them,
+    /// such as `(x)` or `return (a + b)`.
+    pub redundant_parens: bool,
+}
+
+impl SyntaxNormalizations {
+    pub fn all() -> Self {
+        Self {
+            trailing_separators: true,
+            import_order: true,
+            redundant_parens: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Normalised atom content for one side, keyed by `Syntax::id`.
+pub type NormalizedContent = FxHashMap<SyntaxId, String>;
+
+/// Where a token starts: its line, and its column in bytes.
+pub type TokenStart = (LineNumber, u32);
+
+/// The starts of the identifiers on one side that refer to locals.
+/// See `tree_sitter_parser::local_positions`.
+pub type LocalPositions = FxHashSet<TokenStart>;
+
+/// Normalises the tokens on one side of a diff.
+///
+/// The renaming table is stateful, so use a fresh `Normalizer` for
+/// each side.
+pub struct Normalizer<'o> {
+    options: &'o NormalizeOptions,
+    locals: &'o LocalPositions,
+    renames: FxHashMap<String, String>,
+}
+
+impl<'o> Normalizer<'o> {
+    pub fn new(options: &'o NormalizeOptions, locals: &'o LocalPositions) -> Self {
+        Self {
+            options,
+            locals,
+            renames: FxHashMap::default(),
+        }
+    }
+
+    /// The normalised form of an atom with this content, starting at
+    /// `start`. Returns `None` if the content should be kept as-is.
+    pub fn normalize_atom(
+        &mut self,
+        content: &str,
+        kind: AtomKind,
+        start: TokenStart,
+    ) -> Option<String> {
+        match kind {
+            AtomKind::String if self.options.bucket_literals => {
+                Some(string_bucket(content).to_owned())
+            }
+            AtomKind::Normal | AtomKind::Keyword
+                if self.options.bucket_literals && (content == "true" || content == "false") =>
+            {
+                Some("$bool".to_owned())
+            }
+            AtomKind::Normal if self.options.bucket_literals && looks_like_number(content) => {
+                Some("$num".to_owned())
+            }
+            AtomKind::String | AtomKind::Comment => None,
+            _ if self.options.rename_identifiers
+                && self.locals.contains(&start)
+                && !self.options.keep_names.contains(content) =>
+            {
+                let next_id = self.renames.len();
+                Some(
+                    self.renames
+                        .entry(content.to_owned())
+                        .or_insert_with(|| format!("$id{}", next_id))
+                        .clone(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// The normalised text of a token, as shown in a `MatchedPos`.
+    /// Delimiters and kept names are returned unchanged.
+    pub fn normalize_token(&mut self, content: &str, kind: TokenKind, start: TokenStart) -> String {
+        match kind {
+            TokenKind::Atom(atom_kind) => self
+                .normalize_atom(content, atom_kind, start)
+                .unwrap_or_else(|| content.to_owned()),
+            TokenKind::Delimiter => content.to_owned(),
+        }
+    }
+}
+
+fn looks_like_number(content: &str) -> bool {
+    match content.chars().next() {
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    }
+}
+
+fn string_bucket(content: &str) -> &'static str {
+    // Character literals are the only string-like atoms that are
+    // delimited with single quotes and are this short, e.g. 'a' or
+    // '\n'.
+    if content.starts_with('\'')
+        && content.ends_with('\'')
+        && content.len() >= 3
+        && content.chars().count() <= 12
+    {
+        "$char"
+    } else {
+        "$str"
+    }
+}
+
+/// Compute normalised content for all the atoms in `roots`, with a
+/// fresh renaming table for each root. `locals` are the identifiers
+/// on this side that refer to locals.
+///
+/// Assumes that unique IDs have already been set.
+pub fn normalize_side(
+    roots: &[&Syntax],
+    options: &NormalizeOptions,
+    locals: &LocalPositions,
+) -> NormalizedContent {
+    fn walk(node: &Syntax, normalizer: &mut Normalizer, res: &mut NormalizedContent) {
+        match node {
+            Syntax::List { children, .. } => {
+                for child in children {
+                    walk(child, normalizer, res);
+                }
+            }
+            Syntax::Atom {
+                content,
+                kind,
+                position,
+                ..
+            } => {
+                let start = match position.first() {
+                    Some(span) => (span.line, span.start_col),
+                    None => return,
+                };
+                if let Some(normalized) = normalizer.normalize_atom(content, *kind, start) {
+                    res.insert(node.id(), normalized);
+                }
+            }
+        }
+    }
+
+    let mut res = NormalizedContent::default();
+    for root in roots {
+        let mut normalizer = Normalizer::new(options, locals);
+        walk(root, &mut normalizer, &mut res);
+    }
+    res
+}
+
+/// Replacement text for the tokens on one side, so the display can
+/// show normalised code. Only single-line atoms are replaced.
+#[derive(Debug, Default)]
+pub struct Substitutions {
+    /// The start column, end column and replacement of each token,
+    /// sorted by column.
+    by_line: FxHashMap<LineNumber, Vec<(u32, u32, String)>>,
+}
+
+impl Substitutions {
+    /// Substitutions for the atoms in `roots` with normalised content.
+    /// `locals` are the identifiers on this side that refer to locals.
+    pub fn new(roots: &[&Syntax], options: &NormalizeOptions, locals: &LocalPositions) -> Self {
+        fn walk(node: &Syntax, normalized: &NormalizedContent, res: &mut Substitutions) {
+            match node {
+                Syntax::List { children, .. } => {
+                    for child in children {
+                        walk(child, normalized, res);
+                    }
+                }
+                Syntax::Atom { position, .. } => {
+                    if let ([span], Some(text)) = (&position[..], normalized.get(&node.id())) {
+                        res.by_line.entry(span.line).or_default().push((
+                            span.start_col,
+                            span.end_col,
+                            text.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        let normalized = normalize_side(roots, options, locals);
+        let mut res = Self::default();
+        for root in roots {
+            walk(root, &normalized, &mut res);
+        }
+        for substitutions in res.by_line.values_mut() {
+            substitutions.sort();
+        }
+        res
+    }
+
+    /// `src` with every substitution applied.
+    pub fn apply(&self, src: &str) -> String {
+        src.split('\n')
+            .enumerate()
+            .map(|(i, line)| match self.by_line.get(&(i as u32).into()) {
+                Some(substitutions) => {
+                    let mut res = String::with_capacity(line.len());
+                    let mut col = 0;
+                    for (start, end, text) in substitutions {
+                        res.push_str(&line[col..*start as usize]);
+                        res.push_str(text);
+                        col = *end as usize;
+                    }
+                    res.push_str(&line[col..]);
+                    res
+                }
+                None => line.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Where column `col` on `line` is after applying the
+    /// substitutions. A column inside a replaced token is clamped to
+    /// the replacement.
+    fn column(&self, line: LineNumber, col: u32) -> u32 {
+        let substitutions = match self.by_line.get(&line) {
+            Some(substitutions) => substitutions,
+            None => return col,
+        };
+
+        let mut shift: i64 = 0;
+        for (start, end, text) in substitutions {
+            if *end <= col {
+                shift += text.len() as i64 - (end - start) as i64;
+            } else if *start < col {
+                let inside = (col - start).min(text.len() as u32);
+                return (*start as i64 + shift) as u32 + inside;
+            } else {
+                break;
+            }
+        }
+        (col as i64 + shift) as u32
+    }
+
+    fn span(&self, span: SingleLineSpan) -> SingleLineSpan {
+        SingleLineSpan {
+            line: span.line,
+            start_col: self.column(span.line, span.start_col),
+            end_col: self.column(span.line, span.end_col),
+        }
+    }
+}
+
+/// Move `positions` to match the source after `own` is applied.
+/// Positions on the other side of the diff move with `opposite`.
+pub fn shift_positions(
+    positions: &mut [MatchedPos],
+    own: &Substitutions,
+    opposite: &Substitutions,
+) {
+    let shift_all = |spans: &mut Vec<SingleLineSpan>, substitutions: &Substitutions| {
+        for span in spans.iter_mut() {
+            *span = substitutions.span(*span);
+        }
+    };
+
+    for matched_pos in positions {
+        matched_pos.pos = own.span(matched_pos.pos);
+        match &mut matched_pos.kind {
+            MatchKind::UnchangedToken {
+                self_pos,
+                opposite_pos,
+                ..
+            }
+            | MatchKind::Replaced {
+                self_pos,
+                opposite_pos,
+                ..
+            } => {
+                shift_all(self_pos, own);
+                shift_all(opposite_pos, opposite);
+            }
+            MatchKind::NovelLinePart {
+                self_pos,
+                opposite_pos,
+                ..
+            } => {
+                *self_pos = own.span(*self_pos);
+                shift_all(opposite_pos, opposite);
+            }
+            MatchKind::Novel { .. } | MatchKind::NovelWord { .. } | MatchKind::Ignored { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(col: u32) -> TokenStart {
+        (0.into(), col)
+    }
+
+    /// Locals at columns 0 to 9 on the first line.
+    fn locals() -> LocalPositions {
+        (0..10).map(start).collect()
+    }
+
+    #[test]
+    fn test_rename_consistently() {
+        let options = NormalizeOptions::default();
+        let locals = locals();
+        let mut normalizer = Normalizer::new(&options, &locals);
+
+        let names: Vec<_> = ["x", "y", "x"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                normalizer.normalize_token(name, TokenKind::Atom(AtomKind::Normal), start(i as u32))
+            })
+            .collect();
+
+        assert_eq!(names, vec!["$id0", "$id1", "$id0"]);
+    }
+
+    #[test]
+    fn test_keep_names() {
+        let options = NormalizeOptions::default();
+        let locals = locals();
+        let mut normalizer = Normalizer::new(&options, &locals);
+
+        assert_eq!(
+            normalizer.normalize_atom("self", AtomKind::Normal, start(0)),
+            None
+        );
+        assert_eq!(
+            normalizer.normalize_atom("my_var", AtomKind::Normal, start(1)),
+            Some("$id0".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_only_locals_renamed() {
+        let options = NormalizeOptions::default();
+        let locals = locals();
+        let mut normalizer = Normalizer::new(&options, &locals);
+
+        assert_eq!(
+            normalizer.normalize_atom("count", AtomKind::Normal, start(20)),
+            None
+        );
+        assert_eq!(
+            normalizer.normalize_atom("i32", AtomKind::Type, start(20)),
+            None
+        );
+        assert_eq!(
+            normalizer.normalize_atom("count", AtomKind::Normal, start(2)),
+            Some("$id0".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_bucket_literals() {
+        let options = NormalizeOptions::default();
+        let locals = LocalPositions::default();
+        let mut normalizer = Normalizer::new(&options, &locals);
+
+        assert_eq!(
+            normalizer.normalize_atom("\"foo\"", AtomKind::String, start(0)),
+            Some("$str".to_owned())
+        );
+        assert_eq!(
+            normalizer.normalize_atom("'\\n'", AtomKind::String, start(0)),
+            Some("$char".to_owned())
+        );
+        assert_eq!(
+            normalizer.normalize_atom("0x10u8", AtomKind::Normal, start(0)),
+            Some("$num".to_owned())
+        );
+        assert_eq!(
+            normalizer.normalize_atom("true", AtomKind::Keyword, start(0)),
+            Some("$bool".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_options_disabled() {
+        let options = NormalizeOptions {
+            rename_identifiers: false,
+            bucket_literals: false,
+            keep_names: FxHashSet::default(),
+            show_in_display: false,
+        };
+        let locals = locals();
+        let mut normalizer = Normalizer::new(&options, &locals);
+
+        assert_eq!(
+            normalizer.normalize_atom("x", AtomKind::Normal, start(0)),
+            None
+        );
+        assert_eq!(
+            normalizer.normalize_atom("\"foo\"", AtomKind::String, start(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_substitutions_apply_and_shift() {
+        let substitutions = Substitutions {
+            by_line: vec![(
+                0.into(),
+                vec![(4, 8, "$id0".to_owned()), (9, 10, "$id1".to_owned())],
+            )]
+            .into_iter()
+            .collect(),
+        };
+
+        assert_eq!(substitutions.apply("let name=x;\nx"), "let $id0=$id1;\nx");
+        // The `;` after both substitutions moves right by three.
+        assert_eq!(substitutions.column(0.into(), 10), 13);
+        assert_eq!(substitutions.column(0.into(), 8), 8);
+        assert_eq!(substitutions.column(1.into(), 0), 0);
+    }
+}
@@ -0,0 +1,510 @@
+//! Per-language knowledge used when parsing, correcting sliders and
+//! extracting features.
+//!
+//! Each language has a default `LanguagePolicy`, set up in
+//! `tree_sitter_parser::from_language`. A policy file, passed with
+//! `--language-policy`, overrides parts of it so a language can be
+//! tuned without rebuilding difft:
+//!
+//! ```text
+//! # Treat macro invocations as atoms, and record the enclosing
+//! # function or impl as the context of a change.
+//! [Rust]
+//! atom_nodes += token_tree
+//! container_kinds = function_item impl_item
+//!
+//! [Go]
+//! prefer_outer_delimiter = true
+//!
+//! # Swift doc comments are Markdown, so diff their code blocks
+//! # as Swift.
+//! [Swift]
+//! doc_comment_prefixes = ///
+//! ```
+//!
+//! `=` replaces the default value and `+=` adds to it. Delimiter
+//! tokens are given as pairs, e.g. `delimiter_tokens = ( ) [ ]`.
+
+use std::{collections::HashSet, fmt};
+
+use crate::parse::{
+    guess_language::{self as guess, Language},
+    normalize::SyntaxNormalizations,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanguagePolicy {
+    /// Tree-sitter nodes that we treat as indivisible atoms.
+    ///
+    /// This is particularly useful for strings, as some grammars use
+    /// several nodes for a single string literal. We don't want to
+    /// say e.g. the closing string delimiter moved, as it's confusing
+    /// and not well-balanced syntax.
+    ///
+    /// This is also useful for when tree-sitter nodes don't include
+    /// all the children in the source. This is known limitation of
+    /// tree-sitter, and occurs more often for complex string syntax.
+    /// <https://github.com/tree-sitter/tree-sitter/issues/1156>
+    pub atom_nodes: HashSet<String>,
+
+    /// We want to consider delimiter tokens as part of lists, not
+    /// standalone atoms. Tree-sitter includes delimiter tokens, so
+    /// mark which token pairs we consider to be delimiters.
+    pub delimiter_tokens: Vec<(String, String)>,
+
+    /// Tree-sitter nodes that are comments. Extra nodes and nodes
+    /// highlighted as comments are also treated as comments, so this
+    /// is only needed for grammars that do neither.
+    pub comment_kinds: HashSet<String>,
+
+    /// Tree-sitter nodes, such as functions or classes, that we
+    /// record as the context of a change in the vector file. The
+    /// context is the nearest container above the parent of the
+    /// changed node. If there are no containers, or none enclose the
+    /// node, it's the grandparent.
+    pub container_kinds: HashSet<String>,
+
+    /// Should nested slider correction prefer the outer delimiter?
+    /// See `sliders::fix_all_nested_sliders`.
+    pub prefer_outer_delimiter: bool,
+
+    /// Line comment prefixes, such as `///` in Rust, of doc comments
+    /// whose fenced code blocks are parsed as this language.
+    pub doc_comment_prefixes: Vec<String>,
+
+    /// The language of this policy, if it's for a whole language. Code
+    /// blocks in doc comments marked with its name, such as
+    /// ```` ```swift ````, are parsed as this language.
+    pub language: Option<Language>,
+
+    /// Tree-sitter nodes for import statements. When ignoring import
+    /// order, adjacent imports are sorted before diffing.
+    pub import_kinds: HashSet<String>,
+
+    /// Tree-sitter nodes whose children are imported names, such as
+    /// Rust's `use_list`. When ignoring import order, the children
+    /// are sorted before diffing.
+    pub import_list_kinds: HashSet<String>,
+
+    /// Tree-sitter nodes for an expression in parentheses, which we
+    /// can drop when ignoring redundant parentheses.
+    pub parenthesized_kinds: HashSet<String>,
+
+    /// Where an `identifier` declares a local variable or parameter,
+    /// which `--normalize` renames. `kind` matches identifiers that
+    /// are unnamed children of a `kind` node, such as Rust's
+    /// `closure_parameters`. `kind.field` matches identifiers in the
+    /// `field` of a `kind` node, or unnamed children of that field,
+    /// such as `let_declaration.pattern`.
+    pub binding_kinds: HashSet<String>,
+
+    /// Which syntactic differences to ignore. These are set by
+    /// `--normalize-syntax` for every language, rather than by the
+    /// policy file.
+    pub normalizations: SyntaxNormalizations,
+
+    /// Tree-sitter queries for regions to leave out of the diff, such
+    /// as generated code or license headers, with the language each
+    /// query is for. The regions are the nodes captured with
+    /// `@ignore`. These are set by `--ignore-query` and
+    /// `--ignore-query-file`, and include the queries for any
+    /// embedded languages.
+    pub ignore_queries: Vec<(Language, String)>,
+}
+
+impl LanguagePolicy {
+    pub fn new(atom_nodes: HashSet<&str>, delimiter_tokens: Vec<(&str, &str)>) -> Self {
+        Self {
+            atom_nodes: atom_nodes.into_iter().map(String::from).collect(),
+            delimiter_tokens: delimiter_tokens
+                .into_iter()
+                .map(|(open, close)| (open.into(), close.into()))
+                .collect(),
+            comment_kinds: vec!["comment".to_owned()].into_iter().collect(),
+            container_kinds: HashSet::new(),
+            prefer_outer_delimiter: false,
+            doc_comment_prefixes: vec![],
+            language: None,
+            import_kinds: HashSet::new(),
+            import_list_kinds: HashSet::new(),
+            parenthesized_kinds: vec!["parenthesized_expression".to_owned()]
+                .into_iter()
+                .collect(),
+            binding_kinds: HashSet::new(),
+            normalizations: SyntaxNormalizations::default(),
+            ignore_queries: vec![],
+        }
+    }
+}
+
+/// Should nested slider correction prefer the inner or outer
+/// delimiter by default?
+pub fn prefer_outer_delimiter(language: Language) -> bool {
+    use crate::parse::guess_language::Language::*;
+    match language {
+        // For Lisp family languages, we get the best result with the
+        // outer delimiter.
+        EmacsLisp | Clojure | CommonLisp | Janet | Racket | Newick => true,
+        // JSON and TOML are like Lisp: the outer delimiter in an array object
+        // is the most relevant.
+        Json | Toml | Hcl => true,
+        // It's probably the case that outer delimiters
+        // (e.g. grouping) are used more frequently than inner
+        // delimiters in SQL. `(foo = 1 OR bar = 2)` is more likely
+        // than `foo(1)`.
+        Sql => true,
+        // For everything else, prefer the inner delimiter. These
+        // languages have syntax like `foo(bar)` or `foo[bar]` where
+        // the inner delimiter is more relevant.
+        _ => false,
+    }
+}
+
+/// Which doc comments contain code in the language itself?
+pub fn doc_comment_prefixes(language: Language) -> Vec<String> {
+    match language {
+        // Doc comments on the next item, and on the enclosing item.
+        Language::Rust => vec!["///".into(), "//!".into()],
+        _ => vec![],
+    }
+}
+
+/// Which nodes are import statements?
+pub fn import_kinds(language: Language) -> HashSet<String> {
+    use crate::parse::guess_language::Language::*;
+    let kinds: &[&str] = match language {
+        CSharp => &["using_directive"],
+        Go | Java => &["import_declaration"],
+        JavaScript | TypeScript | Tsx => &["import_statement"],
+        Python => &["import_statement", "import_from_statement"],
+        Rust => &["use_declaration"],
+        _ => &[],
+    };
+    kinds.iter().map(|kind| kind.to_string()).collect()
+}
+
+/// Which nodes are lists of imported names?
+pub fn import_list_kinds(language: Language) -> HashSet<String> {
+    use crate::parse::guess_language::Language::*;
+    let kinds: &[&str] = match language {
+        Go => &["import_spec_list"],
+        JavaScript | TypeScript | Tsx => &["named_imports"],
+        Rust => &["use_list"],
+        _ => &[],
+    };
+    kinds.iter().map(|kind| kind.to_string()).collect()
+}
+
+/// Where do identifiers declare locals? See
+/// `LanguagePolicy::binding_kinds`.
+pub fn binding_kinds(language: Language) -> HashSet<String> {
+    use crate::parse::guess_language::Language::*;
+    let kinds: &[&str] = match language {
+        C | CPlusPlus => &[
+            "array_declarator.declarator",
+            "declaration.declarator",
+            "init_declarator.declarator",
+            "parameter_declaration.declarator",
+            "pointer_declarator.declarator",
+            "reference_declarator",
+        ],
+        Go => &[
+            "parameter_declaration.name",
+            "range_clause.left",
+            "short_var_declaration.left",
+            "var_spec.name",
+            "variadic_parameter_declaration.name",
+        ],
+        Java => &[
+            "catch_formal_parameter.name",
+            "enhanced_for_statement.name",
+            "formal_parameter.name",
+            "inferred_parameters",
+            "lambda_expression.parameters",
+            "variable_declarator.name",
+        ],
+        JavaScript | TypeScript | Tsx => &[
+            "array_pattern",
+            "arrow_function.parameter",
+            "assignment_pattern.left",
+            "catch_clause.parameter",
+            "for_in_statement.left",
+            "formal_parameters",
+            "optional_parameter.pattern",
+            "pair_pattern.value",
+            "required_parameter.pattern",
+            "rest_pattern",
+            "variable_declarator.name",
+        ],
+        Python => &[
+            "assignment.left",
+            "default_parameter.name",
+            "dictionary_splat_pattern",
+            "for_statement.left",
+            "lambda_parameters",
+            "list_splat_pattern",
+            "parameters",
+            "typed_default_parameter.name",
+            "typed_parameter",
+        ],
+        Rust => &[
+            "closure_parameters",
+            "field_pattern.pattern",
+            "for_expression.pattern",
+            "let_condition.pattern",
+            "let_declaration.pattern",
+            "match_pattern",
+            "mut_pattern",
+            "parameter.pattern",
+            "ref_pattern",
+            "slice_pattern",
+            "tuple_pattern",
+            "tuple_struct_pattern",
+        ],
+        _ => &[],
+    };
+    kinds.iter().map(|kind| kind.to_string()).collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PolicyError(pub String);
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    AtomNodes,
+    DelimiterTokens,
+    CommentKinds,
+    ContainerKinds,
+    PreferOuterDelimiter,
+    DocCommentPrefixes,
+    ImportKinds,
+    ImportListKinds,
+    ParenthesizedKinds,
+    BindingKinds,
+}
+
+/// A single `name = values` or `name += values` line of a policy
+/// file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Override {
+    language: Language,
+    field: Field,
+    append: bool,
+    values: Vec<String>,
+}
+
+/// Changes to the default policies, read from a policy file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyOverrides {
+    overrides: Vec<Override>,
+}
+
+impl PolicyOverrides {
+    /// Parse a policy file. Each section starts with `[Language]`,
+    /// followed by `name = values` lines. Blank lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_file_content(src: &str) -> Result<Self, PolicyError> {
+        let mut overrides = vec![];
+        let mut language = None;
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parsed = parse_line(line, &mut language)
+                .map_err(|PolicyError(e)| PolicyError(format!("line {}: {}", i + 1, e)))?;
+            overrides.extend(parsed);
+        }
+        Ok(Self { overrides })
+    }
+
+    /// Apply the overrides for `language` to `policy`, in the order
+    /// they appear in the file.
+    pub fn apply(&self, language: Language, policy: &mut LanguagePolicy) {
+        for o in self.overrides.iter().filter(|o| o.language == language) {
+            match o.field {
+                Field::AtomNodes => set_kinds(&mut policy.atom_nodes, o),
+                Field::CommentKinds => set_kinds(&mut policy.comment_kinds, o),
+                Field::ContainerKinds => set_kinds(&mut policy.container_kinds, o),
+                Field::ImportKinds => set_kinds(&mut policy.import_kinds, o),
+                Field::ImportListKinds => set_kinds(&mut policy.import_list_kinds, o),
+                Field::ParenthesizedKinds => set_kinds(&mut policy.parenthesized_kinds, o),
+                Field::BindingKinds => set_kinds(&mut policy.binding_kinds, o),
+                Field::DelimiterTokens => {
+                    if !o.append {
+                        policy.delimiter_tokens.clear();
+                    }
+                    policy.delimiter_tokens.extend(
+                        o.values
+                            .chunks(2)
+                            .map(|pair| (pair[0].clone(), pair[1].clone())),
+                    );
+                }
+                Field::PreferOuterDelimiter => {
+                    policy.prefer_outer_delimiter = o.values[0] == "true";
+                }
+                Field::DocCommentPrefixes => {
+                    if !o.append {
+                        policy.doc_comment_prefixes.clear();
+                    }
+                    policy.doc_comment_prefixes.extend(o.values.iter().cloned());
+                }
+            }
+        }
+    }
+}
+
+fn set_kinds(kinds: &mut HashSet<String>, o: &Override) {
+    if !o.append {
+        kinds.clear();
+    }
+    kinds.extend(o.values.iter().cloned());
+}
+
+/// Parse a section header or a setting. Returns the override, if the
+/// line is a setting.
+fn parse_line(
+    line: &str,
+    language: &mut Option<Language>,
+) -> Result<Option<Override>, PolicyError> {
+    if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        match guess::from_language_name(name.trim()) {
+            Some(lang) => {
+                *language = Some(lang);
+                return Ok(None);
+            }
+            None => return Err(PolicyError(format!("Unknown language: {}", name.trim()))),
+        }
+    }
+
+    let (name, append, values) = if let Some((name, values)) = line.split_once("+=") {
+        (name, true, values)
+    } else if let Some((name, values)) = line.split_once('=') {
+        (name, false, values)
+    } else {
+        return Err(PolicyError(format!(
+            "Expected NAME = VALUES or [Language], got: {}",
+            line
+        )));
+    };
+    let name = name.trim();
+    let values: Vec<String> = values.split_whitespace().map(String::from).collect();
+
+    let language = language
+        .ok_or_else(|| PolicyError(format!("{} must be inside a [Language] section", name)))?;
+    let field = match name {
+        "atom_nodes" => Field::AtomNodes,
+        "delimiter_tokens" => Field::DelimiterTokens,
+        "comment_kinds" => Field::CommentKinds,
+        "container_kinds" => Field::ContainerKinds,
+        "prefer_outer_delimiter" => Field::PreferOuterDelimiter,
+        "doc_comment_prefixes" => Field::DocCommentPrefixes,
+        "import_kinds" => Field::ImportKinds,
+        "import_list_kinds" => Field::ImportListKinds,
+        "parenthesized_kinds" => Field::ParenthesizedKinds,
+        "binding_kinds" => Field::BindingKinds,
+        _ => return Err(PolicyError(format!("Unknown setting: {}", name))),
+    };
+
+    match field {
+        Field::DelimiterTokens if values.len() % 2 != 0 => {
+            return Err(PolicyError(format!(
+                "Expected pairs of open and close tokens for {}",
+                name
+            )));
+        }
+        Field::PreferOuterDelimiter
+            if append || !matches!(&values[..], [v] if v == "true" || v == "false") =>
+        {
+            return Err(PolicyError(format!("Expected {} = true or false", name)));
+        }
+        _ => {}
+    }
+
+    Ok(Some(Override {
+        language,
+        field,
+        append,
+        values,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_overrides() {
+        let overrides = PolicyOverrides::from_file_content(
+            "# Tune Rust.
+[Rust]
+atom_nodes += token_tree
+container_kinds = function_item impl_item
+delimiter_tokens = ( )
+prefer_outer_delimiter = true
+doc_comment_prefixes = ///
+import_kinds += extern_crate_declaration
+binding_kinds += self_parameter
+
+[Go]
+atom_nodes = raw_string_literal
+",
+        )
+        .unwrap();
+
+        let mut policy =
+            LanguagePolicy::new(["string_literal"].into(), vec![("{", "}"), ("(", ")")]);
+        overrides.apply(Language::Rust, &mut policy);
+
+        let atom_nodes: HashSet<String> =
+            ["string_literal".to_owned(), "token_tree".to_owned()].into();
+        assert_eq!(policy.atom_nodes, atom_nodes);
+        assert!(policy.container_kinds.contains("impl_item"));
+        assert_eq!(
+            policy.delimiter_tokens,
+            vec![("(".to_owned(), ")".to_owned())]
+        );
+        assert!(policy.prefer_outer_delimiter);
+        assert_eq!(policy.doc_comment_prefixes, vec!["///".to_owned()]);
+        assert!(policy.comment_kinds.contains("comment"));
+        assert!(policy.import_kinds.contains("extern_crate_declaration"));
+        assert!(policy.binding_kinds.contains("self_parameter"));
+        assert!(policy
+            .parenthesized_kinds
+            .contains("parenthesized_expression"));
+    }
+
+    #[test]
+    fn test_overrides_errors() {
+        assert_eq!(
+            PolicyOverrides::from_file_content("atom_nodes = string"),
+            Err(PolicyError(
+                "line 1: atom_nodes must be inside a [Language] section".into()
+            ))
+        );
+        assert_eq!(
+            PolicyOverrides::from_file_content("[Klingon]"),
+            Err(PolicyError("line 1: Unknown language: Klingon".into()))
+        );
+        assert_eq!(
+            PolicyOverrides::from_file_content("[Rust]\n\ndelimiter_tokens = ( ) [").map(|_| ()),
+            Err(PolicyError(
+                "line 3: Expected pairs of open and close tokens for delimiter_tokens".into()
+            ))
+        );
+        assert_eq!(
+            PolicyOverrides::from_file_content("[Rust]\nprefer_outer_delimiter += true")
+                .map(|_| ()),
+            Err(PolicyError(
+                "line 2: Expected prefer_outer_delimiter = true or false".into()
+            ))
+        );
+    }
+}
@@ -10,6 +10,7 @@ use crate::{
     diff::changes::{ChangeKind::*, ChangeMap},
     diff::myers_diff,
     lines::{is_all_whitespace, LineNumber, NewlinePositions},
+    parse::normalize::{normalize_side, LocalPositions, NormalizeOptions, NormalizedContent},
     positions::SingleLineSpan,
 };
 use Syntax::*;
@@ -30,6 +31,13 @@ impl<'a> fmt::Debug for ChangeKind<'a> {
                     rhs_node.id()
                 )
             }
+            ReplacedAtom(lhs_node, rhs_node) => {
+                format!(
+                    "ReplacedAtom(lhs ID: {}, rhs ID: {})",
+                    lhs_node.id(),
+                    rhs_node.id()
+                )
+            }
             Novel => "Novel".to_owned(),
         };
         f.write_str(&desc)
@@ -401,19 +409,43 @@ pub fn comment_positions<'a>(nodes: &[&'a Syntax<'a>]) -> Vec<SingleLineSpan> {
 
 /// Initialise all the fields in `SyntaxInfo`.
 pub fn init_all_info<'a>(lhs_roots: &[&'a Syntax<'a>], rhs_roots: &[&'a Syntax<'a>]) {
-    init_info(lhs_roots, rhs_roots);
+    init_all_info_with(lhs_roots, rhs_roots, None);
+}
+
+/// Initialise all the fields in `SyntaxInfo`. If `normalize` is set,
+/// atoms are compared by their normalised content, so renamed locals
+/// and changed literals are considered unchanged. The locals are the
+/// identifiers on the LHS and RHS that refer to locals.
+pub fn init_all_info_with<'a>(
+    lhs_roots: &[&'a Syntax<'a>],
+    rhs_roots: &[&'a Syntax<'a>],
+    normalize: Option<(&NormalizeOptions, &LocalPositions, &LocalPositions)>,
+) {
+    init_info(lhs_roots, rhs_roots, normalize);
     init_next_prev(lhs_roots);
     init_next_prev(rhs_roots);
 }
 
-fn init_info<'a>(lhs_roots: &[&'a Syntax<'a>], rhs_roots: &[&'a Syntax<'a>]) {
+fn init_info<'a>(
+    lhs_roots: &[&'a Syntax<'a>],
+    rhs_roots: &[&'a Syntax<'a>],
+    normalize: Option<(&NormalizeOptions, &LocalPositions, &LocalPositions)>,
+) {
     let mut id = NonZeroU32::new(1).unwrap();
     init_info_on_side(lhs_roots, &mut id);
     init_info_on_side(rhs_roots, &mut id);
 
+    let (lhs_normalized, rhs_normalized) = match normalize {
+        Some((options, lhs_locals, rhs_locals)) => (
+            normalize_side(lhs_roots, options, lhs_locals),
+            normalize_side(rhs_roots, options, rhs_locals),
+        ),
+        None => (NormalizedContent::default(), NormalizedContent::default()),
+    };
+
     let mut existing = HashMap::new();
-    set_content_id(lhs_roots, &mut existing);
-    set_content_id(rhs_roots, &mut existing);
+    set_content_id(lhs_roots, &lhs_normalized, &mut existing);
+    set_content_id(rhs_roots, &rhs_normalized, &mut existing);
 
     set_content_is_unique(lhs_roots);
     set_content_is_unique(rhs_roots);
@@ -421,7 +453,11 @@ fn init_info<'a>(lhs_roots: &[&'a Syntax<'a>], rhs_roots: &[&'a Syntax<'a>]) {
 
 type ContentKey = (Option<String>, Option<String>, Vec<u32>, bool, bool);
 
-fn set_content_id(nodes: &[&Syntax], existing: &mut HashMap<ContentKey, u32>) {
+fn set_content_id(
+    nodes: &[&Syntax],
+    normalized: &NormalizedContent,
+    existing: &mut HashMap<ContentKey, u32>,
+) {
     for node in nodes {
         let key: ContentKey = match node {
             List {
@@ -431,7 +467,7 @@ fn set_content_id(nodes: &[&Syntax], existing: &mut HashMap<ContentKey, u32>) {
                 ..
             } => {
                 // Recurse first, so children all have their content_id set.
-                set_content_id(children, existing);
+                set_content_id(children, normalized, existing);
 
                 let children_content_ids: Vec<_> =
                     children.iter().map(|c| c.info().content_id.get()).collect();
@@ -450,7 +486,9 @@ fn set_content_id(nodes: &[&Syntax], existing: &mut HashMap<ContentKey, u32>) {
                 ..
             } => {
                 let is_comment = *highlight == AtomKind::Comment;
-                let clean_content = if is_comment && content.lines().count() > 1 {
+                let clean_content = if let Some(normalized_content) = normalized.get(&node.id()) {
+                    normalized_content.clone()
+                } else if is_comment && content.lines().count() > 1 {
                     content
                         .lines()
                         .map(|l| l.trim_start())
@@ -682,6 +720,12 @@ pub enum MatchKind {
     NovelWord {
         highlight: TokenKind,
     },
+    /// An atom that replaced a similar atom on the other side.
+    Replaced {
+        highlight: TokenKind,
+        self_pos: Vec<SingleLineSpan>,
+        opposite_pos: Vec<SingleLineSpan>,
+    },
     Ignored {
         highlight: TokenKind,
     },
@@ -691,7 +735,10 @@ impl MatchKind {
     pub fn is_novel(&self) -> bool {
         matches!(
             self,
-            MatchKind::Novel { .. } | MatchKind::NovelWord { .. } | MatchKind::NovelLinePart { .. }
+            MatchKind::Novel { .. }
+                | MatchKind::NovelWord { .. }
+                | MatchKind::NovelLinePart { .. }
+                | MatchKind::Replaced { .. }
         )
     }
 }
@@ -703,6 +750,9 @@ pub struct MatchedPos {
 }
 
 // get novel_nodes in a Vec<MatchedPos>
+/// The novel positions on `_line`. This includes the novel words of
+/// line diffs, so sections that fell back to a line diff still produce
+/// vectors, but not the unchanged words on their novel lines.
 pub fn get_novel_nodes<'a>(positions: &'a Vec<MatchedPos>, _line: &LineNumber) -> Vec<&'a MatchedPos>{
     let mut res = vec![];
     for (_, po) in positions.iter().enumerate(){
@@ -710,7 +760,7 @@ pub fn get_novel_nodes<'a>(positions: &'a Vec<MatchedPos>, _line: &LineNumber) -
             MatchKind::UnchangedToken {
                 ..
             } => {},
-            MatchKind::Novel { highlight } =>{
+            MatchKind::Novel { highlight } | MatchKind::NovelWord { highlight } | MatchKind::Replaced { highlight, .. } =>{
                 if (po.pos.line == *_line){
                     // println!("po = {:?}", po);
                     // println!("kind = {:?}", po.kind);
@@ -893,6 +943,24 @@ impl MatchedPos {
                 }
                 res
             }
+            ReplacedAtom(_, opposite) => {
+                let opposite_pos = match opposite {
+                    List { .. } => unreachable!(),
+                    Atom { position, .. } => position.clone(),
+                };
+
+                let kind = MatchKind::Replaced {
+                    highlight,
+                    self_pos: pos.to_vec(),
+                    opposite_pos,
+                };
+                pos.iter()
+                    .map(|line_pos| Self {
+                        kind: kind.clone(),
+                        pos: *line_pos,
+                    })
+                    .collect()
+            }
             Novel => {
                 let kind = MatchKind::Novel { highlight };
                 // Create a MatchedPos for every line that `pos` covers.
@@ -1132,6 +1200,120 @@ mod tests {
         assert_eq!(x, y);
     }
 
+    #[test]
+    fn test_normalized_atoms_equal() {
+        let pos = vec![SingleLineSpan {
+            line: 0.into(),
+            start_col: 2,
+            end_col: 3,
+        }];
+
+        let arena = Arena::new();
+
+        let x = Syntax::new_atom(&arena, pos.clone(), "foo", AtomKind::Normal);
+        let y = Syntax::new_atom(&arena, pos, "bar", AtomKind::Normal);
+        let locals = vec![(0.into(), 2)].into_iter().collect();
+        init_all_info_with(
+            &[x],
+            &[y],
+            Some((&NormalizeOptions::default(), &locals, &locals)),
+        );
+
+        assert_eq!(x, y);
+    }
+
+    #[test]
+    fn test_normalized_non_locals_differ() {
+        let pos = vec![SingleLineSpan {
+            line: 0.into(),
+            start_col: 2,
+            end_col: 3,
+        }];
+
+        let arena = Arena::new();
+
+        let x = Syntax::new_atom(&arena, pos.clone(), "foo", AtomKind::Normal);
+        let y = Syntax::new_atom(&arena, pos, "bar", AtomKind::Normal);
+        let locals = LocalPositions::default();
+        init_all_info_with(
+            &[x],
+            &[y],
+            Some((&NormalizeOptions::default(), &locals, &locals)),
+        );
+
+        assert_ne!(x, y);
+    }
+
+    #[test]
+    fn test_normalized_renames_per_root() {
+        let pos = vec![SingleLineSpan {
+            line: 0.into(),
+            start_col: 2,
+            end_col: 3,
+        }];
+
+        let arena = Arena::new();
+
+        let lhs_first = Syntax::new_list(
+            &arena,
+            "{",
+            pos.clone(),
+            vec![Syntax::new_atom(&arena, pos.clone(), "x", AtomKind::Normal)],
+            "}",
+            pos.clone(),
+        );
+        let lhs_second = Syntax::new_atom(&arena, pos.clone(), "y", AtomKind::Normal);
+        // A new local in the first root doesn't renumber the second.
+        let rhs_first = Syntax::new_list(
+            &arena,
+            "{",
+            pos.clone(),
+            vec![
+                Syntax::new_atom(&arena, pos.clone(), "n", AtomKind::Normal),
+                Syntax::new_atom(&arena, pos.clone(), "x", AtomKind::Normal),
+            ],
+            "}",
+            pos.clone(),
+        );
+        let rhs_second = Syntax::new_atom(&arena, pos, "z", AtomKind::Normal);
+        let locals = vec![(0.into(), 2)].into_iter().collect();
+        init_all_info_with(
+            &[lhs_first, lhs_second],
+            &[rhs_first, rhs_second],
+            Some((&NormalizeOptions::default(), &locals, &locals)),
+        );
+
+        assert_ne!(lhs_first, rhs_first);
+        assert_eq!(lhs_second, rhs_second);
+    }
+
+    #[test]
+    fn test_get_novel_nodes_line_fallback() {
+        let span = |start_col| SingleLineSpan {
+            line: 0.into(),
+            start_col,
+            end_col: start_col + 1,
+        };
+        let highlight = TokenKind::Atom(AtomKind::Normal);
+        let positions = vec![
+            MatchedPos {
+                kind: MatchKind::NovelWord { highlight },
+                pos: span(0),
+            },
+            MatchedPos {
+                kind: MatchKind::NovelLinePart {
+                    highlight,
+                    self_pos: span(2),
+                    opposite_pos: vec![span(2)],
+                },
+                pos: span(2),
+            },
+        ];
+
+        let novels = get_novel_nodes(&positions, &0.into());
+        assert_eq!(novels, vec![&positions[0]]);
+    }
+
     #[test]
     fn test_split_comment_words_basic() {
         let content = "abc";
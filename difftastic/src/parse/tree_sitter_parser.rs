@@ -2,6 +2,7 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::rc::Rc;
 
 use crate::options::DiffOptions;
 use crate::parse::guess_language as guess;
@@ -10,7 +11,13 @@ use typed_arena::Arena;
 
 use crate::{
     lines::NewlinePositions,
-    parse::syntax::{AtomKind, Syntax},
+    parse::{
+        loaded_grammars,
+        normalize::LocalPositions,
+        policy::{self, LanguagePolicy},
+        syntax::{AtomKind, MatchKind, Syntax, TokenKind},
+    },
+    positions::SingleLineSpan,
 };
 
 use super::syntax;
@@ -21,7 +28,8 @@ use super::syntax::MatchedPos;
 /// JavaScript). This contains how to identify such nodes, and what
 /// languages we should parse them as.
 ///
-/// Note that we don't support sub-languages more than one layer deep.
+/// Sub-languages can contain sub-languages in turn, up to
+/// `MAX_SUB_LANGUAGE_DEPTH` deep.
 pub struct TreeSitterSubLanguage {
     /// How to identify a node. The query must contain exactly one
     /// capture group (the name is arbitrary).
@@ -29,6 +37,17 @@ pub struct TreeSitterSubLanguage {
 
     /// What language parser to use (refers in turn to a TreeSitterConfig).
     parse_as: guess::Language,
+
+    /// If set, the node is parsed followed by this text, and it's only
+    /// treated as `parse_as` if that has no parse errors. Otherwise
+    /// the node is kept as it is.
+    ///
+    /// This is for nodes that are only sometimes valid code, such as
+    /// Rust macro bodies. `vec![1, 2]` has the body `[1, 2]`, which
+    /// is an array expression when followed by `;`, whereas the body
+    /// of `foo!(=> x)` isn't valid Rust. Nodes that the policy treats
+    /// as atoms aren't parsed.
+    fallible_suffix: Option<&'static str>,
 }
 
 /// Configuration for a tree-sitter parser.
@@ -36,87 +55,129 @@ pub struct TreeSitterConfig {
     /// The tree-sitter language parser.
     pub language: ts::Language,
 
-    /// Tree-sitter nodes that we treat as indivisible atoms.
-    ///
-    /// This is particularly useful for strings, as some grammars use
-    /// several nodes for a single string literal. We don't want to
-    /// say e.g. the closing string delimiter moved, as it's confusing
-    /// and not well-balanced syntax.
-    ///
-    /// This is also useful for when tree-sitter nodes don't include
-    /// all the children in the source. This is known limitation of
-    /// tree-sitter, and occurs more often for complex string syntax.
-    /// <https://github.com/tree-sitter/tree-sitter/issues/1156>
-    atom_nodes: HashSet<&'static str>,
-
-    /// We want to consider delimiter tokens as part of lists, not
-    /// standalone atoms. Tree-sitter includes delimiter tokens, so
-    /// mark which token pairs we consider to be delimiters.
-    delimiter_tokens: Vec<(&'static str, &'static str)>,
+    /// Atom nodes, delimiters and other per-language knowledge,
+    /// possibly adjusted by a policy file.
+    pub policy: LanguagePolicy,
 
     /// Tree-sitter query used for syntax highlighting this
     /// language.
     highlight_query: ts::Query,
 
+    /// Tree-sitter query whose `@ignore` captures are left out of the
+    /// syntax nodes, built from `LanguagePolicy::ignore_queries`.
+    ignore_query: Option<ts::Query>,
+
     /// Sub-languages in use, if any.
     sub_languages: Vec<TreeSitterSubLanguage>,
 }
 
 extern "C" {
+    #[cfg(feature = "lang-ada")]
     fn tree_sitter_ada() -> ts::Language;
+    #[cfg(feature = "lang-bash")]
     fn tree_sitter_bash() -> ts::Language;
+    #[cfg(feature = "lang-c")]
     fn tree_sitter_c() -> ts::Language;
+    #[cfg(feature = "lang-c-sharp")]
     fn tree_sitter_c_sharp() -> ts::Language;
+    #[cfg(feature = "lang-clojure")]
     fn tree_sitter_clojure() -> ts::Language;
+    #[cfg(feature = "lang-cmake")]
     fn tree_sitter_cmake() -> ts::Language;
+    #[cfg(feature = "lang-cpp")]
     fn tree_sitter_cpp() -> ts::Language;
+    #[cfg(feature = "lang-commonlisp")]
     fn tree_sitter_commonlisp() -> ts::Language;
+    #[cfg(feature = "lang-css")]
     fn tree_sitter_css() -> ts::Language;
+    #[cfg(feature = "lang-dart")]
     fn tree_sitter_dart() -> ts::Language;
+    #[cfg(feature = "lang-elisp")]
     fn tree_sitter_elisp() -> ts::Language;
+    #[cfg(feature = "lang-elixir")]
     fn tree_sitter_elixir() -> ts::Language;
+    #[cfg(feature = "lang-elm")]
     fn tree_sitter_elm() -> ts::Language;
+    #[cfg(feature = "lang-elvish")]
     fn tree_sitter_elvish() -> ts::Language;
+    #[cfg(feature = "lang-erlang")]
     fn tree_sitter_erlang() -> ts::Language;
+    #[cfg(feature = "lang-gleam")]
     fn tree_sitter_gleam() -> ts::Language;
+    #[cfg(feature = "lang-go")]
     fn tree_sitter_go() -> ts::Language;
+    #[cfg(feature = "lang-hare")]
     fn tree_sitter_hare() -> ts::Language;
+    #[cfg(feature = "lang-hack")]
     fn tree_sitter_hack() -> ts::Language;
+    #[cfg(feature = "lang-haskell")]
     fn tree_sitter_haskell() -> ts::Language;
+    #[cfg(feature = "lang-hcl")]
     fn tree_sitter_hcl() -> ts::Language;
+    #[cfg(feature = "lang-html")]
     fn tree_sitter_html() -> ts::Language;
+    #[cfg(feature = "lang-janet")]
     fn tree_sitter_janet_simple() -> ts::Language;
+    #[cfg(feature = "lang-java")]
     fn tree_sitter_java() -> ts::Language;
+    #[cfg(feature = "lang-javascript")]
     fn tree_sitter_javascript() -> ts::Language;
+    #[cfg(feature = "lang-json")]
     fn tree_sitter_json() -> ts::Language;
+    #[cfg(feature = "lang-julia")]
     fn tree_sitter_julia() -> ts::Language;
+    #[cfg(feature = "lang-kotlin")]
     fn tree_sitter_kotlin() -> ts::Language;
+    #[cfg(feature = "lang-lua")]
     fn tree_sitter_lua() -> ts::Language;
+    #[cfg(feature = "lang-make")]
     fn tree_sitter_make() -> ts::Language;
+    #[cfg(feature = "lang-newick")]
     fn tree_sitter_newick() -> ts::Language;
+    #[cfg(feature = "lang-nix")]
     fn tree_sitter_nix() -> ts::Language;
+    #[cfg(feature = "lang-ocaml")]
     fn tree_sitter_ocaml() -> ts::Language;
+    #[cfg(feature = "lang-ocaml")]
     fn tree_sitter_ocaml_interface() -> ts::Language;
+    #[cfg(feature = "lang-pascal")]
     fn tree_sitter_pascal() -> ts::Language;
+    #[cfg(feature = "lang-php")]
     fn tree_sitter_php() -> ts::Language;
+    #[cfg(feature = "lang-perl")]
     fn tree_sitter_perl() -> ts::Language;
+    #[cfg(feature = "lang-python")]
     fn tree_sitter_python() -> ts::Language;
+    #[cfg(feature = "lang-qml")]
     fn tree_sitter_qmljs() -> ts::Language;
+    #[cfg(feature = "lang-r")]
     fn tree_sitter_r() -> ts::Language;
+    #[cfg(feature = "lang-racket")]
     fn tree_sitter_racket() -> ts::Language;
+    #[cfg(feature = "lang-ruby")]
     fn tree_sitter_ruby() -> ts::Language;
+    #[cfg(feature = "lang-rust")]
     fn tree_sitter_rust() -> ts::Language;
+    #[cfg(feature = "lang-scala")]
     fn tree_sitter_scala() -> ts::Language;
+    #[cfg(feature = "lang-sql")]
     fn tree_sitter_sql() -> ts::Language;
+    #[cfg(feature = "lang-swift")]
     fn tree_sitter_swift() -> ts::Language;
+    #[cfg(feature = "lang-toml")]
     fn tree_sitter_toml() -> ts::Language;
+    #[cfg(feature = "lang-typescript")]
     fn tree_sitter_tsx() -> ts::Language;
+    #[cfg(feature = "lang-typescript")]
     fn tree_sitter_typescript() -> ts::Language;
+    #[cfg(feature = "lang-yaml")]
     fn tree_sitter_yaml() -> ts::Language;
+    #[cfg(feature = "lang-zig")]
     fn tree_sitter_zig() -> ts::Language;
 }
 
 // TODO: begin/end and object/end.
+#[cfg(feature = "lang-ocaml")]
 const OCAML_ATOM_NODES: [&str; 6] = [
     "character",
     "string",
@@ -128,61 +189,98 @@ const OCAML_ATOM_NODES: [&str; 6] = [
 
 pub fn from_language(language: guess::Language) -> TreeSitterConfig {
     use guess::Language::*;
-    match language {
+    let mut config = match language {
+        Loaded(id) => {
+            let grammar = loaded_grammars::get(id);
+            TreeSitterConfig {
+                language: grammar.language,
+                policy: LanguagePolicy::new(
+                    grammar
+                        .atom_nodes
+                        .iter()
+                        .map(|kind| kind.as_str())
+                        .collect(),
+                    grammar
+                        .delimiter_tokens
+                        .iter()
+                        .map(|(open, close)| (open.as_str(), close.as_str()))
+                        .collect(),
+                ),
+                highlight_query: ts::Query::new(grammar.language, &grammar.highlights)
+                    .expect("Highlights were checked when the grammar was loaded"),
+                ignore_query: None,
+                sub_languages: vec![],
+            }
+        }
+        #[cfg(feature = "lang-ada")]
         Ada => {
             let language = unsafe { tree_sitter_ada() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_literal", "character_literal"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string_literal", "character_literal"]
+                        .into_iter()
+                        .collect(),
+                    vec![("(", ")"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/ada.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-bash")]
         Bash => {
             let language = unsafe { tree_sitter_bash() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "raw_string", "heredoc_body"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "raw_string", "heredoc_body"]
+                        .into_iter()
+                        .collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/bash.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-c")]
         C => {
             let language = unsafe { tree_sitter_c() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_literal", "char_literal"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string_literal", "char_literal"].into_iter().collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/c.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-cpp")]
         CPlusPlus => {
             let language = unsafe { tree_sitter_cpp() };
             TreeSitterConfig {
                 language,
                 // The C++ grammar extends the C grammar, so the node
                 // names are generally the same.
-                atom_nodes: vec!["string_literal", "char_literal"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    vec!["string_literal", "char_literal"].into_iter().collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     concat!(
@@ -191,587 +289,724 @@ pub fn from_language(language: guess::Language) -> TreeSitterConfig {
                     ),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-clojure")]
         Clojure => {
             let language = unsafe { tree_sitter_clojure() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["kwd_lit"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]")]
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec!["kwd_lit"].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/clojure.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-cmake")]
         CMake => {
             let language = unsafe { tree_sitter_cmake() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["argument"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")")].into_iter().collect(),
+                policy: LanguagePolicy::new(
+                    vec!["argument"].into_iter().collect(),
+                    vec![("(", ")")].into_iter().collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/cmake.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-commonlisp")]
         CommonLisp => {
             let language = unsafe { tree_sitter_commonlisp() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["str_lit", "char_lit"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec!["str_lit", "char_lit"].into_iter().collect(),
+                    vec![("(", ")")],
+                ),
                 highlight_query: ts::Query::new(language, "").unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-c-sharp")]
         CSharp => {
             let language = unsafe { tree_sitter_c_sharp() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![
-                    "string_literal",
-                    "verbatim_string_literal",
-                    "character_literal",
-                ]
-                .into_iter()
-                .collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec![
+                        "string_literal",
+                        "verbatim_string_literal",
+                        "character_literal",
+                    ]
+                    .into_iter()
+                    .collect(),
+                    vec![("{", "}"), ("(", ")")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/c-sharp.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-css")]
         Css => {
             let language = unsafe { tree_sitter_css() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["integer_value", "float_value", "color_value"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec!["integer_value", "float_value", "color_value"]
+                        .into_iter()
+                        .collect(),
+                    vec![("{", "}"), ("(", ")")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/css.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-dart")]
         Dart => {
             let language = unsafe { tree_sitter_dart() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_literal", "script_tag"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    vec!["string_literal", "script_tag"].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/dart.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-elisp")]
         EmacsLisp => {
             let language = unsafe { tree_sitter_elisp() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]")]
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec![].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/elisp.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-elixir")]
         Elixir => {
             let language = unsafe { tree_sitter_elixir() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "heredoc"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("do", "end")]
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec!["string", "heredoc"].into_iter().collect(),
+                    vec![("(", ")"), ("{", "}"), ("do", "end")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/elixir.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-elm")]
         Elm => {
             let language = unsafe { tree_sitter_elm() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_constant_expr"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("[", "]"), ("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec!["string_constant_expr"].into_iter().collect(),
+                    vec![("{", "}"), ("[", "]"), ("(", ")")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/elm.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-elvish")]
         Elvish => {
             let language = unsafe { tree_sitter_elvish() };
             TreeSitterConfig {
                 language,
-                atom_nodes: [].into(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("|", "|")],
+                policy: LanguagePolicy::new(
+                    [].into(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("|", "|")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/elvish.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-erlang")]
         Erlang => {
             let language = unsafe { tree_sitter_erlang() };
             TreeSitterConfig {
                 language,
-                atom_nodes: [].into(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new([].into(), vec![("(", ")"), ("{", "}"), ("[", "]")]),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/erlang.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-gleam")]
         Gleam => {
             let language = unsafe { tree_sitter_gleam() };
             TreeSitterConfig {
                 language,
-                atom_nodes: ["string"].into(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    ["string"].into(),
+                    vec![("(", ")"), ("[", "]"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/gleam.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-go")]
         Go => {
             let language = unsafe { tree_sitter_go() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["interpreted_string_literal", "raw_string_literal"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("{", "}"), ("[", "]"), ("(", ")")]
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec!["interpreted_string_literal", "raw_string_literal"]
+                        .into_iter()
+                        .collect(),
+                    vec![("{", "}"), ("[", "]"), ("(", ")")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/go.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-hack")]
         Hack => {
             let language = unsafe { tree_sitter_hack() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["prefixed_string", "heredoc"].into_iter().collect(),
-                delimiter_tokens: vec![("[", "]"), ("(", ")"), ("<", ">"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    vec!["prefixed_string", "heredoc"].into_iter().collect(),
+                    vec![("[", "]"), ("(", ")"), ("<", ">"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/hack.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-hare")]
         Hare => {
             let language = unsafe { tree_sitter_hare() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_constant", "rune_constant"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("[", "]"), ("(", ")"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    vec!["string_constant", "rune_constant"]
+                        .into_iter()
+                        .collect(),
+                    vec![("[", "]"), ("(", ")"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/hare.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-haskell")]
         Haskell => {
             let language = unsafe { tree_sitter_haskell() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![].into_iter().collect(),
-                delimiter_tokens: vec![("[", "]"), ("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec![].into_iter().collect(),
+                    vec![("[", "]"), ("(", ")")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/haskell.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-hcl")]
         Hcl => {
             let language = unsafe { tree_sitter_hcl() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_lit", "heredoc_template"].into_iter().collect(),
-                delimiter_tokens: vec![
-                    ("[", "]"),
-                    ("(", ")"),
-                    ("{", "}"),
-                    ("%{", "}"),
-                    ("%{~", "~}"),
-                    ("${", "}"),
-                ],
+                policy: LanguagePolicy::new(
+                    vec!["string_lit", "heredoc_template"].into_iter().collect(),
+                    vec![
+                        ("[", "]"),
+                        ("(", ")"),
+                        ("{", "}"),
+                        ("%{", "}"),
+                        ("%{~", "~}"),
+                        ("${", "}"),
+                    ],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/hcl.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-html")]
         Html => {
             let language = unsafe { tree_sitter_html() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![
-                    "doctype",
-                    "quoted_attribute_value",
-                    "comment",
-                    "raw_text",
-                    "tag_name",
-                    "text",
-                ]
-                .into_iter()
-                .collect(),
-                delimiter_tokens: vec![("<", ">"), ("<!", ">"), ("<!--", "-->")]
+                policy: LanguagePolicy::new(
+                    vec![
+                        "doctype",
+                        "quoted_attribute_value",
+                        "comment",
+                        "raw_text",
+                        "tag_name",
+                        "text",
+                    ]
                     .into_iter()
                     .collect(),
+                    vec![("<", ">"), ("<!", ">"), ("<!--", "-->")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/html.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![
                     TreeSitterSubLanguage {
                         query: ts::Query::new(language, "(style_element (raw_text) @contents)")
                             .unwrap(),
                         parse_as: Css,
+                        fallible_suffix: None,
                     },
                     TreeSitterSubLanguage {
                         query: ts::Query::new(language, "(script_element (raw_text) @contents)")
                             .unwrap(),
                         parse_as: JavaScript,
+                        fallible_suffix: None,
                     },
                 ],
             }
         }
+        #[cfg(feature = "lang-janet")]
         Janet => {
             let language = unsafe { tree_sitter_janet_simple() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![].into_iter().collect(),
-                delimiter_tokens: vec![
-                    ("@{", "}"),
-                    ("@(", ")"),
-                    ("@[", "]"),
-                    ("{", "}"),
-                    ("(", ")"),
-                    ("[", "]"),
-                ]
-                .into_iter()
-                .collect(),
+                policy: LanguagePolicy::new(
+                    vec![].into_iter().collect(),
+                    vec![
+                        ("@{", "}"),
+                        ("@(", ")"),
+                        ("@[", "]"),
+                        ("{", "}"),
+                        ("(", ")"),
+                        ("[", "]"),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/janet_simple.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-java")]
         Java => {
             let language = unsafe { tree_sitter_java() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_literal"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string_literal"].into_iter().collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/java.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-javascript")]
         JavaScript | Jsx => {
             let language = unsafe { tree_sitter_javascript() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "template_string", "regex"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![
-                    ("[", "]"),
-                    ("(", ")"),
-                    ("{", "}"),
-                    // We may see a standalone < token in an expression
-                    // like 1 < 2, but we should never see both a < and a
-                    // > at the same level in JSX.
-                    ("<", ">"),
-                ],
+                policy: LanguagePolicy::new(
+                    vec!["string", "template_string", "regex"]
+                        .into_iter()
+                        .collect(),
+                    vec![
+                        ("[", "]"),
+                        ("(", ")"),
+                        ("{", "}"),
+                        // We may see a standalone < token in an expression
+                        // like 1 < 2, but we should never see both a < and a
+                        // > at the same level in JSX.
+                        ("<", ">"),
+                    ],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/javascript.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-json")]
         Json => {
             let language = unsafe { tree_sitter_json() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string"].into_iter().collect(),
+                    vec![("{", "}"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/json.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-julia")]
         Julia => {
             let language = unsafe { tree_sitter_julia() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![
-                    "string_literal",
-                    "prefixed_string_literal",
-                    "command_literal",
-                    "character_literal",
-                ]
-                .into_iter()
-                .collect(),
-                delimiter_tokens: vec![("{", "}"), ("[", "]"), ("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec![
+                        "string_literal",
+                        "prefixed_string_literal",
+                        "command_literal",
+                        "character_literal",
+                    ]
+                    .into_iter()
+                    .collect(),
+                    vec![("{", "}"), ("[", "]"), ("(", ")")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/julia.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-kotlin")]
         Kotlin => {
             let language = unsafe { tree_sitter_kotlin() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["line_string_literal", "character_literal"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]"), ("<", ">")]
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec!["line_string_literal", "character_literal"]
+                        .into_iter()
+                        .collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]"), ("<", ">")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/kotlin.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-lua")]
         Lua => {
             let language = unsafe { tree_sitter_lua() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]")]
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec!["string"].into_iter().collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]")]
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/lua.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-make")]
         Make => {
             let language = unsafe { tree_sitter_make() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["shell_text", "text"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")")].into_iter().collect(),
+                policy: LanguagePolicy::new(
+                    vec!["shell_text", "text"].into_iter().collect(),
+                    vec![("(", ")")].into_iter().collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/make.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![TreeSitterSubLanguage {
                     query: ts::Query::new(language, "(shell_function (shell_command) @contents)")
                         .unwrap(),
                     parse_as: Bash,
+                    fallible_suffix: None,
                 }],
             }
         }
+        #[cfg(feature = "lang-newick")]
         Newick => {
             let language = unsafe { tree_sitter_newick() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")")],
+                policy: LanguagePolicy::new(vec![].into_iter().collect(), vec![("(", ")")]),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/newick.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-nix")]
         Nix => {
             let language = unsafe { tree_sitter_nix() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string_expression", "indented_string_expression"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("{", "}"), ("[", "]")].into_iter().collect(),
+                policy: LanguagePolicy::new(
+                    vec!["string_expression", "indented_string_expression"]
+                        .into_iter()
+                        .collect(),
+                    vec![("{", "}"), ("[", "]")].into_iter().collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/nix.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-ocaml")]
         OCaml => {
             let language = unsafe { tree_sitter_ocaml() };
             TreeSitterConfig {
                 language,
-                atom_nodes: OCAML_ATOM_NODES.iter().copied().collect(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    OCAML_ATOM_NODES.iter().copied().collect(),
+                    vec![("(", ")"), ("[", "]"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/ocaml.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-ocaml")]
         OCamlInterface => {
             let language = unsafe { tree_sitter_ocaml_interface() };
             TreeSitterConfig {
                 language,
-                atom_nodes: OCAML_ATOM_NODES.iter().copied().collect(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    OCAML_ATOM_NODES.iter().copied().collect(),
+                    vec![("(", ")"), ("[", "]"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/ocaml.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-pascal")]
         Pascal => {
             let language = unsafe { tree_sitter_pascal() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec![].into_iter().collect(),
+                    vec![("(", ")"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/pascal.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-perl")]
         Perl => {
             let language = unsafe { tree_sitter_perl() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![
-                    "string_single_quoted",
-                    "string_double_quoted",
-                    "comments",
-                    "command_qx_quoted",
-                    "patter_matcher_m",
-                    "regex_pattern_qr",
-                    "transliteration_tr_or_y",
-                    "substitution_pattern_s",
-                ]
-                .into_iter()
-                .collect(),
-                delimiter_tokens: vec![("(", ")"), ("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec![
+                        "string_single_quoted",
+                        "string_double_quoted",
+                        "comments",
+                        "command_qx_quoted",
+                        "patter_matcher_m",
+                        "regex_pattern_qr",
+                        "transliteration_tr_or_y",
+                        "substitution_pattern_s",
+                    ]
+                    .into_iter()
+                    .collect(),
+                    vec![("(", ")"), ("{", "}"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/perl.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-php")]
         Php => {
             let language = unsafe { tree_sitter_php() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "encapsed_string"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "encapsed_string"].into_iter().collect(),
+                    vec![("(", ")"), ("[", "]"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/php.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-python")]
         Python => {
             let language = unsafe { tree_sitter_python() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")"), ("[", "]"), ("{", "}")],
+                policy: LanguagePolicy::new(
+                    vec!["string"].into_iter().collect(),
+                    vec![("(", ")"), ("[", "]"), ("{", "}")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/python.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-qml")]
         Qml => {
             let language = unsafe { tree_sitter_qmljs() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "template_string", "regex"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "template_string", "regex"]
+                        .into_iter()
+                        .collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     concat!(
@@ -781,139 +1016,182 @@ pub fn from_language(language: guess::Language) -> TreeSitterConfig {
                     ),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-r")]
         R => {
             let language = unsafe { tree_sitter_r() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "special"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "special"].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/r.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-racket")]
         Racket => {
             let language = unsafe { tree_sitter_racket() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "byte_string", "regex", "here_string"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "byte_string", "regex", "here_string"]
+                        .into_iter()
+                        .collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/racket.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-ruby")]
         Ruby => {
             let language = unsafe { tree_sitter_ruby() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "heredoc_body", "regex"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![
-                    ("{", "}"),
-                    ("(", ")"),
-                    ("[", "]"),
-                    ("|", "|"),
-                    ("def", "end"),
-                    ("begin", "end"),
-                    ("class", "end"),
-                ],
+                policy: LanguagePolicy::new(
+                    vec!["string", "heredoc_body", "regex"]
+                        .into_iter()
+                        .collect(),
+                    vec![
+                        ("{", "}"),
+                        ("(", ")"),
+                        ("[", "]"),
+                        ("|", "|"),
+                        ("def", "end"),
+                        ("begin", "end"),
+                        ("class", "end"),
+                    ],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/ruby.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-rust")]
         Rust => {
             let language = unsafe { tree_sitter_rust() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["char_literal", "string_literal"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("|", "|"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    vec!["char_literal", "string_literal"].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("|", "|"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/rust.scm"),
                 )
                 .unwrap(),
-                sub_languages: vec![],
+                ignore_query: None,
+                // Tree-sitter leaves macro bodies as flat token
+                // trees, so parse them as Rust when we can.
+                sub_languages: vec![TreeSitterSubLanguage {
+                    query: ts::Query::new(language, "(macro_invocation (token_tree) @contents)")
+                        .unwrap(),
+                    parse_as: Rust,
+                    fallible_suffix: Some(";"),
+                }],
             }
         }
+        #[cfg(feature = "lang-scala")]
         Scala => {
             let language = unsafe { tree_sitter_scala() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "template_string"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "template_string"].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/scala.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-sql")]
         Sql => {
             let language = unsafe { tree_sitter_sql() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "identifier"].into_iter().collect(),
-                delimiter_tokens: vec![("(", ")")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "identifier"].into_iter().collect(),
+                    vec![("(", ")")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/sql.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-swift")]
         Swift => {
             let language = unsafe { tree_sitter_swift() };
             TreeSitterConfig {
                 language,
-                atom_nodes: ["line_string_literal"].into(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    ["line_string_literal"].into(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/swift.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-toml")]
         Toml => {
             let language = unsafe { tree_sitter_toml() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "quoted_key"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("[", "]")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "quoted_key"].into_iter().collect(),
+                    vec![("{", "}"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/toml.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-typescript")]
         Tsx => {
             let language = unsafe { tree_sitter_tsx() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "template_string"].into_iter().collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "template_string"].into_iter().collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     concat!(
@@ -922,17 +1200,21 @@ pub fn from_language(language: guess::Language) -> TreeSitterConfig {
                     ),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-typescript")]
         TypeScript => {
             let language = unsafe { tree_sitter_typescript() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["string", "template_string", "regex", "predefined_type"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                policy: LanguagePolicy::new(
+                    vec!["string", "template_string", "regex", "predefined_type"]
+                        .into_iter()
+                        .collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]"), ("<", ">")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     concat!(
@@ -941,49 +1223,158 @@ pub fn from_language(language: guess::Language) -> TreeSitterConfig {
                     ),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-yaml")]
         Yaml => {
             let language = unsafe { tree_sitter_yaml() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec![
-                    "string_scalar",
-                    "double_quote_scalar",
-                    "single_quote_scalar",
-                    "block_scalar",
-                ]
-                .into_iter()
-                .collect(),
-                delimiter_tokens: (vec![("{", "}"), ("(", ")"), ("[", "]")]),
+                policy: LanguagePolicy::new(
+                    vec![
+                        "string_scalar",
+                        "double_quote_scalar",
+                        "single_quote_scalar",
+                        "block_scalar",
+                    ]
+                    .into_iter()
+                    .collect(),
+                    vec![("{", "}"), ("(", ")"), ("[", "]")],
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/yaml.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[cfg(feature = "lang-zig")]
         Zig => {
             let language = unsafe { tree_sitter_zig() };
             TreeSitterConfig {
                 language,
-                atom_nodes: vec!["STRINGLITERALSINGLE", "BUILTINIDENTIFIER"]
-                    .into_iter()
-                    .collect(),
-                delimiter_tokens: (vec![("{", "}"), ("[", "]"), ("(", ")")])
-                    .into_iter()
-                    .collect(),
+                policy: LanguagePolicy::new(
+                    vec!["STRINGLITERALSINGLE", "BUILTINIDENTIFIER"]
+                        .into_iter()
+                        .collect(),
+                    (vec![("{", "}"), ("[", "]"), ("(", ")")])
+                        .into_iter()
+                        .collect(),
+                ),
                 highlight_query: ts::Query::new(
                     language,
                     include_str!("../../vendored_parsers/highlights/zig.scm"),
                 )
                 .unwrap(),
+                ignore_query: None,
                 sub_languages: vec![],
             }
         }
+        #[allow(unreachable_patterns)]
+        _ => panic!(
+            "Support for {} was not compiled in",
+            guess::language_name(language)
+        ),
+    };
+    config.policy.prefer_outer_delimiter = policy::prefer_outer_delimiter(language);
+    config.policy.doc_comment_prefixes = policy::doc_comment_prefixes(language);
+    config.policy.language = Some(language);
+    config.policy.import_kinds = policy::import_kinds(language);
+    config.policy.import_list_kinds = policy::import_list_kinds(language);
+    config.policy.binding_kinds = policy::binding_kinds(language);
+    config
+}
+
+/// The configuration for `language`, with its policy adjusted by the
+/// policy file and `--normalize-syntax`. Sub-languages use their
+/// default policies, apart from the normalizations and ignore
+/// queries.
+pub fn from_language_with_options(
+    language: guess::Language,
+    diff_options: &DiffOptions,
+) -> TreeSitterConfig {
+    let mut config = from_language(language);
+    diff_options
+        .policy_overrides
+        .apply(language, &mut config.policy);
+    config.policy.normalizations = diff_options.syntax_normalizations;
+    config.policy.ignore_queries = diff_options.ignore_queries.clone();
+    config.ignore_query = compile_ignore_query(
+        config.language,
+        &ignore_queries_for(language, &config.policy.ignore_queries),
+    )
+    .expect("Ignore queries were checked when parsing arguments");
+    config
+}
+
+/// The queries in `queries` that are for `language`.
+fn ignore_queries_for(
+    language: guess::Language,
+    queries: &[(guess::Language, String)],
+) -> Vec<String> {
+    queries
+        .iter()
+        .filter(|(query_language, _)| *query_language == language)
+        .map(|(_, query)| query.clone())
+        .collect()
+}
+
+/// Compile `queries` for `language` as a single query. Returns `None`
+/// if there are no queries.
+fn compile_ignore_query(
+    language: ts::Language,
+    queries: &[String],
+) -> Result<Option<ts::Query>, String> {
+    if queries.is_empty() {
+        return Ok(None);
+    }
+
+    let query = ts::Query::new(language, &queries.join("\n")).map_err(|e| e.to_string())?;
+    if query.capture_index_for_name("ignore").is_none() {
+        return Err("The query doesn't capture any nodes with @ignore".into());
+    }
+    Ok(Some(query))
+}
+
+/// Check that `query` is a valid ignore query for `language`.
+pub fn check_ignore_query(language: guess::Language, query: &str) -> Result<(), String> {
+    if !guess::is_compiled_in(language) {
+        return Err(format!(
+            "Support for {} was not compiled in",
+            guess::language_name(language)
+        ));
     }
+    let config = from_language(language);
+    compile_ignore_query(config.language, &[query.to_owned()]).map(|_| ())
+}
+
+/// The nodes of `tree` captured with `@ignore` by the ignore query.
+pub fn ignored_nodes<'t>(
+    tree: &'t tree_sitter::Tree,
+    src: &str,
+    config: &TreeSitterConfig,
+) -> Vec<ts::Node<'t>> {
+    let query = match &config.ignore_query {
+        Some(query) => query,
+        None => return vec![],
+    };
+    let ignore_capture = query.capture_index_for_name("ignore");
+
+    let mut nodes = vec![];
+    let mut query_cursor = ts::QueryCursor::new();
+    for m in query_cursor.matches(query, tree.root_node(), src.as_bytes()) {
+        nodes.extend(
+            m.captures
+                .iter()
+                .filter(|c| Some(c.index) == ignore_capture)
+                .map(|c| c.node),
+        );
+    }
+    nodes
 }
 
 /// Parse `src` with tree-sitter.
@@ -1013,15 +1404,54 @@ pub fn to_tree_with_limit(
     Ok((to_tree(lhs_src, config), to_tree(rhs_src, config)))
 }
 
+/// How deeply sub-languages can nest. For example, a Rust macro call
+/// inside the body of another macro call is two deep.
+const MAX_SUB_LANGUAGE_DEPTH: usize = 8;
+
+/// A node parsed as another language.
+pub struct SubTree {
+    pub tree: tree_sitter::Tree,
+    /// The configuration for `tree`, or `None` if it's the outer
+    /// tree's language.
+    config: Option<Rc<TreeSitterConfig>>,
+    highlights: HighlightedNodeIds,
+    /// The IDs of the nodes in the outer tree parsed as `tree`. This
+    /// is a single node, except for code blocks in doc comments,
+    /// which have a comment node for each line.
+    nodes: Vec<usize>,
+    /// The byte range of the node in `tree` to convert, if it's not
+    /// the root.
+    root_range: Option<std::ops::Range<usize>>,
+    /// Nodes of `tree` parsed as other languages in turn.
+    pub subtrees: SubTrees,
+}
+
+/// Trees for nodes parsed as other languages, keyed by the ID of the
+/// node in the outer tree.
+pub type SubTrees = HashMap<usize, Rc<SubTree>>;
+
 /// Find any nodes that can be parsed as other languages (e.g. JavaScript embedded in HTML),
 /// and return a map of their node IDs mapped to parsed trees. Every time we see such a node,
 /// we will ignore it and recurse into the root node of the given tree instead.
-pub fn parse_subtrees(
+pub fn parse_subtrees(src: &str, config: &TreeSitterConfig, tree: &tree_sitter::Tree) -> SubTrees {
+    parse_nested_subtrees(src, config, tree, 1, &mut vec![])
+}
+
+/// Find the sub-languages in `tree`, and the sub-languages in their
+/// trees, until `MAX_SUB_LANGUAGE_DEPTH`. `configs` holds the
+/// sub-language configurations built so far, as building one compiles
+/// its queries.
+fn parse_nested_subtrees(
     src: &str,
     config: &TreeSitterConfig,
     tree: &tree_sitter::Tree,
-) -> HashMap<usize, (tree_sitter::Tree, TreeSitterConfig, HighlightedNodeIds)> {
+    depth: usize,
+    configs: &mut Vec<(guess::Language, Rc<TreeSitterConfig>)>,
+) -> SubTrees {
     let mut subtrees = HashMap::new();
+    if depth > MAX_SUB_LANGUAGE_DEPTH {
+        return subtrees;
+    }
 
     for language in &config.sub_languages {
         let mut query_cursor = tree_sitter::QueryCursor::new();
@@ -1030,26 +1460,284 @@ pub fn parse_subtrees(
             if node.byte_range().is_empty() {
                 continue;
             }
+            if language.fallible_suffix.is_some() && config.policy.atom_nodes.contains(node.kind())
+            {
+                continue;
+            }
 
-            let subconfig = from_language(language.parse_as);
+            let subconfig = sub_language_config(config, language.parse_as, configs);
             let mut parser = ts::Parser::new();
             parser
                 .set_language(subconfig.language)
                 .expect("Incompatible tree-sitter version");
-            parser
-                .set_included_ranges(&[node.range()])
-                .expect("Incompatible tree-sitter version");
 
-            let tree = parser.parse(src, None).unwrap();
-            let sub_highlights = tree_highlights(&tree, src, &subconfig);
+            let (tree, root_range) = match language.fallible_suffix {
+                Some(suffix) => match parse_with_suffix(&mut parser, src, node, suffix) {
+                    Some(tree) => (tree, Some(node.byte_range())),
+                    None => continue,
+                },
+                None => {
+                    parser
+                        .set_included_ranges(&[node.range()])
+                        .expect("Incompatible tree-sitter version");
+                    (parser.parse(src, None).unwrap(), None)
+                }
+            };
+            let highlights = tree_highlights(&tree, src, &subconfig);
+            let nested = parse_nested_subtrees(src, &subconfig, &tree, depth + 1, configs);
+
+            subtrees.insert(
+                node.id(),
+                Rc::new(SubTree {
+                    tree,
+                    config: Some(subconfig),
+                    highlights,
+                    nodes: vec![node.id()],
+                    root_range,
+                    subtrees: nested,
+                }),
+            );
+        }
+    }
 
-            subtrees.insert(node.id(), (tree, subconfig, sub_highlights));
+    for (ranges, nodes) in doc_code_blocks(src, &config.policy, tree) {
+        let mut parser = ts::Parser::new();
+        parser
+            .set_language(config.language)
+            .expect("Incompatible tree-sitter version");
+        parser
+            .set_included_ranges(&ranges)
+            .expect("Incompatible tree-sitter version");
+        let tree = parser.parse(src, None).unwrap();
+        // Keep examples that don't parse, e.g. pseudo-code, as
+        // comments.
+        if tree.root_node().has_error() {
+            continue;
+        }
+
+        let highlights = tree_highlights(&tree, src, config);
+        let nested = parse_nested_subtrees(src, config, &tree, depth + 1, configs);
+        let subtree = Rc::new(SubTree {
+            tree,
+            config: None,
+            highlights,
+            nodes: nodes.iter().map(|node| node.id()).collect(),
+            root_range: None,
+            subtrees: nested,
+        });
+        for node in nodes {
+            subtrees.insert(node.id(), subtree.clone());
         }
     }
 
     subtrees
 }
 
+/// Is this the info string of a code block in the host language,
+/// e.g. ```` ```swift ```` or ```` ```no_run ````? Code blocks in doc
+/// comments are in the host language unless they say otherwise.
+/// Rustdoc attributes such as `no_run` are accepted for any language.
+fn is_doc_code_info(info: &str, host: Option<guess::Language>) -> bool {
+    info.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|attr| !attr.is_empty())
+        .all(|attr| {
+            let is_host = host.is_some() && guess::from_language_alias(attr) == host;
+            is_host
+                || matches!(
+                    attr,
+                    "ignore"
+                        | "should_panic"
+                        | "no_run"
+                        | "compile_fail"
+                        | "test_harness"
+                        | "allow_fail"
+                        | "standalone"
+                )
+                || attr.starts_with("edition")
+                || attr.starts_with("ignore-")
+        })
+}
+
+/// Where we are in a doc comment, when looking for code blocks.
+enum Fence<'t> {
+    Outside,
+    /// In a code block in the host language, with the range of code
+    /// and the comment node of each line so far.
+    Code(Vec<ts::Range>, Vec<ts::Node<'t>>),
+    /// In a code block in another language.
+    Other,
+}
+
+/// Find the fenced code blocks in doc comments, which are line
+/// comments starting with one of the policy's `doc_comment_prefixes`.
+/// Returns the ranges of code in each block, leaving out the comment
+/// prefix on each line, and the comment nodes it spans.
+fn doc_code_blocks<'t>(
+    src: &str,
+    policy: &LanguagePolicy,
+    tree: &'t tree_sitter::Tree,
+) -> Vec<(Vec<ts::Range>, Vec<ts::Node<'t>>)> {
+    let prefixes = &policy.doc_comment_prefixes;
+    let mut blocks = vec![];
+    if prefixes.is_empty() {
+        return blocks;
+    }
+
+    let mut fence = Fence::Outside;
+    let mut previous: Option<ts::Node> = None;
+    let mut cursor = tree.walk();
+    'walk: loop {
+        let node = cursor.node();
+        let text = &src[node.start_byte()..node.end_byte()];
+        let content = prefixes
+            .iter()
+            .find_map(|prefix| text.strip_prefix(prefix.as_str()))
+            .filter(|_| node.child_count() == 0);
+
+        if let Some(content) = content {
+            // A doc comment is a run of comments on consecutive lines.
+            let continues = previous.map_or(false, |previous| {
+                previous.next_sibling() == Some(node)
+                    && previous.end_position().row + 1 == node.start_position().row
+            });
+            if !continues {
+                fence = Fence::Outside;
+            }
+            previous = Some(node);
+
+            let content = content.strip_prefix(' ').unwrap_or(content);
+            if let Some(info) = content.trim_start().strip_prefix("```") {
+                fence = match fence {
+                    Fence::Outside if is_doc_code_info(info, policy.language) => {
+                        Fence::Code(vec![], vec![])
+                    }
+                    Fence::Outside => Fence::Other,
+                    Fence::Code(ranges, nodes) => {
+                        if !nodes.is_empty() {
+                            blocks.push((ranges, nodes));
+                        }
+                        Fence::Outside
+                    }
+                    Fence::Other => Fence::Outside,
+                };
+            } else if let Fence::Code(ranges, nodes) = &mut fence {
+                // Rustdoc hides lines starting with `# `, but they're
+                // still part of the example.
+                let code = if content == "#" {
+                    ""
+                } else {
+                    content.strip_prefix("# ").unwrap_or(content)
+                };
+                let mut range = ts::Range {
+                    start_byte: node.end_byte() - code.len(),
+                    end_byte: node.end_byte(),
+                    start_point: ts::Point {
+                        row: node.end_position().row,
+                        column: node.end_position().column - code.len(),
+                    },
+                    end_point: node.end_position(),
+                };
+                if src.as_bytes().get(node.end_byte()) == Some(&b'\n') {
+                    range.end_byte += 1;
+                    range.end_point = ts::Point {
+                        row: range.end_point.row + 1,
+                        column: 0,
+                    };
+                }
+                if range.end_byte > range.start_byte {
+                    ranges.push(range);
+                }
+                nodes.push(node);
+            }
+        }
+
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'walk;
+            }
+        }
+    }
+
+    blocks
+}
+
+fn sub_language_config(
+    config: &TreeSitterConfig,
+    language: guess::Language,
+    configs: &mut Vec<(guess::Language, Rc<TreeSitterConfig>)>,
+) -> Rc<TreeSitterConfig> {
+    if let Some((_, subconfig)) = configs.iter().find(|(lang, _)| *lang == language) {
+        return subconfig.clone();
+    }
+
+    let mut subconfig = from_language(language);
+    // A language embedded in itself, such as Rust macro bodies, keeps
+    // any policy overrides.
+    if subconfig.language == config.language {
+        subconfig.policy = config.policy.clone();
+    } else {
+        subconfig.policy.normalizations = config.policy.normalizations;
+        subconfig.policy.ignore_queries = config.policy.ignore_queries.clone();
+    }
+    subconfig.ignore_query = compile_ignore_query(
+        subconfig.language,
+        &ignore_queries_for(language, &subconfig.policy.ignore_queries),
+    )
+    .expect("Ignore queries were checked when parsing arguments");
+    let subconfig = Rc::new(subconfig);
+    configs.push((language, subconfig.clone()));
+    subconfig
+}
+
+/// Parse the text of `node` followed by `suffix`. Returns the tree if
+/// it has no parse errors, and a single node spans all of `node`.
+fn parse_with_suffix(
+    parser: &mut ts::Parser,
+    src: &str,
+    node: ts::Node,
+    suffix: &str,
+) -> Option<tree_sitter::Tree> {
+    let range = node.range();
+    let suffix_range = ts::Range {
+        start_byte: range.end_byte,
+        end_byte: range.end_byte + suffix.len(),
+        start_point: range.end_point,
+        end_point: ts::Point {
+            row: range.end_point.row,
+            column: range.end_point.column + suffix.len(),
+        },
+    };
+    parser
+        .set_included_ranges(&[range, suffix_range])
+        .expect("Incompatible tree-sitter version");
+
+    // The parser only reads the included ranges, so it sees the text
+    // of the node immediately followed by the suffix.
+    let tree = parser.parse_with(
+        &mut |offset, _| {
+            if offset < range.end_byte {
+                &src.as_bytes()[offset..range.end_byte]
+            } else {
+                suffix
+                    .as_bytes()
+                    .get(offset - range.end_byte..)
+                    .unwrap_or(&[])
+            }
+        },
+        None,
+    )?;
+
+    let root = tree.root_node();
+    let reparsed = root.descendant_for_byte_range(range.start_byte, range.end_byte)?;
+    if root.has_error() || reparsed.byte_range() != node.byte_range() {
+        return None;
+    }
+    Some(tree)
+}
+
 /// Calculate which tree-sitter node IDs should have which syntax
 /// highlighting.
 fn tree_highlights(
@@ -1138,6 +1826,10 @@ fn tree_highlights(
         keyword_ids,
         string_ids,
         type_ids,
+        ignore_ids: ignored_nodes(tree, src, config)
+            .iter()
+            .map(|node| node.id())
+            .collect(),
     }
 }
 
@@ -1199,6 +1891,126 @@ pub fn comment_positions(
         .collect()
 }
 
+/// The positions of the tokens that `SyntaxNormalizations` or the
+/// ignore query leave out of the syntax nodes, such as trailing commas
+/// or generated code. These are ignored in the same way as comments
+/// with `--ignore-comments`.
+pub fn ignored_positions(
+    tree: &tree_sitter::Tree,
+    src: &str,
+    config: &TreeSitterConfig,
+) -> Vec<MatchedPos> {
+    let mut ignored = vec![];
+    if (config.policy.normalizations.is_empty() && config.policy.ignore_queries.is_empty())
+        || src.trim().is_empty()
+    {
+        return ignored;
+    }
+
+    let arena = Arena::new();
+    let subtrees = parse_subtrees(src, config, tree);
+    convert_toplevel(
+        tree,
+        src,
+        &arena,
+        config,
+        &subtrees,
+        false,
+        &mut |_| None,
+        &mut ignored,
+    );
+    ignored
+}
+
+/// The starts of the `identifier` nodes in `tree` that refer to
+/// locals, for `--normalize`.
+///
+/// A local is a name that an item (a child of the root) declares in a
+/// binding position, according to `LanguagePolicy::binding_kinds`. All
+/// the identifiers in that item with the same name are treated as the
+/// local, since we don't resolve scopes. Identifiers inside embedded
+/// languages and macro bodies aren't included.
+pub fn local_positions(
+    tree: &tree_sitter::Tree,
+    src: &str,
+    config: &TreeSitterConfig,
+) -> LocalPositions {
+    let binding_kinds = &config.policy.binding_kinds;
+    let mut res = LocalPositions::default();
+    if binding_kinds.is_empty() {
+        return res;
+    }
+
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let items: Vec<ts::Node> = if root.child_count() == 0 {
+        vec![root]
+    } else {
+        root.children(&mut cursor).collect()
+    };
+
+    for item in items {
+        let mut identifiers = vec![];
+        let mut bound = HashSet::new();
+
+        // The kind and field name of each ancestor of the current node
+        // within the item.
+        let mut ancestors: Vec<(&str, Option<&str>)> = vec![];
+        let mut cursor = item.walk();
+        loop {
+            let node = cursor.node();
+            let field = cursor.field_name();
+
+            if node.kind() == "identifier" {
+                let name = &src[node.start_byte()..node.end_byte()];
+                identifiers.push((node.start_position(), name));
+
+                let parent = ancestors.last();
+                let is_binding = match (parent, field) {
+                    (Some((parent_kind, _)), Some(field)) => {
+                        binding_kinds.contains(&format!("{}.{}", parent_kind, field))
+                    }
+                    (Some((parent_kind, parent_field)), None) => {
+                        binding_kinds.contains(*parent_kind)
+                            || match (ancestors.iter().rev().nth(1), parent_field) {
+                                (Some((grandparent_kind, _)), Some(parent_field)) => binding_kinds
+                                    .contains(&format!("{}.{}", grandparent_kind, parent_field)),
+                                _ => false,
+                            }
+                    }
+                    (None, _) => false,
+                };
+                if is_binding {
+                    bound.insert(name);
+                }
+            }
+
+            if cursor.goto_first_child() {
+                ancestors.push((node.kind(), field));
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    break;
+                }
+                ancestors.pop();
+            }
+            if ancestors.is_empty() && cursor.node() == item {
+                break;
+            }
+        }
+
+        res.extend(
+            identifiers
+                .into_iter()
+                .filter(|(_, name)| bound.contains(name))
+                .map(|(start, _)| ((start.row as u32).into(), start.column as u32)),
+        );
+    }
+
+    res
+}
+
 #[derive(Debug)]
 pub struct ExceededParseErrorLimit(pub usize);
 
@@ -1225,7 +2037,18 @@ pub fn to_syntax_with_limit<'a>(
         config,
         diff_options.ignore_comments,
     );
-    syntax::init_all_info(&lhs_nodes, &rhs_nodes);
+    match &diff_options.normalize {
+        Some(options) => {
+            let lhs_locals = local_positions(lhs_tree, lhs_src, config);
+            let rhs_locals = local_positions(rhs_tree, rhs_src, config);
+            syntax::init_all_info_with(
+                &lhs_nodes,
+                &rhs_nodes,
+                Some((options, &lhs_locals, &rhs_locals)),
+            );
+        }
+        None => syntax::init_all_info(&lhs_nodes, &rhs_nodes),
+    }
 
     let error_count = lhs_error_count + rhs_error_count;
     if error_count > diff_options.parse_error_limit {
@@ -1242,6 +2065,87 @@ pub fn to_syntax<'a>(
     config: &TreeSitterConfig,
     ignore_comments: bool,
 ) -> (Vec<&'a Syntax<'a>>, usize) {
+    let subtrees = parse_subtrees(src, config, tree);
+    let (toplevel, error_count) = to_syntax_with_reuse(
+        tree,
+        src,
+        arena,
+        config,
+        &subtrees,
+        ignore_comments,
+        &mut |_| None,
+    );
+    (toplevel_nodes(tree, config, toplevel), error_count)
+}
+
+/// The syntax nodes from `to_syntax_with_reuse`, without the children
+/// of the root that didn't produce one. When ignoring import order,
+/// runs of adjacent imports are sorted.
+pub fn toplevel_nodes<'a>(
+    tree: &tree_sitter::Tree,
+    config: &TreeSitterConfig,
+    toplevel: Vec<Option<&'a Syntax<'a>>>,
+) -> Vec<&'a Syntax<'a>> {
+    let root = tree.root_node();
+    let mut kinds = vec![];
+    let mut nodes = vec![];
+    // If the root has no children, `toplevel` is the root itself.
+    if root.child_count() == toplevel.len() {
+        let mut cursor = root.walk();
+        for (child, syntax) in root.children(&mut cursor).zip(toplevel) {
+            if let Some(syntax) = syntax {
+                kinds.push(child.kind());
+                nodes.push(syntax);
+            }
+        }
+        sort_imports(&config.policy, root.kind(), &kinds, &mut nodes);
+    } else {
+        nodes.extend(toplevel.into_iter().flatten());
+    }
+    nodes
+}
+
+/// Convert a tree-sitter tree to difftastic syntax nodes, returning
+/// the syntax node for each child of the root (if it produced one)
+/// and the number of parse errors. `subtrees` is from
+/// `parse_subtrees`.
+///
+/// `reuse` is called with each child of the root. If it returns
+/// `Some`, that is used instead of converting the child. Reused
+/// children must not contain parse errors, as they aren't counted.
+pub fn to_syntax_with_reuse<'a>(
+    tree: &tree_sitter::Tree,
+    src: &str,
+    arena: &'a Arena<Syntax<'a>>,
+    config: &TreeSitterConfig,
+    subtrees: &SubTrees,
+    ignore_comments: bool,
+    reuse: &mut dyn FnMut(ts::Node) -> Option<Option<&'a Syntax<'a>>>,
+) -> (Vec<Option<&'a Syntax<'a>>>, usize) {
+    convert_toplevel(
+        tree,
+        src,
+        arena,
+        config,
+        subtrees,
+        ignore_comments,
+        reuse,
+        &mut vec![],
+    )
+}
+
+/// `to_syntax_with_reuse`, also adding the positions of tokens left
+/// out by `SyntaxNormalizations` or the ignore query to `ignored`.
+fn convert_toplevel<'a>(
+    tree: &tree_sitter::Tree,
+    src: &str,
+    arena: &'a Arena<Syntax<'a>>,
+    config: &TreeSitterConfig,
+    subtrees: &SubTrees,
+    ignore_comments: bool,
+    reuse: &mut dyn FnMut(ts::Node) -> Option<Option<&'a Syntax<'a>>>,
+    ignored: &mut Vec<MatchedPos>,
+) -> (Vec<Option<&'a Syntax<'a>>>, usize) {
     // Don't return anything on an empty input. Most parsers return a
     // zero-width top-level AST node on empty files, which is
     // confusing and not useful for diffing.
@@ -1251,12 +2155,7 @@ pub fn to_syntax<'a>(
 
     let highlights = tree_highlights(tree, src, config);
 
-    // Parse sub-languages, if any, which will be used both for
-    // highlighting and for more precise Syntax nodes where applicable.
-    let subtrees = parse_subtrees(src, config, tree);
-
     let nl_pos = NewlinePositions::from(src);
-    // println!("{:?}", nl_pos);
     let mut cursor = tree.walk();
 
     let mut error_count: usize = 0;
@@ -1265,21 +2164,38 @@ pub fn to_syntax<'a>(
     }
 
     // The tree always has a single root, whereas we want nodes for
-    // each top level syntax item.
-    cursor.goto_first_child();
+    // each top level syntax item. If the root has no children, we
+    // convert the root itself.
+    let has_children = cursor.goto_first_child();
 
-    let nodes = all_syntaxes_from_cursor(
-        arena,
-        src,
-        &nl_pos,
-        &mut cursor,
-        &mut error_count,
-        config,
-        &highlights,
-        &subtrees,
-        ignore_comments,
-    );
-    (nodes, error_count)
+    let mut toplevel = vec![];
+    loop {
+        let reused = if has_children {
+            reuse(cursor.node())
+        } else {
+            None
+        };
+        toplevel.push(match reused {
+            Some(node) => node,
+            None => syntax_from_cursor(
+                arena,
+                src,
+                &nl_pos,
+                &mut cursor,
+                &mut error_count,
+                config,
+                &highlights,
+                subtrees,
+                ignore_comments,
+                ignored,
+            ),
+        });
+
+        if !cursor.goto_next_sibling() {
+            break;
+        }
+    }
+    (toplevel, error_count)
 }
 
 /// Parse `src` with tree-sitter and convert to difftastic Syntax.
@@ -1323,15 +2239,15 @@ fn child_tokens<'a>(src: &'a str, cursor: &mut ts::TreeCursor) -> Vec<Option<&'a
 fn find_delim_positions(
     src: &str,
     cursor: &mut ts::TreeCursor,
-    lang_delims: &[(&str, &str)],
+    lang_delims: &[(String, String)],
 ) -> Option<(usize, usize)> {
     let tokens = child_tokens(src, cursor);
 
     for (i, token) in tokens.iter().enumerate() {
         for (open_delim, close_delim) in lang_delims {
-            if *token == Some(open_delim) {
+            if *token == Some(open_delim.as_str()) {
                 for (j, token) in tokens.iter().skip(i + 1).enumerate() {
-                    if *token == Some(close_delim) {
+                    if *token == Some(close_delim.as_str()) {
                         return Some((i, i + 1 + j));
                     }
                 }
@@ -1347,44 +2263,8 @@ pub struct HighlightedNodeIds {
     comment_ids: HashSet<usize>,
     string_ids: HashSet<usize>,
     type_ids: HashSet<usize>,
-}
-
-/// Convert all the tree-sitter nodes at this level to difftastic
-/// syntax nodes.
-///
-/// `cursor` should be pointing at the first tree-sitter node in a level.
-fn all_syntaxes_from_cursor<'a>(
-    arena: &'a Arena<Syntax<'a>>,
-    src: &str,
-    nl_pos: &NewlinePositions,
-    cursor: &mut ts::TreeCursor,
-    error_count: &mut usize,
-    config: &TreeSitterConfig,
-    highlights: &HighlightedNodeIds,
-    subtrees: &HashMap<usize, (tree_sitter::Tree, TreeSitterConfig, HighlightedNodeIds)>,
-    ignore_comments: bool,
-) -> Vec<&'a Syntax<'a>> {
-    let mut result: Vec<&Syntax> = vec![];
-
-    loop {
-        result.extend(syntax_from_cursor(
-            arena,
-            src,
-            nl_pos,
-            cursor,
-            error_count,
-            config,
-            highlights,
-            subtrees,
-            ignore_comments,
-        ));
-
-        if !cursor.goto_next_sibling() {
-            break;
-        }
-    }
-
-    result
+    /// Nodes matched by the ignore query, which we leave out.
+    ignore_ids: HashSet<usize>,
 }
 
 /// Convert the tree-sitter node at `cursor` to a difftastic syntax
@@ -1397,36 +2277,113 @@ fn syntax_from_cursor<'a>(
     error_count: &mut usize,
     config: &TreeSitterConfig,
     highlights: &HighlightedNodeIds,
-    subtrees: &HashMap<usize, (tree_sitter::Tree, TreeSitterConfig, HighlightedNodeIds)>,
+    subtrees: &SubTrees,
     ignore_comments: bool,
+    ignored: &mut Vec<MatchedPos>,
 ) -> Option<&'a Syntax<'a>> {
     let node = cursor.node();
 
+    if highlights.ignore_ids.contains(&node.id()) {
+        ignore_token(nl_pos, node, TokenKind::Atom(AtomKind::Comment), ignored);
+        return None;
+    }
+
     // See if we should go into a sub-document instead (e.g. embedded JavaScript in HTML).
-    if let Some((subtree, subconfig, subhighlights)) = subtrees.get(&node.id()) {
-        let mut sub_cursor = subtree.walk();
+    if let Some(subtree) = subtrees.get(&node.id()) {
+        // A code block spanning several comment lines is converted
+        // with the first line.
+        if subtree.nodes[0] != node.id() {
+            return None;
+        }
+
+        let mut sub_root = subtree.tree.root_node();
+        if let Some(range) = &subtree.root_range {
+            sub_root = sub_root
+                .descendant_for_byte_range(range.start, range.end)
+                .unwrap();
+        }
+        let sub_config = subtree.config.as_deref().unwrap_or(config);
+        let mut sub_cursor = sub_root.walk();
+
+        // The parentheses in `foo!(x)` delimit the macro call, even
+        // though the body is re-parsed as the expression `(x)`.
+        if subtree.root_range.is_some() && is_redundant_parens(src, sub_root, &sub_config.policy) {
+            return Some(list_from_cursor(
+                arena,
+                src,
+                nl_pos,
+                &mut sub_cursor,
+                error_count,
+                sub_config,
+                &subtree.highlights,
+                &subtree.subtrees,
+                ignore_comments,
+                ignored,
+            ));
+        }
+
         return syntax_from_cursor(
             arena,
             src,
             nl_pos,
             &mut sub_cursor,
             error_count,
-            subconfig,
-            subhighlights,
-            &HashMap::new(),
+            sub_config,
+            &subtree.highlights,
+            &subtree.subtrees,
             ignore_comments,
+            ignored,
         );
     }
 
+    if is_redundant_parens(src, node, &config.policy) {
+        // Convert the expression inside, and ignore the parentheses.
+        cursor.goto_first_child();
+        ignore_token(nl_pos, cursor.node(), TokenKind::Delimiter, ignored);
+        cursor.goto_next_sibling();
+        let inner = syntax_from_cursor(
+            arena,
+            src,
+            nl_pos,
+            cursor,
+            error_count,
+            config,
+            highlights,
+            subtrees,
+            ignore_comments,
+            ignored,
+        );
+        cursor.goto_next_sibling();
+        ignore_token(nl_pos, cursor.node(), TokenKind::Delimiter, ignored);
+        cursor.goto_parent();
+        return inner;
+    }
+
     if node.is_error() {
         *error_count += 1;
 
         // Treat error nodes as atoms, even if they have children.
-        atom_from_cursor(arena, src, nl_pos, cursor, highlights, ignore_comments)
-    } else if config.atom_nodes.contains(node.kind()) {
+        atom_from_cursor(
+            arena,
+            src,
+            nl_pos,
+            cursor,
+            config,
+            highlights,
+            ignore_comments,
+        )
+    } else if config.policy.atom_nodes.contains(node.kind()) {
         // Treat nodes like string literals as atoms, regardless
         // of whether they have children.
-        atom_from_cursor(arena, src, nl_pos, cursor, highlights, ignore_comments)
+        atom_from_cursor(
+            arena,
+            src,
+            nl_pos,
+            cursor,
+            config,
+            highlights,
+            ignore_comments,
+        )
     } else if node.child_count() > 0 {
         Some(list_from_cursor(
             arena,
@@ -1438,9 +2395,18 @@ fn syntax_from_cursor<'a>(
             highlights,
             subtrees,
             ignore_comments,
+            ignored,
         ))
     } else {
-        atom_from_cursor(arena, src, nl_pos, cursor, highlights, ignore_comments)
+        atom_from_cursor(
+            arena,
+            src,
+            nl_pos,
+            cursor,
+            config,
+            highlights,
+            ignore_comments,
+        )
     }
 }
 
@@ -1454,8 +2420,9 @@ fn list_from_cursor<'a>(
     error_count: &mut usize,
     config: &TreeSitterConfig,
     highlights: &HighlightedNodeIds,
-    subtrees: &HashMap<usize, (tree_sitter::Tree, TreeSitterConfig, HighlightedNodeIds)>,
+    subtrees: &SubTrees,
     ignore_comments: bool,
+    ignored: &mut Vec<MatchedPos>,
 ) -> &'a Syntax<'a> {
     let root_node = cursor.node();
 
@@ -1473,11 +2440,17 @@ fn list_from_cursor<'a>(
     //     println!("{:?}", outer_close_position);
     // }
 
-    let (i, j) = match find_delim_positions(src, cursor, &config.delimiter_tokens) {
+    let (i, j) = match find_delim_positions(src, cursor, &config.policy.delimiter_tokens) {
         Some((i, j)) => (i as isize, j as isize),
         None => (-1, root_node.child_count() as isize),
     };
 
+    let trailing_separator = if i >= 0 {
+        trailing_separator(src, root_node, i as usize, j as usize, &config.policy)
+    } else {
+        None
+    };
+
     let mut inner_open_content = outer_open_content;
     let mut inner_open_position = outer_open_position.clone();
     let mut inner_close_content = outer_close_content;
@@ -1497,6 +2470,7 @@ fn list_from_cursor<'a>(
     // delimiters, so we can construct lists.
     let mut before_delim = vec![];
     let mut between_delim = vec![];
+    let mut between_kinds = vec![];
     let mut after_delim = vec![];
 
     let mut node_i = 0;
@@ -1514,12 +2488,15 @@ fn list_from_cursor<'a>(
                 highlights,
                 subtrees,
                 ignore_comments,
+                ignored,
             ));
         } else if node_i == i {
             inner_open_content = &src[node.start_byte()..node.end_byte()];
             inner_open_position = nl_pos.from_offsets(node.start_byte(), node.end_byte());
+        } else if trailing_separator == Some(node_i as usize) {
+            ignore_token(nl_pos, node, TokenKind::Atom(AtomKind::Normal), ignored);
         } else if node_i < j {
-            between_delim.extend(syntax_from_cursor(
+            if let Some(syntax) = syntax_from_cursor(
                 arena,
                 src,
                 nl_pos,
@@ -1529,7 +2506,11 @@ fn list_from_cursor<'a>(
                 highlights,
                 subtrees,
                 ignore_comments,
-            ));
+                ignored,
+            ) {
+                between_delim.push(syntax);
+                between_kinds.push(node.kind());
+            }
         } else if node_i == j {
             inner_close_content = &src[node.start_byte()..node.end_byte()];
             inner_close_position = nl_pos.from_offsets(node.start_byte(), node.end_byte());
@@ -1544,6 +2525,7 @@ fn list_from_cursor<'a>(
                 highlights,
                 subtrees,
                 ignore_comments,
+                ignored,
             ));
         }
 
@@ -1553,6 +2535,12 @@ fn list_from_cursor<'a>(
         node_i += 1;
     }
     cursor.goto_parent();
+    sort_imports(
+        &config.policy,
+        root_node.kind(),
+        &between_kinds,
+        &mut between_delim,
+    );
 
     let inner_list = Syntax::new_list(
         arena,
@@ -1588,12 +2576,190 @@ fn list_from_cursor<'a>(
     }
 }
 
+/// Ignore `node` when diffing, because a `SyntaxNormalization` or
+/// the ignore query left it out of the syntax nodes.
+fn ignore_token(
+    nl_pos: &NewlinePositions,
+    node: ts::Node,
+    highlight: TokenKind,
+    ignored: &mut Vec<MatchedPos>,
+) {
+    ignored.extend(
+        nl_pos
+            .from_offsets(node.start_byte(), node.end_byte())
+            .into_iter()
+            .map(|pos: SingleLineSpan| MatchedPos {
+                kind: MatchKind::Ignored { highlight },
+                pos,
+            }),
+    );
+}
+
+/// The index of the comma after the last item of the list `node`, if
+/// we're ignoring trailing separators. `open` and `close` are the
+/// indexes of the list's delimiters.
+///
+/// Lists with a single item are left alone, as a trailing comma
+/// often matters there: `(x,)` is a tuple but `(x)` isn't.
+fn trailing_separator(
+    src: &str,
+    node: ts::Node,
+    open: usize,
+    close: usize,
+    policy: &LanguagePolicy,
+) -> Option<usize> {
+    if !policy.normalizations.trailing_separators {
+        return None;
+    }
+
+    let is_comma = |i: usize| {
+        let child = node.child(i).unwrap();
+        child.child_count() == 0 && &src[child.start_byte()..child.end_byte()] == ","
+    };
+    // Skip any comments after the comma.
+    let last = (open + 1..close)
+        .rev()
+        .find(|i| !node.child(*i).unwrap().is_extra())?;
+    if is_comma(last) && (open + 1..last).any(is_comma) {
+        Some(last)
+    } else {
+        None
+    }
+}
+
+/// Can we ignore the parentheses of `node`? They're redundant if they
+/// only contain a simple expression, such as a name, literal or call,
+/// or if the enclosing statement doesn't need them, such as
+/// `return (x + y);`.
+///
+/// The condition of a control flow statement is never redundant, since
+/// `if (x)` or `while (x)` need their parentheses in C-like languages.
+fn is_redundant_parens(src: &str, node: ts::Node, policy: &LanguagePolicy) -> bool {
+    if !policy.normalizations.redundant_parens || !policy.parenthesized_kinds.contains(node.kind())
+    {
+        return false;
+    }
+    // Don't ignore comments inside the parentheses.
+    if node.child_count() != 3 {
+        return false;
+    }
+    let text = |i: usize| {
+        let child = node.child(i).unwrap();
+        &src[child.start_byte()..child.end_byte()]
+    };
+    if text(0) != "(" || text(2) != ")" {
+        return false;
+    }
+
+    let inner = node.child(1).unwrap();
+    let parent = match node.parent() {
+        Some(parent) => parent,
+        None => return true,
+    };
+    // `(x.f)()` calls a field, whereas `x.f()` calls a method.
+    if parent.kind().contains("call") && parent.child(0) == Some(node) {
+        return false;
+    }
+    let is_statement =
+        parent.kind().ends_with("_statement") || parent.kind() == "switch_expression";
+    if is_statement
+        && ["condition", "value"]
+            .iter()
+            .any(|field| parent.child_by_field_name(field) == Some(node))
+    {
+        return false;
+    }
+
+    let inner_kind = inner.kind();
+    let is_simple = inner.child_count() == 0
+        || inner_kind.ends_with("identifier")
+        || inner_kind.contains("literal")
+        || policy.parenthesized_kinds.contains(inner_kind)
+        || [
+            "call",
+            "call_expression",
+            "field_expression",
+            "index_expression",
+            "macro_invocation",
+            "member_expression",
+            "method_invocation",
+            "selector_expression",
+            "string",
+            "subscript_expression",
+        ]
+        .contains(&inner_kind);
+    let parent_kind = parent.kind();
+    let needs_no_parens = parent_kind.ends_with("_statement")
+        || ["let_declaration", "return_expression"].contains(&parent_kind);
+
+    is_simple || needs_no_parens
+}
+
+/// The text of `node` as a sort key, ignoring whitespace.
+fn sort_key(node: &Syntax, key: &mut String) {
+    match node {
+        Syntax::List {
+            open_content,
+            children,
+            close_content,
+            ..
+        } => {
+            key.push_str(open_content);
+            for child in children {
+                key.push(' ');
+                sort_key(child, key);
+            }
+            key.push(' ');
+            key.push_str(close_content);
+        }
+        Syntax::Atom { content, .. } => key.push_str(content),
+    }
+}
+
+/// If we're ignoring import order, sort the children of import lists,
+/// and runs of adjacent imports, by their text. `list_kind` is the
+/// tree-sitter kind of the parent of `nodes`, and `kinds` are the
+/// kinds of `nodes`.
+fn sort_imports<'a>(
+    policy: &LanguagePolicy,
+    list_kind: &str,
+    kinds: &[&str],
+    nodes: &mut [&'a Syntax<'a>],
+) {
+    if !policy.normalizations.import_order {
+        return;
+    }
+    let sort = |nodes: &mut [&'a Syntax<'a>]| {
+        nodes.sort_by_cached_key(|node| {
+            let mut key = String::new();
+            sort_key(node, &mut key);
+            key
+        })
+    };
+
+    if policy.import_list_kinds.contains(list_kind) {
+        sort(nodes);
+        return;
+    }
+
+    let mut start = 0;
+    while start < nodes.len() {
+        let len = kinds[start..]
+            .iter()
+            .take_while(|kind| policy.import_kinds.contains(**kind))
+            .count();
+        sort(&mut nodes[start..start + len]);
+        start += len.max(1);
+    }
+}
+
 /// Convert the tree-sitter node at `cursor` to a difftastic atom.
 fn atom_from_cursor<'a>(
     arena: &'a Arena<Syntax<'a>>,
     src: &str,
     nl_pos: &NewlinePositions,
     cursor: &mut ts::TreeCursor,
+    config: &TreeSitterConfig,
     highlights: &HighlightedNodeIds,
     ignore_comments: bool,
 ) -> Option<&'a Syntax<'a>> {
@@ -1624,13 +2790,13 @@ fn atom_from_cursor<'a>(
     let highlight = if node.is_error() {
         AtomKind::TreeSitterError
     } else if node.is_extra()
-        || node.kind() == "comment"
+        || config.policy.comment_kinds.contains(node.kind())
         || highlights.comment_ids.contains(&node.id())
     {
         // 'extra' nodes in tree-sitter are comments. Most parsers use
         // 'comment' as their comment node name, but if they don't we
         // can still detect comments by looking at their syntax
-        // highlighting.
+        // highlighting, or the language policy.
 
         if ignore_comments {
             return None;
@@ -1653,6 +2819,17 @@ fn atom_from_cursor<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        diff::{
+            changes::ChangeMap,
+            cost_model::CostModel,
+            dijkstra::SearchAlgorithm,
+            sections::{self, FallbackMode},
+            unchanged,
+        },
+        options::DEFAULT_GRAPH_LIMIT,
+        parse::normalize::SyntaxNormalizations,
+    };
 
     /// Simple smoke test for tree-sitter parsing. Having a test also
     /// ensures that this file has its coverage measured.
@@ -1714,4 +2891,241 @@ mod tests {
             }
         };
     }
+
+    #[test]
+    fn test_rust_macro_subtrees() {
+        let config = from_language(guess::Language::Rust);
+        let src = "vec![1, 2];\nfoo!(=> x);\n";
+        let tree = to_tree(src, &config);
+        let subtrees = parse_subtrees(src, &config, &tree);
+
+        // The body of foo! isn't a Rust expression, so it's left as a
+        // token tree.
+        assert_eq!(subtrees.len(), 1);
+        let subtree = subtrees.values().next().unwrap();
+        let body = subtree
+            .tree
+            .root_node()
+            .descendant_for_byte_range(4, 10)
+            .unwrap();
+        assert_eq!(body.kind(), "array_expression");
+
+        // A policy can keep macro bodies as atoms.
+        let mut config = config;
+        config.policy.atom_nodes.insert("token_tree".into());
+        assert!(parse_subtrees(src, &config, &tree).is_empty());
+    }
+
+    #[test]
+    fn test_nested_subtrees() {
+        fn depth(subtrees: &SubTrees) -> usize {
+            subtrees
+                .values()
+                .map(|subtree| 1 + depth(&subtree.subtrees))
+                .max()
+                .unwrap_or(0)
+        }
+
+        let config = from_language(guess::Language::Rust);
+        let src = "vec![vec![1]];\n";
+        let tree = to_tree(src, &config);
+        let subtrees = parse_subtrees(src, &config, &tree);
+        assert_eq!(depth(&subtrees), 2);
+
+        let outer = subtrees.values().next().unwrap();
+        let inner = outer.subtrees.values().next().unwrap();
+        let body = inner
+            .tree
+            .root_node()
+            .descendant_for_byte_range(9, 12)
+            .unwrap();
+        assert_eq!(body.kind(), "array_expression");
+
+        let src = format!("{}1{};\n", "vec![".repeat(10), "]".repeat(10));
+        let tree = to_tree(&src, &config);
+        assert_eq!(
+            depth(&parse_subtrees(&src, &config, &tree)),
+            MAX_SUB_LANGUAGE_DEPTH
+        );
+    }
+
+    #[test]
+    fn test_doc_comment_code_blocks() {
+        let config = from_language(guess::Language::Rust);
+        let src = "/// ```
+/// let x = add(1);
+/// # assert_eq!(x, 2);
+/// ```
+///
+/// ```text
+/// add(1) = 2
+/// ```
+///
+/// ```
+/// add(1) = 2
+/// ```
+fn add() {}
+";
+        let tree = to_tree(src, &config);
+        let subtrees = parse_subtrees(src, &config, &tree);
+
+        // Only the first block is Rust, and it's keyed by both lines.
+        assert_eq!(subtrees.len(), 2);
+        let subtree = subtrees.values().next().unwrap();
+        assert_eq!(subtree.nodes.len(), 2);
+        assert_eq!(
+            subtree.tree.root_node().to_sexp(),
+            "(source_file (let_declaration pattern: (identifier) value: (call_expression function: (identifier) arguments: (arguments (integer_literal)))) (expression_statement (macro_invocation macro: (identifier) (token_tree (identifier) (integer_literal)))))"
+        );
+        // The hidden line is parsed without the `# `.
+        let assert_start = src.find("assert_eq").unwrap();
+        let statement = subtree.tree.root_node().child(1).unwrap();
+        assert_eq!(statement.start_byte(), assert_start);
+    }
+
+    #[test]
+    fn test_syntax_normalizations() {
+        let mut config = from_language(guess::Language::Rust);
+        config.policy.normalizations = SyntaxNormalizations::all();
+        let arena = Arena::new();
+
+        let lhs_src = "use a::{c, b};\nuse x;\nfn f() { let y = (z); g(1, 2) }";
+        let rhs_src = "use x;\nuse a::{b, c,};\nfn f() { let y = z; g(1, 2,) }";
+        let lhs = parse(&arena, lhs_src, &config, false);
+        let rhs = parse(&arena, rhs_src, &config, false);
+        syntax::init_all_info(&lhs, &rhs);
+        assert_eq!(lhs, rhs);
+
+        // The parentheses on the left and the commas on the right are
+        // ignored.
+        let lhs_ignored = ignored_positions(&to_tree(lhs_src, &config), lhs_src, &config);
+        let rhs_ignored = ignored_positions(&to_tree(rhs_src, &config), rhs_src, &config);
+        assert_eq!(lhs_ignored.len(), 2);
+        assert_eq!(rhs_ignored.len(), 2);
+        assert!(rhs_ignored
+            .iter()
+            .all(|pos| pos.pos.end_col - pos.pos.start_col == 1));
+
+        // A tuple of one item needs its comma, and `(a + b) * c`
+        // needs its parentheses.
+        for (lhs_src, rhs_src) in [
+            ("let t = (x,);", "let t = (x);"),
+            ("(a + b) * c;", "a + b * c;"),
+        ] {
+            let lhs = parse(&arena, lhs_src, &config, false);
+            let rhs = parse(&arena, rhs_src, &config, false);
+            syntax::init_all_info(&lhs, &rhs);
+            assert_ne!(lhs, rhs);
+        }
+
+        // The parentheses of a macro call aren't redundant.
+        let src = "println!(\"a\");";
+        assert!(ignored_positions(&to_tree(src, &config), src, &config).is_empty());
+    }
+
+    #[test]
+    fn test_statement_condition_parens_kept() {
+        let mut config = from_language(guess::Language::JavaScript);
+        config.policy.normalizations = SyntaxNormalizations::all();
+        let arena = Arena::new();
+
+        let lhs_src = "if (x) { a(); }";
+        let rhs_src = "if (x) { b(); }";
+        assert!(ignored_positions(&to_tree(lhs_src, &config), lhs_src, &config).is_empty());
+
+        let lhs = parse(&arena, lhs_src, &config, false);
+        let rhs = parse(&arena, rhs_src, &config, false);
+        syntax::init_all_info(&lhs, &rhs);
+        let mut change_map = ChangeMap::default();
+        let sections = unchanged::mark_unchanged(&lhs, &rhs, &mut change_map);
+        sections::mark_sections(
+            sections,
+            &mut change_map,
+            DEFAULT_GRAPH_LIMIT,
+            None,
+            SearchAlgorithm::Dijkstra,
+            &CostModel::default(),
+            FallbackMode::Line,
+        );
+
+        // The parentheses are unchanged, rather than ignored.
+        let positions = syntax::change_positions(&lhs, &change_map);
+        for col in [3, 5] {
+            let paren = positions.iter().find(|mp| mp.pos.start_col == col).unwrap();
+            assert!(matches!(paren.kind, MatchKind::UnchangedToken { .. }));
+        }
+    }
+
+    #[test]
+    fn test_ignore_query() {
+        let diff_options = DiffOptions {
+            ignore_queries: vec![(
+                guess::Language::Rust,
+                "((attribute_item) @ignore . (mod_item) @ignore)".into(),
+            )],
+            ..DiffOptions::default()
+        };
+        let config = from_language_with_options(guess::Language::Rust, &diff_options);
+        let src = "fn f() {}\n#[cfg(test)]\nmod tests {}\n";
+
+        let arena = Arena::new();
+        let nodes = parse(&arena, src, &config, false);
+        assert_eq!(nodes.len(), 1);
+        let ignored = ignored_positions(&to_tree(src, &config), src, &config);
+        let ignored_lines: Vec<u32> = ignored.iter().map(|pos| pos.pos.line.0).collect();
+        assert_eq!(ignored_lines, vec![1, 2]);
+
+        assert!(check_ignore_query(guess::Language::Rust, "(function_item) @ignore").is_ok());
+        assert!(check_ignore_query(guess::Language::Rust, "(function_item) @item").is_err());
+        assert!(check_ignore_query(guess::Language::Rust, "(no_such_kind) @ignore").is_err());
+
+        // Queries for an embedded language apply to it too.
+        let diff_options = DiffOptions {
+            ignore_queries: vec![(guess::Language::Css, "(declaration) @ignore".into())],
+            ..DiffOptions::default()
+        };
+        let config = from_language_with_options(guess::Language::Html, &diff_options);
+        let src = "<style>\n.a { color: red; }\n</style>\n";
+        let ignored = ignored_positions(&to_tree(src, &config), src, &config);
+        assert!(!ignored.is_empty());
+        assert!(ignored.iter().all(|pos| pos.pos.line.0 == 1));
+    }
+
+    #[test]
+    fn test_is_doc_code_info() {
+        let rust = Some(guess::Language::Rust);
+        assert!(is_doc_code_info("", rust));
+        assert!(is_doc_code_info("rust", rust));
+        assert!(is_doc_code_info("no_run,edition2018", rust));
+        assert!(!is_doc_code_info("text", rust));
+        assert!(!is_doc_code_info("rust,sh", rust));
+
+        let swift = Some(guess::Language::Swift);
+        assert!(is_doc_code_info("swift", swift));
+        assert!(is_doc_code_info("Swift", swift));
+        assert!(!is_doc_code_info("rust", swift));
+    }
+
+    #[test]
+    fn test_local_positions() {
+        let src = "fn f(a: i32) -> usize {\n    let b = a.len();\n    Some(b)\n}";
+        let config = from_language(guess::Language::Rust);
+        let locals = local_positions(&to_tree(src, &config), src, &config);
+
+        let mut names: Vec<&str> = src
+            .lines()
+            .enumerate()
+            .flat_map(|(row, line)| {
+                locals
+                    .iter()
+                    .filter(move |(line_num, _)| line_num.0 as usize == row)
+                    .map(move |(_, col)| &line[*col as usize..*col as usize + 1])
+            })
+            .collect();
+        names.sort_unstable();
+
+        // The parameter and the let binding, not the function, the
+        // types, the method or the enum variant.
+        assert_eq!(names, vec!["a", "a", "b", "b"]);
+    }
 }
@@ -0,0 +1,175 @@
+//! Per-file measurements of the diff pipeline, written by `--stats`.
+//!
+//! Each file is one CSV row, so the stats of a mining run can be
+//! loaded next to the vector file and joined on the repo and commit.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::{
+    diff::dijkstra::{SearchStats, EDGE_KINDS},
+    parse::syntax::Syntax,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct FileStats {
+    pub repo_name: String,
+    pub commit_hash: String,
+    pub path: String,
+    pub language: String,
+    /// The time taken to parse each side into `Syntax` nodes.
+    pub lhs_parse: Duration,
+    pub rhs_parse: Duration,
+    pub lhs_nodes: usize,
+    pub rhs_nodes: usize,
+    /// The number of sections returned by `mark_unchanged`.
+    pub sections: usize,
+    /// The number of sections that fell back to a line diff.
+    pub degraded_sections: usize,
+    pub search: SearchStats,
+    pub slider_fixes: usize,
+    pub total: Duration,
+}
+
+/// The number of syntax nodes in `roots`, including the roots
+/// themselves.
+pub fn node_count(roots: &[&Syntax]) -> usize {
+    roots
+        .iter()
+        .map(|node| match node {
+            Syntax::List {
+                num_descendants, ..
+            } => *num_descendants as usize + 1,
+            Syntax::Atom { .. } => 1,
+        })
+        .sum()
+}
+
+fn header() -> Vec<String> {
+    let mut fields: Vec<String> = [
+        "repo",
+        "commit_hash",
+        "path",
+        "language",
+        "lhs_parse_us",
+        "rhs_parse_us",
+        "lhs_nodes",
+        "rhs_nodes",
+        "sections",
+        "degraded_sections",
+        "vertices",
+        "path_cost",
+    ]
+    .iter()
+    .map(|field| field.to_string())
+    .collect();
+    fields.extend(EDGE_KINDS.iter().map(|kind| format!("edges_{}", kind)));
+    fields.push("slider_fixes".into());
+    fields.push("total_us".into());
+    fields
+}
+
+impl FileStats {
+    fn record(&self) -> Vec<String> {
+        let mut fields = vec![
+            self.repo_name.clone(),
+            self.commit_hash.clone(),
+            self.path.clone(),
+            self.language.clone(),
+            self.lhs_parse.as_micros().to_string(),
+            self.rhs_parse.as_micros().to_string(),
+            self.lhs_nodes.to_string(),
+            self.rhs_nodes.to_string(),
+            self.sections.to_string(),
+            self.degraded_sections.to_string(),
+            self.search.vertices.to_string(),
+            self.search.cost.to_string(),
+        ];
+        fields.extend(
+            self.search
+                .edge_counts
+                .iter()
+                .map(|count| count.to_string()),
+        );
+        fields.push(self.slider_fixes.to_string());
+        fields.push(self.total.as_micros().to_string());
+        fields
+    }
+}
+
+/// Write `stats` as a CSV row, preceded by the header row if
+/// `with_header` is set.
+fn write_stats<W: Write>(writer: W, stats: &FileStats, with_header: bool) -> io::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    if with_header {
+        wtr.write_record(header())?;
+    }
+    wtr.write_record(stats.record())?;
+    wtr.flush()
+}
+
+/// Append `stats` to the CSV file at `path`, writing the header if the
+/// file is new or empty. A path of `-` writes to stderr instead.
+pub fn append_stats(path: &str, stats: &FileStats) -> io::Result<()> {
+    if path == "-" {
+        return write_stats(io::stderr(), stats, true);
+    }
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let is_empty = file.metadata()?.len() == 0;
+    write_stats(file, stats, is_empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_matches_header() {
+        let stats = FileStats::default();
+        assert_eq!(stats.record().len(), header().len());
+    }
+
+    #[test]
+    fn test_write_stats() {
+        let mut search = SearchStats {
+            vertices: 12,
+            cost: 601,
+            ..SearchStats::default()
+        };
+        search.edge_counts[0] = 1;
+        search.edge_counts[5] = 2;
+
+        let stats = FileStats {
+            repo_name: "repo".into(),
+            commit_hash: "abc123".into(),
+            path: "src/main.rs".into(),
+            language: "Rust".into(),
+            lhs_parse: Duration::from_micros(1500),
+            rhs_parse: Duration::from_micros(1600),
+            lhs_nodes: 10,
+            rhs_nodes: 11,
+            sections: 1,
+            degraded_sections: 0,
+            search,
+            slider_fixes: 1,
+            total: Duration::from_millis(5),
+        };
+
+        let mut out = vec![];
+        write_stats(&mut out, &stats, true).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("repo,commit_hash,path,language,"));
+        assert!(lines[0].contains(",edges_UnchangedNode,"));
+        assert_eq!(
+            lines[1],
+            "repo,abc123,src/main.rs,Rust,1500,1600,10,11,1,0,12,601,1,0,0,0,0,2,0,0,1,5000"
+        );
+    }
+}